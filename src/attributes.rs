@@ -0,0 +1,261 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::user::{UserAccount, UserParams};
+
+/// How a mapped attribute's values are represented in JSON.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AttributeType {
+    #[default]
+    String,
+    Integer,
+    Boolean,
+}
+
+/// One directory attribute exposed as a JSON field of a user.
+#[derive(Debug, Clone)]
+pub struct AttributeMapping {
+    pub attribute: String,
+    pub field: String,
+    pub kind: AttributeType,
+    pub multi_valued: bool,
+    pub writable: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RawAttributeMapping {
+    field: Option<String>,
+    #[serde(default, rename = "type")]
+    kind: AttributeType,
+    #[serde(default)]
+    multi_valued: bool,
+    #[serde(default)]
+    writable: bool,
+}
+
+/// Extra attributes read from and written to user entries on top of the
+/// fixed `UserAccount` fields.
+#[derive(Debug, Clone)]
+pub struct AttributeMap {
+    mappings: Vec<AttributeMapping>,
+}
+
+/// Mappings used when a directory does not configure `attributes`.
+const DEFAULT_MAPPINGS: [(&str, &str, bool); 6] = [
+    ("mail", "mail", false),
+    ("department", "department", true),
+    ("title", "title", true),
+    ("telephoneNumber", "telephoneNumber", true),
+    ("manager", "manager", true),
+    ("employeeID", "employeeID", true),
+];
+
+impl Default for AttributeMap {
+    fn default() -> Self {
+        AttributeMap {
+            mappings: DEFAULT_MAPPINGS
+                .iter()
+                .map(|(attribute, field, writable)| AttributeMapping {
+                    attribute: attribute.to_string(),
+                    field: field.to_string(),
+                    kind: AttributeType::String,
+                    multi_valued: false,
+                    writable: *writable,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl AttributeMap {
+    /// Builds the map configured under `<prefix>.attributes`, keyed by
+    /// directory attribute. Fields may not shadow those of `UserAccount`, nor
+    /// when writable those of `UserParams`.
+    pub fn from_config(
+        prefix: &str,
+        raw: Option<BTreeMap<String, RawAttributeMapping>>,
+        errors: &mut Vec<String>,
+    ) -> AttributeMap {
+        let raw = match raw {
+            Some(raw) => raw,
+            None => return AttributeMap::default(),
+        };
+
+        let mut mappings: Vec<AttributeMapping> = Vec::new();
+        for (attribute, mapping) in raw {
+            let field = mapping.field.unwrap_or_else(|| attribute.clone());
+            let reserved = UserAccount::field_names()
+                .into_iter()
+                .chain(if mapping.writable {
+                    UserParams::field_names()
+                } else {
+                    vec![]
+                })
+                .any(|r| r.eq_ignore_ascii_case(&field));
+            if reserved {
+                errors.push(format!(
+                    "{}.attributes.{}: field {} is already a user field",
+                    prefix, attribute, field
+                ));
+            }
+            if mappings.iter().any(|m| m.field == field) {
+                errors.push(format!(
+                    "{}.attributes.{}: field {} is mapped twice",
+                    prefix, attribute, field
+                ));
+            }
+            if mappings
+                .iter()
+                .any(|m| m.attribute.eq_ignore_ascii_case(&attribute))
+            {
+                errors.push(format!(
+                    "{}.attributes.{} is mapped twice",
+                    prefix, attribute
+                ));
+            }
+            mappings.push(AttributeMapping {
+                attribute,
+                field,
+                kind: mapping.kind,
+                multi_valued: mapping.multi_valued,
+                writable: mapping.writable,
+            });
+        }
+
+        AttributeMap { mappings }
+    }
+
+    /// The JSON fields of every mapping, in order.
+    pub fn fields(&self) -> impl Iterator<Item = &str> {
+        self.mappings.iter().map(|m| m.field.as_str())
+    }
+
+    /// The JSON fields for an entry's mapped attributes. Attributes the entry
+    /// does not have are left out.
+    pub fn read(&self, attrs: &HashMap<String, Vec<String>>) -> BTreeMap<String, Value> {
+        let mut fields = BTreeMap::new();
+        for mapping in &self.mappings {
+            // Attribute names are case-insensitive and servers return them as
+            // the schema spells them.
+            let values = match attrs
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(&mapping.attribute))
+            {
+                Some((_, values)) => values,
+                None => continue,
+            };
+
+            let mut values = values.iter().map(|v| to_json(mapping.kind, v));
+            let value = if mapping.multi_valued {
+                Value::Array(values.collect())
+            } else {
+                values.next().unwrap_or(Value::Null)
+            };
+            fields.insert(mapping.field.clone(), value);
+        }
+        fields
+    }
+
+    /// The JSON value of a mapped field given as text, as in CSV. Values of
+    /// multi-valued fields are separated by `;`. Unknown fields and text not
+    /// of the field's type stay strings, for `write` to report.
+    pub fn from_text(&self, field: &str, text: &str) -> Value {
+        let mapping = match self.mappings.iter().find(|m| m.field == field) {
+            Some(mapping) => mapping,
+            None => return Value::String(text.to_string()),
+        };
+        let parse = |text: &str| match mapping.kind {
+            AttributeType::String => Value::String(text.to_string()),
+            AttributeType::Integer => text
+                .parse::<i64>()
+                .map(Value::from)
+                .unwrap_or_else(|_| Value::String(text.to_string())),
+            AttributeType::Boolean => match text.to_ascii_lowercase().as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                _ => Value::String(text.to_string()),
+            },
+        };
+        if mapping.multi_valued {
+            Value::Array(text.split(';').map(|v| parse(v.trim())).collect())
+        } else {
+            parse(text)
+        }
+    }
+
+    /// The directory attributes to write for the given JSON fields. Fails on
+    /// unknown or read-only fields and on values of the wrong type.
+    pub fn write(
+        &self,
+        fields: &BTreeMap<String, Value>,
+    ) -> Result<Vec<(String, Vec<String>)>, String> {
+        let mut attrs = Vec::new();
+        for (field, value) in fields {
+            let mapping = self
+                .mappings
+                .iter()
+                .find(|m| &m.field == field)
+                .ok_or_else(|| format!("{} is not a known field", field))?;
+            if !mapping.writable {
+                return Err(format!("{} is read-only", field));
+            }
+
+            let values = match (value, mapping.multi_valued) {
+                (Value::Null, _) => continue,
+                (Value::Array(values), true) => values
+                    .iter()
+                    .map(|v| from_json(mapping.kind, v))
+                    .collect::<Option<Vec<_>>>(),
+                (value, _) => from_json(mapping.kind, value).map(|v| vec![v]),
+            };
+            match values {
+                Some(values) if values.is_empty() => {}
+                Some(values) => attrs.push((mapping.attribute.clone(), values)),
+                None => return Err(format!("{} is not a valid {}", field, type_name(mapping))),
+            }
+        }
+        Ok(attrs)
+    }
+}
+
+fn to_json(kind: AttributeType, value: &str) -> Value {
+    match kind {
+        AttributeType::String => Value::String(value.to_string()),
+        AttributeType::Integer => value
+            .parse::<i64>()
+            .map(Value::from)
+            .unwrap_or_else(|_| Value::String(value.to_string())),
+        AttributeType::Boolean => match value {
+            "TRUE" => Value::Bool(true),
+            "FALSE" => Value::Bool(false),
+            _ => Value::String(value.to_string()),
+        },
+    }
+}
+
+fn from_json(kind: AttributeType, value: &Value) -> Option<String> {
+    match (kind, value) {
+        (AttributeType::String, Value::String(s)) => Some(s.clone()),
+        (AttributeType::Integer, Value::Number(n)) => n.as_i64().map(|n| n.to_string()),
+        (AttributeType::Boolean, Value::Bool(b)) => {
+            Some(if *b { "TRUE" } else { "FALSE" }.to_string())
+        }
+        _ => None,
+    }
+}
+
+fn type_name(mapping: &AttributeMapping) -> String {
+    let kind = match mapping.kind {
+        AttributeType::String => "string",
+        AttributeType::Integer => "integer",
+        AttributeType::Boolean => "boolean",
+    };
+    if mapping.multi_valued {
+        format!("{} or list of {}s", kind, kind)
+    } else {
+        kind.to_string()
+    }
+}
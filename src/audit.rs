@@ -0,0 +1,252 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use rocket::request::{FromRequest, Outcome};
+use rocket::tokio::fs::{File, OpenOptions};
+use rocket::tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use rocket::tokio::sync::Mutex;
+use rocket::Request;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Caller;
+use crate::mailbox::Mailbox;
+use crate::request_id::RequestId;
+
+const REDACTED: &str = "<redacted>";
+const SECRET_ATTRIBUTES: [&str; 3] = ["unicodePwd", "userPassword", "password"];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditChange {
+    pub attribute: String,
+    pub operation: String,
+    pub values: Vec<String>,
+}
+
+impl AuditChange {
+    pub fn new(attribute: &str, operation: &str, values: Vec<String>) -> Self {
+        let values = if SECRET_ATTRIBUTES
+            .iter()
+            .any(|secret| secret.eq_ignore_ascii_case(attribute))
+        {
+            values.iter().map(|_| REDACTED.to_string()).collect()
+        } else {
+            values
+        };
+
+        AuditChange {
+            attribute: attribute.to_string(),
+            operation: operation.to_string(),
+            values,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    pub request_id: String,
+    pub actor: String,
+    pub action: String,
+    pub target_dn: Option<String>,
+    pub target_guid: Option<String>,
+    pub changes: Vec<AuditChange>,
+    pub result_code: Option<u32>,
+    pub success: bool,
+    /// Why an operation outside the directory failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(FromForm, Debug, Default)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    pub request_id: Option<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub success: Option<bool>,
+    pub limit: Option<usize>,
+}
+
+impl AuditFilter {
+    fn parse_time(value: &Option<String>) -> Result<Option<DateTime<Utc>>, String> {
+        match value {
+            Some(value) => DateTime::parse_from_rfc3339(value)
+                .map(|t| Some(t.with_timezone(&Utc)))
+                .map_err(|_| format!("Invalid timestamp: {}", value)),
+            None => Ok(None),
+        }
+    }
+
+    fn matches(
+        &self,
+        record: &AuditRecord,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> bool {
+        let contains = |haystack: &Option<String>, needle: &str| {
+            haystack
+                .as_deref()
+                .map(|h| h.to_lowercase().contains(&needle.to_lowercase()))
+                .unwrap_or(false)
+        };
+
+        self.actor.as_ref().is_none_or(|a| &record.actor == a)
+            && self.action.as_ref().is_none_or(|a| &record.action == a)
+            && self
+                .request_id
+                .as_ref()
+                .is_none_or(|r| &record.request_id == r)
+            && self.success.is_none_or(|s| record.success == s)
+            && self
+                .target
+                .as_ref()
+                .is_none_or(|t| contains(&record.target_dn, t) || contains(&record.target_guid, t))
+            && since.is_none_or(|s| record.timestamp >= s)
+            && until.is_none_or(|u| record.timestamp <= u)
+    }
+}
+
+/// Append-only JSON lines log of every write made to the directory.
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub async fn open(path: impl Into<PathBuf>) -> std::io::Result<AuditLog> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        Ok(AuditLog {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub async fn append(&self, record: &AuditRecord) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock().await;
+        file.write_all(&line).await?;
+        file.flush().await
+    }
+
+    /// Returns the newest records first.
+    pub async fn query(&self, filter: &AuditFilter) -> Result<Vec<AuditRecord>, String> {
+        let since = AuditFilter::parse_time(&filter.since)?;
+        let until = AuditFilter::parse_time(&filter.until)?;
+
+        let file = File::open(&self.path).await.map_err(|e| e.to_string())?;
+        let mut lines = BufReader::new(file).lines();
+        let mut records = Vec::new();
+        while let Some(line) = lines.next_line().await.map_err(|e| e.to_string())? {
+            if let Ok(record) = serde_json::from_str::<AuditRecord>(&line) {
+                if filter.matches(&record, since, until) {
+                    records.push(record);
+                }
+            }
+        }
+
+        records.reverse();
+        if let Some(limit) = filter.limit {
+            records.truncate(limit);
+        }
+        Ok(records)
+    }
+}
+
+/// Request guard which stamps audit records with the caller and request ID.
+/// It owns its handle on the log, so background jobs can keep recording.
+#[derive(Clone)]
+pub struct Auditor {
+    log: Arc<AuditLog>,
+    actor: String,
+    request_id: String,
+}
+
+impl Auditor {
+    pub fn actor(&self) -> &str {
+        &self.actor
+    }
+
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    pub async fn record(
+        &self,
+        action: &str,
+        target_dn: Option<String>,
+        target_guid: Option<String>,
+        changes: Vec<AuditChange>,
+        result_code: Option<u32>,
+    ) {
+        self.append(AuditRecord {
+            timestamp: Utc::now(),
+            request_id: self.request_id.clone(),
+            actor: self.actor.clone(),
+            action: action.to_string(),
+            target_dn,
+            target_guid,
+            changes,
+            result_code,
+            success: result_code == Some(0),
+            error: None,
+        })
+        .await;
+    }
+
+    /// Records a change to a user's mailbox.
+    pub async fn record_mailbox(&self, target_dn: &str, mailbox: &Mailbox) {
+        self.append(AuditRecord {
+            timestamp: Utc::now(),
+            request_id: self.request_id.clone(),
+            actor: self.actor.clone(),
+            action: format!("mailbox.{}", mailbox.action),
+            target_dn: Some(target_dn.to_string()),
+            target_guid: None,
+            changes: vec![AuditChange::new(
+                "mailbox",
+                mailbox.action,
+                vec![format!("{}:{}", mailbox.provider, mailbox.address)],
+            )],
+            result_code: None,
+            success: mailbox.error.is_none(),
+            error: mailbox.error.clone(),
+        })
+        .await;
+    }
+
+    async fn append(&self, record: AuditRecord) {
+        if let Err(e) = self.log.append(&record).await {
+            tracing::error!(error = %e, record = ?record, "failed to write audit record");
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Auditor {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let log = match request.rocket().state::<Arc<AuditLog>>() {
+            Some(log) => log.clone(),
+            None => return Outcome::Error((rocket::http::Status::InternalServerError, ())),
+        };
+        let caller = request.guard::<Caller>().await.unwrap();
+        let request_id = RequestId::of(request);
+
+        Outcome::Success(Auditor {
+            log,
+            actor: caller.identity,
+            request_id: request_id.0,
+        })
+    }
+}
@@ -6,4 +6,107 @@
 //         Ok(user) => Ok(Json(user)),
 //         Err(_) => Err(Status::Unauthorized),
 //     }
-// }
\ No newline at end of file
+// }
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+
+use crate::errors::Rejection;
+use crate::ServerState;
+
+/// The identity of whoever is calling the API. The service sits behind an
+/// authenticating proxy which passes the user on in `X-Remote-User`; that
+/// header is only believed on connections from `auth.trusted_proxies`.
+/// Other callers are identified by their address.
+#[derive(Clone, Debug)]
+pub struct Caller {
+    pub identity: String,
+    /// Whether a trusted proxy vouched for the identity.
+    pub authenticated: bool,
+}
+
+/// Whether the request came straight from one of `auth.trusted_proxies`.
+pub fn from_trusted_proxy(request: &Request<'_>) -> bool {
+    let (Some(state), Some(remote)) = (request.rocket().state::<ServerState>(), request.remote())
+    else {
+        return false;
+    };
+    let ip = remote.ip().to_canonical();
+    state
+        .config
+        .auth
+        .trusted_proxies
+        .iter()
+        .any(|proxy| proxy.contains(&ip))
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Caller {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if !from_trusted_proxy(request) {
+            // Anyone can set headers, so only the connection itself counts
+            let identity = request
+                .remote()
+                .map(|remote| remote.ip().to_canonical().to_string())
+                .unwrap_or_else(|| "anonymous".to_string());
+            return Outcome::Success(Caller {
+                identity,
+                authenticated: false,
+            });
+        }
+        let caller = match request.headers().get_one("X-Remote-User") {
+            Some(user) if !user.is_empty() => Caller {
+                identity: user.to_string(),
+                authenticated: true,
+            },
+            _ => Caller {
+                identity: request
+                    .client_ip()
+                    .map(|ip| ip.to_string())
+                    .unwrap_or_else(|| "anonymous".to_string()),
+                authenticated: false,
+            },
+        };
+        Outcome::Success(caller)
+    }
+}
+
+/// A caller allowed to use the admin-only endpoints, as set in the `auth`
+/// configuration. Like `X-Remote-User`, `X-Remote-Groups` only counts on
+/// connections from a trusted proxy. Anyone else is turned away with 403.
+#[derive(Clone, Debug)]
+pub struct Admin(pub Caller);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let state = match request.rocket().state::<ServerState>() {
+            Some(state) => state,
+            None => return Outcome::Error((Status::InternalServerError, ())),
+        };
+        let auth = &state.config.auth;
+        let caller = request.guard::<Caller>().await.unwrap();
+
+        let groups = request
+            .headers()
+            .get_one("X-Remote-Groups")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim);
+        // Only callers a trusted proxy vouched for can be admins
+        let admin = caller.authenticated
+            && (auth.admins.contains(&caller.identity)
+                || groups
+                    .filter(|g| !g.is_empty())
+                    .any(|g| auth.admin_groups.iter().any(|a| a == g)));
+        if admin {
+            return Outcome::Success(Admin(caller));
+        }
+        request.local_cache(|| Rejection(Some(format!("{} is not an admin", caller.identity))));
+        Outcome::Error((Status::Forbidden, ()))
+    }
+}
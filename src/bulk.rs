@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+
+use rocket::data::{self, Data, FromData, ToByteUnit};
+use rocket::http::Status;
+use rocket::request::Outcome;
+use rocket::Request;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::attributes::AttributeMap;
+use crate::audit::Auditor;
+use crate::config::PasswordPolicy;
+use crate::directory::{Connection, Directory};
+use crate::errors::{APIErrors, Rejection};
+use crate::jobs::JobHandle;
+use crate::mailbox::{Mailbox, Templates};
+use crate::user::{UserAccount, UserParams};
+use crate::webhooks::{self, Event, EventKind};
+use crate::ServerState;
+
+type EntryAttributes = Vec<(String, Vec<String>)>;
+
+/// Largest import accepted unless the `bulk` limit says otherwise.
+const DEFAULT_LIMIT_MIB: u64 = 10;
+
+/// The rows of a bulk import, each parsed on its own so that one bad row
+/// does not reject the others. Takes a JSON array of users, or CSV whose
+/// header names the user fields.
+pub struct BulkUsers(pub Vec<Result<UserParams, String>>);
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for BulkUsers {
+    type Error = String;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let reject = |status: Status, reason: String| {
+            req.local_cache(|| Rejection(Some(reason.clone())));
+            data::Outcome::Error((status, reason))
+        };
+
+        let directory = match req.guard::<&Directory>().await {
+            Outcome::Success(directory) => directory,
+            _ => return reject(Status::NotFound, "Unknown directory".to_string()),
+        };
+        let csv = match req.content_type() {
+            Some(ct) if ct.is_csv() => true,
+            Some(ct) if ct.is_json() => false,
+            _ => {
+                return reject(
+                    Status::UnsupportedMediaType,
+                    "Send text/csv or application/json".to_string(),
+                )
+            }
+        };
+
+        let limit = req
+            .limits()
+            .get("bulk")
+            .unwrap_or(DEFAULT_LIMIT_MIB.mebibytes());
+        let body = match data.open(limit).into_string().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(_) => {
+                return reject(
+                    Status::PayloadTooLarge,
+                    format!("Imports are limited to {}", limit),
+                )
+            }
+            Err(e) => return reject(Status::BadRequest, e.to_string()),
+        };
+
+        let rows = if csv {
+            parse_csv(&body, &directory.config.attributes)
+        } else {
+            parse_json(&body)
+        };
+        match rows {
+            Ok(rows) => data::Outcome::Success(BulkUsers(rows)),
+            Err(e) => reject(Status::BadRequest, e),
+        }
+    }
+}
+
+fn parse_json(body: &str) -> Result<Vec<Result<UserParams, String>>, String> {
+    let values: Vec<Value> =
+        serde_json::from_str(body).map_err(|e| format!("Expected a JSON array: {}", e))?;
+    Ok(values
+        .into_iter()
+        .map(|value| serde_json::from_value(value).map_err(|e| e.to_string()))
+        .collect())
+}
+
+fn parse_csv(
+    body: &str,
+    attributes: &AttributeMap,
+) -> Result<Vec<Result<UserParams, String>>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(body.as_bytes());
+    let headers = reader
+        .headers()
+        .map_err(|e| format!("Unreadable CSV header: {}", e))?
+        .clone();
+    let fields = UserParams::field_names();
+
+    Ok(reader
+        .records()
+        .map(|record| {
+            let record = record.map_err(|e| e.to_string())?;
+            let mut row = Map::new();
+            for (header, cell) in headers.iter().zip(record.iter()) {
+                // An empty cell leaves the field unset
+                if cell.is_empty() {
+                    continue;
+                }
+                let value = if header == "create_mailbox" || header == "create_cpanel_account" {
+                    match cell.to_ascii_lowercase().as_str() {
+                        "true" | "yes" | "1" => Value::Bool(true),
+                        "false" | "no" | "0" => Value::Bool(false),
+                        _ => return Err(format!("{} must be true or false", header)),
+                    }
+                } else if fields.iter().any(|f| f == header) {
+                    Value::String(cell.to_string())
+                } else {
+                    attributes.from_text(header, cell)
+                };
+                row.insert(header.to_string(), value);
+            }
+            serde_json::from_value(Value::Object(row)).map_err(|e| e.to_string())
+        })
+        .collect())
+}
+
+#[derive(FromForm, Debug, Default)]
+pub struct BulkOptions {
+    pub dry_run: Option<bool>,
+    /// Runs the import as a job.
+    #[field(name = "async")]
+    pub background: Option<bool>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RowStatus {
+    /// Dry run: the row would be created.
+    Valid,
+    Created,
+    /// A user with the same login already exists.
+    Exists,
+    Failed,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RowResult {
+    /// 1-based position among the data rows.
+    pub row: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub login: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dn: Option<String>,
+    pub status: RowStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mailbox: Option<Mailbox>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BulkReport {
+    pub dry_run: bool,
+    /// Rows in the input.
+    pub total: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    /// Rows never reached because the job was cancelled.
+    pub unprocessed: usize,
+    pub rows: Vec<RowResult>,
+}
+
+/// Validates and, unless `dry_run`, creates every row on one connection.
+/// Rows are independent: a failed row is reported and the next one tried.
+/// Run as a job, the report stops at the row the job was cancelled on, and
+/// leaves out generated mailbox passwords as it is kept in the job store.
+pub async fn import(
+    ldap: &mut Connection,
+    directory: &Directory,
+    state: &ServerState,
+    auditor: &Auditor,
+    users: BulkUsers,
+    dry_run: bool,
+    job: Option<&JobHandle>,
+) -> BulkReport {
+    let (templates, policy) = (&state.templates, &state.config.password_policy);
+    let total = users.0.len();
+    let mut seen_logins: HashMap<String, usize> = HashMap::new();
+    let mut seen_dns: HashMap<String, usize> = HashMap::new();
+    let mut containers: HashMap<String, bool> = HashMap::new();
+    let mut rows = Vec::with_capacity(users.0.len());
+
+    for (index, user) in users.0.into_iter().enumerate() {
+        if let Some(job) = job {
+            if !job.progress(index, total, &rows).await {
+                break;
+            }
+        }
+        let mut result = RowResult {
+            row: index + 1,
+            login: None,
+            dn: None,
+            status: RowStatus::Failed,
+            error: None,
+            mailbox: None,
+        };
+        let user = match user {
+            Ok(user) => user,
+            Err(e) => {
+                result.error = Some(e);
+                rows.push(result);
+                continue;
+            }
+        };
+        let login = directory.flavor.login_name(&user).to_string();
+        result.login = Some(login.clone());
+
+        let prepared = prepare(directory, templates, policy, &user).and_then(|(dn, attrs)| {
+            // Duplicates within the import itself
+            let earlier = seen_logins
+                .get(&login.to_lowercase())
+                .or_else(|| seen_dns.get(&dn.to_lowercase()));
+            match earlier {
+                Some(earlier) => Err(format!("duplicate of row {}", earlier)),
+                None => Ok((dn, attrs)),
+            }
+        });
+        let (dn, attrs) = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                result.error = Some(e);
+                rows.push(result);
+                continue;
+            }
+        };
+        seen_logins.insert(login.to_lowercase(), result.row);
+        seen_dns.insert(dn.to_lowercase(), result.row);
+        result.dn = Some(dn.clone());
+
+        let container = user.container(directory).unwrap_or_default().to_string();
+        let exists = match containers.get(&container) {
+            Some(exists) => Ok(*exists),
+            None => ldap.entry_exists(&container).await,
+        };
+        match exists {
+            Ok(true) => {
+                containers.insert(container, true);
+            }
+            Ok(false) => {
+                containers.insert(container.clone(), false);
+                result.error = Some(format!("container {} does not exist", container));
+                rows.push(result);
+                continue;
+            }
+            Err(e) => {
+                result.error = Some(describe(e));
+                rows.push(result);
+                continue;
+            }
+        }
+
+        if dry_run {
+            match UserAccount::get_dn_from_uname(ldap, directory, &login).await {
+                Ok(None) => result.status = RowStatus::Valid,
+                Ok(Some(_)) => result.status = RowStatus::Exists,
+                Err(e) => result.error = Some(describe(e)),
+            }
+            rows.push(result);
+            continue;
+        }
+
+        let template = templates.get(user.template.as_deref()).unwrap();
+        let changes = user.audit_changes(directory.flavor.as_ref(), &attrs);
+        match UserAccount::create_new_user(ldap, directory, template, user, attrs).await {
+            Ok(account) => {
+                let guid = UserAccount::get_guid(ldap, directory.flavor.as_ref(), &dn).await;
+                auditor
+                    .record("user.create", Some(dn.clone()), guid, changes, Some(0))
+                    .await;
+                if let Some(mailbox) = &account.mailbox {
+                    auditor.record_mailbox(&dn, mailbox).await;
+                }
+                let event = Event::new(
+                    EventKind::UserCreated,
+                    &directory.name,
+                    auditor,
+                    &dn,
+                    Some(&login),
+                    webhooks::account_data(&account),
+                );
+                state.events.publish_write(ldap, event).await;
+                result.status = RowStatus::Created;
+                result.mailbox = account.mailbox.map(|mailbox| match job {
+                    Some(_) => Mailbox {
+                        password: None,
+                        ..mailbox
+                    },
+                    None => mailbox,
+                });
+            }
+            Err(APIErrors::EntryExists) => {
+                auditor
+                    .record("user.create", Some(dn), None, changes, Some(68))
+                    .await;
+                result.status = RowStatus::Exists;
+            }
+            Err(e) => {
+                auditor
+                    .record("user.create", Some(dn), None, changes, e.result_code())
+                    .await;
+                result.error = Some(describe(e));
+            }
+        }
+        rows.push(result);
+    }
+    if let Some(job) = job {
+        job.progress(rows.len(), total, &rows).await;
+    }
+
+    let succeeded = rows
+        .iter()
+        .filter(|r| matches!(r.status, RowStatus::Valid | RowStatus::Created))
+        .count();
+    BulkReport {
+        dry_run,
+        total,
+        succeeded,
+        failed: rows.len() - succeeded,
+        unprocessed: total - rows.len(),
+        rows,
+    }
+}
+
+/// The DN and attributes of a row's entry, once the row passes every check
+/// which needs no directory.
+fn prepare(
+    directory: &Directory,
+    templates: &Templates,
+    policy: &PasswordPolicy,
+    user: &UserParams,
+) -> Result<(String, EntryAttributes), String> {
+    if templates.get(user.template.as_deref()).is_none() {
+        return Err(format!(
+            "unknown template {}",
+            user.template.as_deref().unwrap_or_default()
+        ));
+    }
+    policy.check(&user.password)?;
+    let dn = user.dn(directory)?;
+    let attrs = user.ldap_attributes(directory)?;
+    Ok((dn, attrs))
+}
+
+fn describe(e: APIErrors) -> String {
+    match e {
+        APIErrors::Timeout => "directory operation timed out",
+        APIErrors::EntryExists => "user already exists",
+        APIErrors::EntryNotFound => "user not found after creation",
+        APIErrors::ConnectionError => "directory connection failed",
+        _ => "directory operation failed",
+    }
+    .to_string()
+}
@@ -0,0 +1,1142 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use figment::providers::{Env, Format, Toml, Yaml};
+use figment::Figment;
+use ipnet::IpNet;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::attributes::{AttributeMap, RawAttributeMapping};
+use crate::mailbox::DEFAULT_TEMPLATE;
+use crate::timeout::TimeoutConfig;
+use crate::webhooks::EventKind;
+
+/// Environment variables the service has always been configured with, and
+/// the config keys they now map onto.
+const LEGACY_ENV: [(&str, &str); 8] = [
+    ("LDAP_SERVER", "ldap.server"),
+    ("LOGIN_USERNAME", "ldap.username"),
+    ("LOGIN_PASSWORD", "ldap.password"),
+    ("BASE_DN", "ldap.base_dn"),
+    ("CPANEL_URL", "cpanel.url"),
+    ("CPANEL_ACCESS_TOKEN", "cpanel.access_token"),
+    ("AUDIT_LOG_PATH", "audit.log_path"),
+    ("READINESS_TIMEOUT_MS", "health.readiness_timeout_ms"),
+];
+
+/// A configuration value which must never end up in logs.
+#[derive(Deserialize, Clone, Default)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Reads a secret given either inline or as the path of a file holding it,
+/// the latter being how container orchestrators usually hand secrets over.
+fn resolve_secret(
+    name: &str,
+    value: Option<Secret>,
+    file: Option<PathBuf>,
+    errors: &mut Vec<String>,
+) -> Option<Secret> {
+    match (value, file) {
+        (Some(_), Some(_)) => {
+            errors.push(format!("{} and {}_file are both set", name, name));
+            None
+        }
+        (Some(value), None) => Some(value),
+        (None, Some(path)) => match std::fs::read_to_string(&path) {
+            Ok(contents) => Some(Secret(contents.trim_end_matches(['\r', '\n']).to_string())),
+            Err(e) => {
+                errors.push(format!(
+                    "{}_file: cannot read {}: {}",
+                    name,
+                    path.display(),
+                    e
+                ));
+                None
+            }
+        },
+        (None, None) => None,
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct RawLdapConfig {
+    server: Option<String>,
+    servers: Option<Vec<LdapServer>>,
+    discovery: Option<SrvDiscovery>,
+    username: Option<String>,
+    password: Option<Secret>,
+    password_file: Option<PathBuf>,
+    base_dn: Option<String>,
+    groups_dn: Option<String>,
+    #[serde(default)]
+    flavor: SchemaFlavor,
+    #[serde(default = "default_pool_size")]
+    pool_size: usize,
+    attributes: Option<BTreeMap<String, RawAttributeMapping>>,
+    #[serde(default)]
+    tls: RawTlsConfig,
+    #[serde(default)]
+    bind: RawBindConfig,
+    #[serde(default)]
+    timeouts: TimeoutConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+struct RawBindConfig {
+    #[serde(default)]
+    mechanism: BindMechanism,
+    keytab: Option<PathBuf>,
+    spn_host: Option<String>,
+}
+
+/// The TLS stack verifies the certificate against the host of the server URL
+/// and cannot be told another name, so unknown keys such as `server_name` are
+/// rejected rather than silently ignored.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+struct RawTlsConfig {
+    starttls: Option<bool>,
+    ca_file: Option<PathBuf>,
+    client_cert_file: Option<PathBuf>,
+    client_key_file: Option<PathBuf>,
+    min_version: Option<String>,
+    #[serde(default)]
+    insecure_skip_verify: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+struct RawScimConfig {
+    bearer_token: Option<Secret>,
+    bearer_token_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct RawCpanelConfig {
+    url: Option<String>,
+    #[serde(default = "default_cpanel_timeout_ms")]
+    timeout_ms: u64,
+    access_token: Option<Secret>,
+    access_token_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct RawWebhooksConfig {
+    #[serde(default = "default_dead_letter_path")]
+    dead_letter_path: PathBuf,
+    #[serde(default = "default_webhook_max_attempts")]
+    max_attempts: u32,
+    #[serde(default = "default_webhook_initial_backoff_ms")]
+    initial_backoff_ms: u64,
+    #[serde(default)]
+    endpoints: BTreeMap<String, RawWebhookEndpoint>,
+}
+
+impl Default for RawWebhooksConfig {
+    fn default() -> Self {
+        RawWebhooksConfig {
+            dead_letter_path: default_dead_letter_path(),
+            max_attempts: default_webhook_max_attempts(),
+            initial_backoff_ms: default_webhook_initial_backoff_ms(),
+            endpoints: BTreeMap::new(),
+        }
+    }
+}
+
+fn default_dead_letter_path() -> PathBuf {
+    PathBuf::from("webhook-dead-letters.redb")
+}
+
+fn default_webhook_max_attempts() -> u32 {
+    5
+}
+
+fn default_webhook_initial_backoff_ms() -> u64 {
+    1000
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct RawWebhookEndpoint {
+    url: Option<String>,
+    secret: Option<Secret>,
+    secret_file: Option<PathBuf>,
+    #[serde(default)]
+    events: Vec<EventKind>,
+    #[serde(default = "default_webhook_timeout_ms")]
+    timeout_ms: u64,
+}
+
+fn default_webhook_timeout_ms() -> u64 {
+    5000
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct RawCorsConfig {
+    #[serde(default)]
+    allowed_origins: Vec<String>,
+    #[serde(default)]
+    allowed_origin_patterns: Vec<String>,
+    #[serde(default = "default_cors_allow_credentials")]
+    allow_credentials: bool,
+    #[serde(default = "default_cors_max_age_secs")]
+    max_age_secs: u64,
+}
+
+impl Default for RawCorsConfig {
+    fn default() -> Self {
+        RawCorsConfig {
+            allowed_origins: vec![],
+            allowed_origin_patterns: vec![],
+            allow_credentials: default_cors_allow_credentials(),
+            max_age_secs: default_cors_max_age_secs(),
+        }
+    }
+}
+
+fn default_cors_allow_credentials() -> bool {
+    true
+}
+
+fn default_cors_max_age_secs() -> u64 {
+    600
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct RawConfig {
+    ldap: Option<RawLdapConfig>,
+    #[serde(default)]
+    directories: BTreeMap<String, RawLdapConfig>,
+    default_directory: Option<String>,
+    cpanel: Option<RawCpanelConfig>,
+    #[serde(default)]
+    templates: BTreeMap<String, TemplateConfig>,
+    #[serde(default)]
+    password_policy: PasswordPolicy,
+    #[serde(default)]
+    auth: RawAuthConfig,
+    #[serde(default)]
+    audit: AuditConfig,
+    #[serde(default)]
+    jobs: JobsConfig,
+    #[serde(default)]
+    webhooks: RawWebhooksConfig,
+    #[serde(default)]
+    change_feed: ChangeFeedConfig,
+    #[serde(default)]
+    health: HealthConfig,
+    #[serde(default)]
+    cors: RawCorsConfig,
+    #[serde(default)]
+    scim: RawScimConfig,
+}
+
+/// Which directory schema a profile speaks.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SchemaFlavor {
+    #[default]
+    #[serde(alias = "ad")]
+    ActiveDirectory,
+    #[serde(alias = "389ds", alias = "rfc2307")]
+    Openldap,
+}
+
+/// How the service authenticates its own connections.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BindMechanism {
+    /// Username and password.
+    #[default]
+    Simple,
+    /// SASL EXTERNAL with the TLS client certificate.
+    External,
+    /// SASL GSSAPI with Kerberos credentials from a keytab. Requires the
+    /// `gssapi` feature.
+    Gssapi,
+}
+
+#[derive(Debug, Clone)]
+pub struct BindConfig {
+    pub mechanism: BindMechanism,
+    /// Client keytab for GSSAPI, exported as `KRB5_CLIENT_KTNAME`.
+    pub keytab: Option<PathBuf>,
+    /// Host part of the `ldap/<host>` service principal, when it differs
+    /// from the server URL.
+    pub spn_host: Option<String>,
+}
+
+/// Lowest TLS version accepted from a directory server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    Tls10,
+    Tls11,
+    Tls12,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// Upgrade `ldap://` connections with StartTLS. Has no effect on
+    /// `ldaps://`.
+    pub starttls: bool,
+    /// PEM bundle of CAs trusted in addition to the system store.
+    pub ca_file: Option<PathBuf>,
+    /// PEM client certificate and PKCS #8 key, presented when set.
+    pub client_cert_file: Option<PathBuf>,
+    pub client_key_file: Option<PathBuf>,
+    pub min_version: TlsVersion,
+    /// Accept any certificate. Only ever meant for lab directories.
+    pub insecure_skip_verify: bool,
+}
+
+/// One server of a directory. Lower priorities are tried first; servers of
+/// equal priority are picked in proportion to their weights (RFC 2782).
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LdapServer {
+    pub url: String,
+    #[serde(default)]
+    pub priority: u16,
+    #[serde(default)]
+    pub weight: u16,
+}
+
+/// Finds a directory's servers through `_ldap._tcp.<domain>` SRV records,
+/// or `_ldaps._tcp.<domain>` for LDAPS.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SrvDiscovery {
+    pub domain: String,
+    #[serde(default)]
+    pub ldaps: bool,
+}
+
+/// Connection settings for one named directory.
+#[derive(Debug, Clone)]
+pub struct LdapConfig {
+    /// Servers to fail over between, unless `discovery` is set.
+    pub servers: Vec<LdapServer>,
+    pub discovery: Option<SrvDiscovery>,
+    pub username: String,
+    /// Required for simple binds. With SASL it is the simple bind fallback.
+    pub password: Option<Secret>,
+    pub base_dn: String,
+    /// The container SCIM groups are created in. Group operations are
+    /// limited to groups under it, and refused when it is not set.
+    pub groups_dn: Option<String>,
+    pub flavor: SchemaFlavor,
+    pub pool_size: usize,
+    pub attributes: AttributeMap,
+    pub tls: TlsConfig,
+    pub bind: BindConfig,
+    pub timeouts: TimeoutConfig,
+}
+
+fn default_pool_size() -> usize {
+    1
+}
+
+#[derive(Debug, Clone)]
+pub struct CpanelConfig {
+    pub url: String,
+    pub access_token: Secret,
+    /// Limit on each UAPI call, connecting included.
+    pub timeout: Duration,
+}
+
+fn default_cpanel_timeout_ms() -> u64 {
+    10_000
+}
+
+/// Which mail system a template provisions mailboxes on.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MailboxKind {
+    #[default]
+    None,
+    Cpanel,
+}
+
+/// What happens to the mailbox of a deleted user.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MailboxDeletion {
+    Delete,
+    /// Keeps the mail but blocks logins.
+    #[default]
+    Suspend,
+}
+
+/// Settings applied to users created from a template.
+#[derive(Deserialize, Debug, Clone)]
+pub struct TemplateConfig {
+    #[serde(default)]
+    pub mailbox: MailboxKind,
+    /// Domain of new mailboxes; that of the user's `mail` when unset.
+    pub mail_domain: Option<String>,
+    /// 0 for no quota.
+    #[serde(default = "default_mailbox_quota_mb")]
+    pub mailbox_quota_mb: u64,
+    #[serde(default)]
+    pub mailbox_on_delete: MailboxDeletion,
+}
+
+fn default_mailbox_quota_mb() -> u64 {
+    2048
+}
+
+/// Rules passwords are checked against before anything is written, so that
+/// bulk dry runs catch what the directory would refuse.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// How many of lowercase letters, uppercase letters, digits and other
+    /// characters a password must mix.
+    pub min_classes: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 8,
+            min_classes: 3,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn check(&self, password: &str) -> Result<(), String> {
+        if password.chars().count() < self.min_length {
+            return Err(format!(
+                "password must be at least {} characters long",
+                self.min_length
+            ));
+        }
+        let classes = [
+            password.chars().any(|c| c.is_lowercase()),
+            password.chars().any(|c| c.is_uppercase()),
+            password.chars().any(|c| c.is_ascii_digit()),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+        if classes.iter().filter(|&&has| has).count() < self.min_classes {
+            return Err(format!(
+                "password must mix at least {} of lowercase, uppercase, digits and symbols",
+                self.min_classes
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+struct RawAuthConfig {
+    #[serde(default)]
+    admins: Vec<String>,
+    #[serde(default)]
+    admin_groups: Vec<String>,
+    #[serde(default)]
+    trusted_proxies: Vec<String>,
+}
+
+/// Who may call the admin-only endpoints. Callers are matched on the
+/// identity they are audited under, or on a group passed by the proxy in
+/// `X-Remote-Groups`. Nobody is an admin unless configured.
+#[derive(Debug, Clone, Default)]
+pub struct AuthConfig {
+    pub admins: Vec<String>,
+    pub admin_groups: Vec<String>,
+    /// Addresses of the authenticating proxies. The identity headers are
+    /// only believed on connections from one of them.
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AuditConfig {
+    #[serde(default = "default_audit_log_path")]
+    pub log_path: PathBuf,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            log_path: default_audit_log_path(),
+        }
+    }
+}
+
+fn default_audit_log_path() -> PathBuf {
+    PathBuf::from("audit.log")
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct JobsConfig {
+    #[serde(default = "default_jobs_store_path")]
+    pub store_path: PathBuf,
+    /// Where jobs write what they produce, such as exports.
+    #[serde(default = "default_jobs_artifact_dir")]
+    pub artifact_dir: PathBuf,
+    /// Jobs run at once; the rest wait their turn.
+    #[serde(default = "default_jobs_max_running")]
+    pub max_running: usize,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            store_path: default_jobs_store_path(),
+            artifact_dir: default_jobs_artifact_dir(),
+            max_running: default_jobs_max_running(),
+        }
+    }
+}
+
+fn default_jobs_store_path() -> PathBuf {
+    PathBuf::from("jobs.redb")
+}
+
+fn default_jobs_artifact_dir() -> PathBuf {
+    PathBuf::from("job-artifacts")
+}
+
+fn default_jobs_max_running() -> usize {
+    2
+}
+
+/// Watching directories for changes made without the API.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ChangeFeedConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_change_feed_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+}
+
+impl Default for ChangeFeedConfig {
+    fn default() -> Self {
+        ChangeFeedConfig {
+            enabled: false,
+            poll_interval_ms: default_change_feed_poll_interval_ms(),
+        }
+    }
+}
+
+fn default_change_feed_poll_interval_ms() -> u64 {
+    30000
+}
+
+/// Where lifecycle events are sent, and how hard to try.
+#[derive(Debug, Clone)]
+pub struct WebhooksConfig {
+    /// Deliveries which used up their attempts, kept for replay.
+    pub dead_letter_path: PathBuf,
+    pub max_attempts: u32,
+    /// Wait before the first retry; it doubles with each retry after.
+    pub initial_backoff: Duration,
+    pub endpoints: BTreeMap<String, WebhookEndpoint>,
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookEndpoint {
+    pub url: String,
+    /// Key of the HMAC-SHA256 signature on each payload.
+    pub secret: Secret,
+    /// Events sent to the endpoint; every event when empty.
+    pub events: Vec<EventKind>,
+    pub timeout: Duration,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HealthConfig {
+    #[serde(default = "default_readiness_timeout_ms")]
+    pub readiness_timeout_ms: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            readiness_timeout_ms: default_readiness_timeout_ms(),
+        }
+    }
+}
+
+fn default_readiness_timeout_ms() -> u64 {
+    2000
+}
+
+/// Who may call the SCIM endpoints besides admins.
+#[derive(Debug, Clone, Default)]
+pub struct ScimConfig {
+    /// Sent by provisioning clients as `Authorization: Bearer <token>`.
+    pub bearer_token: Option<Secret>,
+}
+
+/// Which browser origins may call the API. An origin is allowed when it is
+/// listed, or `*` is, or it matches one of the patterns as a whole. Nobody
+/// is allowed unless configured.
+#[derive(Debug, Clone, Default)]
+pub struct CorsConfig {
+    /// Lowercased, as browsers send them.
+    pub allowed_origins: Vec<String>,
+    pub allowed_origin_patterns: Vec<Regex>,
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight, in seconds.
+    pub max_age_secs: u64,
+}
+
+impl LdapConfig {
+    /// The configured servers, for log messages.
+    pub fn describe_servers(&self) -> String {
+        match &self.discovery {
+            Some(discovery) => format!("SRV records for {}", discovery.domain),
+            None => self
+                .servers
+                .iter()
+                .map(|s| s.url.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+        }
+    }
+}
+
+/// Validated service configuration, loaded once at startup.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub directories: BTreeMap<String, LdapConfig>,
+    pub default_directory: String,
+    pub cpanel: Option<CpanelConfig>,
+    pub templates: BTreeMap<String, TemplateConfig>,
+    pub password_policy: PasswordPolicy,
+    pub auth: AuthConfig,
+    pub audit: AuditConfig,
+    pub jobs: JobsConfig,
+    pub webhooks: WebhooksConfig,
+    pub change_feed: ChangeFeedConfig,
+    pub health: HealthConfig,
+    pub cors: CorsConfig,
+    pub scim: ScimConfig,
+}
+
+impl Config {
+    /// Layers, lowest priority first: Rocket's own figment (`Rocket.toml` and
+    /// `ROCKET_*`), `ldap-api.toml`, `ldap-api.yaml`, the file named by
+    /// `LDAP_API_CONFIG`, the legacy environment variables and finally
+    /// `LDAP_API_*` variables, with `__` separating nested keys. Any
+    /// `<NAME>_FILE` variable is read as the path to a file holding `<NAME>`.
+    pub fn figment() -> Figment {
+        let mut figment = rocket::Config::figment()
+            .merge(Toml::file("ldap-api.toml"))
+            .merge(Yaml::file("ldap-api.yaml"));
+
+        if let Ok(path) = std::env::var("LDAP_API_CONFIG") {
+            figment = match Path::new(&path).extension().and_then(|e| e.to_str()) {
+                Some("yaml") | Some("yml") => figment.merge(Yaml::file(path)),
+                _ => figment.merge(Toml::file(path)),
+            };
+        }
+
+        figment
+            .merge(legacy_env())
+            .merge(Env::prefixed("LDAP_API_").ignore(&["CONFIG"]).split("__"))
+    }
+
+    /// Extracts and validates the configuration, collecting every problem
+    /// found rather than stopping at the first.
+    pub fn from_figment(figment: &Figment) -> Result<Config, Vec<String>> {
+        let raw: RawConfig = figment
+            .extract()
+            .map_err(|e| e.into_iter().map(|e| e.to_string()).collect::<Vec<_>>())?;
+
+        let mut errors = Vec::new();
+
+        let mut directories = BTreeMap::new();
+        for (name, raw_directory) in raw.directories {
+            let prefix = format!("directories.{}", name);
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                errors.push(format!(
+                    "{}: directory names may only contain letters, digits, '-' and '_'",
+                    prefix
+                ));
+            }
+            directories.insert(name, validate_ldap(&prefix, raw_directory, &mut errors));
+        }
+
+        match raw.ldap {
+            Some(_) if directories.contains_key(DEFAULT_DIRECTORY) => errors.push(format!(
+                "ldap and directories.{} both configure the default directory",
+                DEFAULT_DIRECTORY
+            )),
+            Some(raw_ldap) => {
+                let ldap = validate_ldap("ldap", raw_ldap, &mut errors);
+                directories.insert(DEFAULT_DIRECTORY.to_string(), ldap);
+            }
+            None if directories.is_empty() => {
+                errors.push("ldap or at least one entry in directories is required".to_string())
+            }
+            None => {}
+        }
+
+        // The Kerberos library reads the client keytab from the environment,
+        // so it is shared by the whole process.
+        let mut keytabs = directories.values().filter_map(|d| d.bind.keytab.as_ref());
+        if let Some(first) = keytabs.next() {
+            if keytabs.any(|k| k != first) {
+                errors.push("all directories must use the same bind.keytab".to_string());
+            }
+        }
+
+        let default_directory = match raw.default_directory {
+            Some(name) => {
+                if !directories.contains_key(&name) {
+                    errors.push(format!("default_directory {} is not configured", name));
+                }
+                name
+            }
+            None if directories.len() == 1 => directories.keys().next().unwrap().clone(),
+            None if directories.contains_key(DEFAULT_DIRECTORY) => DEFAULT_DIRECTORY.to_string(),
+            None => {
+                if !directories.is_empty() {
+                    errors.push(
+                        "default_directory is required when several directories are configured"
+                            .to_string(),
+                    );
+                }
+                String::new()
+            }
+        };
+
+        let cpanel = raw.cpanel.and_then(|c| {
+            if c.timeout_ms == 0 {
+                errors.push("cpanel.timeout_ms must be at least 1".to_string());
+            }
+            let access_token = resolve_secret(
+                "cpanel.access_token",
+                c.access_token,
+                c.access_token_file,
+                &mut errors,
+            );
+            match (c.url, access_token) {
+                (None, None) => None,
+                (Some(url), Some(access_token)) => Some(CpanelConfig {
+                    url: url.trim_end_matches('/').to_string(),
+                    access_token,
+                    timeout: Duration::from_millis(c.timeout_ms),
+                }),
+                _ => {
+                    errors.push(
+                        "cpanel.url and cpanel.access_token must be set together".to_string(),
+                    );
+                    None
+                }
+            }
+        });
+
+        // Mailboxes are only provisioned by templates that ask for them
+        let mut templates = raw.templates;
+        if templates.is_empty() {
+            templates.insert(
+                DEFAULT_TEMPLATE.to_string(),
+                TemplateConfig {
+                    mailbox: MailboxKind::None,
+                    mail_domain: None,
+                    mailbox_quota_mb: default_mailbox_quota_mb(),
+                    mailbox_on_delete: MailboxDeletion::default(),
+                },
+            );
+        }
+        if raw.password_policy.min_classes > 4 {
+            errors.push("password_policy.min_classes must be at most 4".to_string());
+        }
+        if raw.jobs.max_running == 0 {
+            errors.push("jobs.max_running must be at least 1".to_string());
+        }
+        let webhooks = validate_webhooks(raw.webhooks, &mut errors);
+        let cors = validate_cors(raw.cors, &mut errors);
+        let auth = validate_auth(raw.auth, &mut errors);
+        let bearer_token = resolve_secret(
+            "scim.bearer_token",
+            raw.scim.bearer_token,
+            raw.scim.bearer_token_file,
+            &mut errors,
+        )
+        .filter(|token| !token.expose().is_empty());
+        if raw.change_feed.poll_interval_ms < 1000 {
+            errors.push("change_feed.poll_interval_ms must be at least 1000".to_string());
+        }
+        for (name, template) in &templates {
+            if template.mailbox == MailboxKind::Cpanel && cpanel.is_none() {
+                errors.push(format!(
+                    "templates.{}.mailbox is cpanel but cpanel is not configured",
+                    name
+                ));
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        Ok(Config {
+            directories,
+            default_directory,
+            cpanel,
+            templates,
+            password_policy: raw.password_policy,
+            auth,
+            audit: raw.audit,
+            jobs: raw.jobs,
+            webhooks,
+            change_feed: raw.change_feed,
+            health: raw.health,
+            cors,
+            scim: ScimConfig { bearer_token },
+        })
+    }
+}
+
+/// The name given to the directory configured through the `ldap` section.
+pub const DEFAULT_DIRECTORY: &str = "default";
+
+fn validate_auth(raw: RawAuthConfig, errors: &mut Vec<String>) -> AuthConfig {
+    let mut trusted_proxies = Vec::new();
+    for proxy in &raw.trusted_proxies {
+        match proxy
+            .parse::<IpNet>()
+            .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+        {
+            Ok(net) => trusted_proxies.push(net),
+            Err(_) => errors.push(format!(
+                "auth.trusted_proxies: {} is not an address or CIDR range",
+                proxy
+            )),
+        }
+    }
+    AuthConfig {
+        admins: raw.admins,
+        admin_groups: raw.admin_groups,
+        trusted_proxies,
+    }
+}
+
+fn validate_cors(raw: RawCorsConfig, errors: &mut Vec<String>) -> CorsConfig {
+    for origin in &raw.allowed_origins {
+        if origin == "*" {
+            if raw.allow_credentials {
+                errors.push(
+                    "cors.allowed_origins cannot hold * while cors.allow_credentials is true"
+                        .to_string(),
+                );
+            }
+            continue;
+        }
+        let host = origin
+            .strip_prefix("https://")
+            .or_else(|| origin.strip_prefix("http://"));
+        if !matches!(host, Some(host) if !host.is_empty() && !host.contains('/')) {
+            errors.push(format!(
+                "cors.allowed_origins: {} is not an origin, as in https://app.example.com",
+                origin
+            ));
+        }
+    }
+    // Anchored, so that a pattern for example.com cannot be satisfied by
+    // example.com.attacker.net
+    let mut patterns = Vec::new();
+    for pattern in &raw.allowed_origin_patterns {
+        match Regex::new(&format!("^(?:{})$", pattern)) {
+            Ok(regex) => patterns.push(regex),
+            Err(e) => errors.push(format!(
+                "cors.allowed_origin_patterns: {} is not a valid pattern: {}",
+                pattern, e
+            )),
+        }
+    }
+    CorsConfig {
+        allowed_origins: raw
+            .allowed_origins
+            .iter()
+            .map(|origin| origin.to_lowercase())
+            .collect(),
+        allowed_origin_patterns: patterns,
+        allow_credentials: raw.allow_credentials,
+        max_age_secs: raw.max_age_secs,
+    }
+}
+
+fn validate_webhooks(raw: RawWebhooksConfig, errors: &mut Vec<String>) -> WebhooksConfig {
+    if raw.max_attempts == 0 {
+        errors.push("webhooks.max_attempts must be at least 1".to_string());
+    }
+    let mut endpoints = BTreeMap::new();
+    for (name, endpoint) in raw.endpoints {
+        let prefix = format!("webhooks.endpoints.{}", name);
+        let url = match endpoint.url {
+            Some(url) if url.starts_with("https://") || url.starts_with("http://") => url,
+            Some(url) => {
+                errors.push(format!(
+                    "{}.url must be an http(s) URL, got {}",
+                    prefix, url
+                ));
+                continue;
+            }
+            None => {
+                errors.push(format!("{}.url is required", prefix));
+                continue;
+            }
+        };
+        if endpoint.timeout_ms == 0 {
+            errors.push(format!("{}.timeout_ms must be at least 1", prefix));
+        }
+        let secret = resolve_secret(
+            &format!("{}.secret", prefix),
+            endpoint.secret,
+            endpoint.secret_file,
+            errors,
+        );
+        let secret = match secret {
+            Some(secret) if !secret.expose().is_empty() => secret,
+            _ => {
+                errors.push(format!("{}.secret is required", prefix));
+                continue;
+            }
+        };
+        endpoints.insert(
+            name,
+            WebhookEndpoint {
+                url,
+                secret,
+                events: endpoint.events,
+                timeout: Duration::from_millis(endpoint.timeout_ms),
+            },
+        );
+    }
+    WebhooksConfig {
+        dead_letter_path: raw.dead_letter_path,
+        max_attempts: raw.max_attempts,
+        initial_backoff: Duration::from_millis(raw.initial_backoff_ms),
+        endpoints,
+    }
+}
+
+fn validate_ldap(prefix: &str, raw: RawLdapConfig, errors: &mut Vec<String>) -> LdapConfig {
+    let mut required = |value: Option<String>, name: &str| match value {
+        Some(value) if !value.trim().is_empty() => value,
+        _ => {
+            errors.push(format!("{}.{} is required", prefix, name));
+            String::new()
+        }
+    };
+
+    let base_dn = required(raw.base_dn, "base_dn");
+
+    let simple = raw.bind.mechanism == BindMechanism::Simple;
+    let username = if simple {
+        required(raw.username, "username")
+    } else {
+        raw.username.unwrap_or_default()
+    };
+
+    if let Some(groups_dn) = &raw.groups_dn {
+        if !groups_dn
+            .to_lowercase()
+            .ends_with(&format!(",{}", base_dn.to_lowercase()))
+        {
+            errors.push(format!(
+                "{}.groups_dn must be under {}.base_dn",
+                prefix, prefix
+            ));
+        }
+    }
+    if raw.pool_size == 0 {
+        errors.push(format!("{}.pool_size must be at least 1", prefix));
+    }
+    let t = &raw.timeouts;
+    for (name, ms) in [
+        ("bind_ms", t.bind_ms),
+        ("search_ms", t.search_ms),
+        ("add_ms", t.add_ms),
+        ("modify_ms", t.modify_ms),
+        ("delete_ms", t.delete_ms),
+        ("max_ms", t.max_ms),
+    ] {
+        if ms == 0 {
+            errors.push(format!("{}.timeouts.{} must be at least 1", prefix, name));
+        }
+    }
+
+    let servers = match (raw.server, raw.servers, &raw.discovery) {
+        (Some(url), None, None) => vec![LdapServer {
+            url,
+            priority: 0,
+            weight: 0,
+        }],
+        (None, Some(servers), None) if !servers.is_empty() => servers,
+        (None, None, Some(discovery)) => {
+            if discovery.domain.trim().is_empty() {
+                errors.push(format!("{}.discovery.domain is required", prefix));
+            }
+            vec![]
+        }
+        (None, None, None) | (None, Some(_), None) => {
+            errors.push(format!(
+                "{}.server, {}.servers or {}.discovery is required",
+                prefix, prefix, prefix
+            ));
+            vec![]
+        }
+        _ => {
+            errors.push(format!(
+                "only one of {}.server, {}.servers and {}.discovery may be set",
+                prefix, prefix, prefix
+            ));
+            vec![]
+        }
+    };
+    for server in &servers {
+        if !server.url.starts_with("ldap://") && !server.url.starts_with("ldaps://") {
+            errors.push(format!(
+                "{}.server must be an ldap:// or ldaps:// URL, got {}",
+                prefix, server.url
+            ));
+        }
+    }
+
+    let password = match (raw.password, raw.password_file) {
+        (None, None) => {
+            if simple {
+                errors.push(format!("{}.password is required", prefix));
+            }
+            None
+        }
+        (password, file) => resolve_secret(&format!("{}.password", prefix), password, file, errors),
+    };
+
+    let tls = validate_tls(prefix, raw.tls, errors);
+    match raw.bind.mechanism {
+        BindMechanism::External if tls.client_cert_file.is_none() => errors.push(format!(
+            "{}.bind.mechanism external needs {}.tls.client_cert_file",
+            prefix, prefix
+        )),
+        BindMechanism::Gssapi if !cfg!(feature = "gssapi") => errors.push(format!(
+            "{}.bind.mechanism gssapi needs a build with the gssapi feature",
+            prefix
+        )),
+        _ => {}
+    }
+    if let Some(keytab) = &raw.bind.keytab {
+        if let Err(e) = std::fs::metadata(keytab) {
+            errors.push(format!(
+                "{}.bind.keytab: cannot read {}: {}",
+                prefix,
+                keytab.display(),
+                e
+            ));
+        }
+    }
+
+    LdapConfig {
+        servers,
+        discovery: raw.discovery,
+        username,
+        password,
+        base_dn,
+        groups_dn: raw.groups_dn,
+        flavor: raw.flavor,
+        pool_size: raw.pool_size,
+        attributes: AttributeMap::from_config(prefix, raw.attributes, errors),
+        tls,
+        bind: BindConfig {
+            mechanism: raw.bind.mechanism,
+            keytab: raw.bind.keytab,
+            spn_host: raw.bind.spn_host,
+        },
+        timeouts: raw.timeouts,
+    }
+}
+
+fn validate_tls(prefix: &str, raw: RawTlsConfig, errors: &mut Vec<String>) -> TlsConfig {
+    let min_version = match raw.min_version.as_deref() {
+        Some("1.0") => TlsVersion::Tls10,
+        Some("1.1") => TlsVersion::Tls11,
+        Some("1.2") | None => TlsVersion::Tls12,
+        Some(other) => {
+            errors.push(format!(
+                "{}.tls.min_version must be one of 1.0, 1.1 or 1.2, got {}",
+                prefix, other
+            ));
+            TlsVersion::Tls12
+        }
+    };
+
+    if raw.client_cert_file.is_some() != raw.client_key_file.is_some() {
+        errors.push(format!(
+            "{}.tls.client_cert_file and {}.tls.client_key_file must be set together",
+            prefix, prefix
+        ));
+    }
+    for (name, path) in [
+        ("ca_file", &raw.ca_file),
+        ("client_cert_file", &raw.client_cert_file),
+        ("client_key_file", &raw.client_key_file),
+    ] {
+        if let Some(path) = path {
+            if let Err(e) = std::fs::metadata(path) {
+                errors.push(format!(
+                    "{}.tls.{}: cannot read {}: {}",
+                    prefix,
+                    name,
+                    path.display(),
+                    e
+                ));
+            }
+        }
+    }
+
+    TlsConfig {
+        starttls: raw.starttls.unwrap_or(true),
+        ca_file: raw.ca_file,
+        client_cert_file: raw.client_cert_file,
+        client_key_file: raw.client_key_file,
+        min_version,
+        insecure_skip_verify: raw.insecure_skip_verify,
+    }
+}
+
+/// Maps `LDAP_SERVER`, `LOGIN_PASSWORD_FILE` and friends onto config keys.
+/// `_FILE` variants of the non-secret settings are ignored.
+fn legacy_env() -> Env {
+    Env::raw().filter_map(|key| {
+        let key = key.as_str();
+        let (name, file) = match key.strip_suffix("_FILE") {
+            Some(name) => (name, true),
+            None => (key, false),
+        };
+        let (_, path) = LEGACY_ENV
+            .iter()
+            .find(|(env, _)| env.eq_ignore_ascii_case(name))?;
+
+        let is_secret = path.ends_with("password") || path.ends_with("access_token");
+        match (file, is_secret) {
+            (false, _) => Some(path.to_string().into()),
+            (true, true) => Some(format!("{}_file", path).into()),
+            (true, false) => None,
+        }
+    })
+}
@@ -0,0 +1,193 @@
+use std::str::FromStr;
+
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::http::{Header, Method, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder, Response};
+use rocket::{Build, Request, Rocket};
+
+use crate::config::CorsConfig;
+use crate::errors::Rejection;
+use crate::ServerState;
+
+/// Adds CORS headers to responses for the origins allowed in the `cors`
+/// configuration. The allowed origin is echoed back rather than `*`, which
+/// browsers refuse together with credentials.
+pub struct Cors;
+
+/// The `cors` configuration and the methods each mounted path is served
+/// with, gathered on ignite.
+pub struct CorsPolicy {
+    config: CorsConfig,
+    routes: Vec<(Method, String)>,
+}
+
+impl CorsPolicy {
+    pub fn allows(&self, origin: &str) -> bool {
+        let origin = origin.to_lowercase();
+        self.config
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || *allowed == origin)
+            || self
+                .config
+                .allowed_origin_patterns
+                .iter()
+                .any(|pattern| pattern.is_match(&origin))
+    }
+
+    /// The methods of the routes matching `path`, preflights aside.
+    fn methods(&self, path: &str) -> Vec<Method> {
+        let mut methods: Vec<Method> = Vec::new();
+        for (method, route) in &self.routes {
+            if route_matches(route, path) && !methods.contains(method) {
+                methods.push(*method);
+            }
+        }
+        methods.sort_by_key(|method| method.as_str());
+        methods
+    }
+}
+
+/// Whether a mounted route path, as in `/d/main/users/<uname>`, matches a
+/// request path.
+fn route_matches(route: &str, path: &str) -> bool {
+    let mut segments = path.split('/').filter(|segment| !segment.is_empty());
+    for pattern in route.split('/').filter(|segment| !segment.is_empty()) {
+        if pattern.starts_with('<') && pattern.ends_with("..>") {
+            return true;
+        }
+        match segments.next() {
+            Some(segment) if pattern.starts_with('<') || pattern == segment => {}
+            _ => return false,
+        }
+    }
+    segments.next().is_none()
+}
+
+#[rocket::async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "Add CORS headers to responses",
+            kind: Kind::Ignite | Kind::Response,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let config = rocket
+            .state::<ServerState>()
+            .map(|state| state.config.cors.clone())
+            .unwrap_or_default();
+        let routes = rocket
+            .routes()
+            .filter(|route| route.method != Method::Options)
+            .map(|route| (route.method, route.uri.path().to_string()))
+            .collect();
+        Ok(rocket.manage(CorsPolicy { config, routes }))
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let Some(policy) = request.rocket().state::<CorsPolicy>() else {
+            return;
+        };
+        // Whether the headers below are sent depends on the origin, so
+        // caches must not hand one origin's response to another
+        response.adjoin_header(Header::new("Vary", "Origin"));
+        let Some(origin) = request.headers().get_one("Origin") else {
+            return;
+        };
+        if !policy.allows(origin) {
+            return;
+        }
+        response.set_header(Header::new(
+            "Access-Control-Allow-Origin",
+            origin.to_string(),
+        ));
+        if policy.config.allow_credentials {
+            response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+        }
+    }
+}
+
+/// An `OPTIONS` request for a mounted path, answered with the methods the
+/// path is served with. CORS preflights from an allowed origin also get the
+/// headers the browser asked for and how long to cache the answer; those
+/// from other origins, or for a method the path is not served with, are
+/// turned away.
+pub struct Preflight {
+    methods: Vec<Method>,
+    /// Only set for preflights.
+    request_headers: Option<String>,
+    max_age_secs: Option<u64>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Preflight {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(policy) = request.rocket().state::<CorsPolicy>() else {
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+        let path = request.uri().path();
+        let methods = policy.methods(path.as_str());
+        if methods.is_empty() {
+            return Outcome::Error((Status::NotFound, ()));
+        }
+
+        let headers = request.headers();
+        let (Some(origin), Some(requested)) = (
+            headers.get_one("Origin"),
+            headers.get_one("Access-Control-Request-Method"),
+        ) else {
+            return Outcome::Success(Preflight {
+                methods,
+                request_headers: None,
+                max_age_secs: None,
+            });
+        };
+        if !policy.allows(origin) {
+            request.local_cache(|| Rejection(Some(format!("origin {} is not allowed", origin))));
+            return Outcome::Error((Status::Forbidden, ()));
+        }
+        if !Method::from_str(requested).is_ok_and(|method| methods.contains(&method)) {
+            request.local_cache(|| {
+                Rejection(Some(format!("{} is not served at {}", requested, path)))
+            });
+            return Outcome::Error((Status::MethodNotAllowed, ()));
+        }
+        Outcome::Success(Preflight {
+            methods,
+            request_headers: Some(
+                headers
+                    .get_one("Access-Control-Request-Headers")
+                    .unwrap_or_default()
+                    .to_string(),
+            ),
+            max_age_secs: Some(policy.config.max_age_secs),
+        })
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Preflight {
+    fn respond_to(self, _request: &'r Request<'_>) -> response::Result<'static> {
+        let mut methods: Vec<&str> = self.methods.iter().map(|method| method.as_str()).collect();
+        methods.push(Method::Options.as_str());
+        let methods = methods.join(", ");
+
+        let mut response = Response::build();
+        response
+            .status(Status::NoContent)
+            .header(Header::new("Allow", methods.clone()));
+        if let (Some(request_headers), Some(max_age)) = (self.request_headers, self.max_age_secs) {
+            response
+                .header(Header::new("Access-Control-Allow-Methods", methods))
+                .header(Header::new("Access-Control-Max-Age", max_age.to_string()));
+            if !request_headers.is_empty() {
+                response.header(Header::new("Access-Control-Allow-Headers", request_headers));
+            }
+        }
+        response.ok()
+    }
+}
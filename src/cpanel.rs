@@ -0,0 +1,240 @@
+use std::fmt;
+
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::config::CpanelConfig;
+use crate::metrics::metrics;
+
+/// The result object every UAPI function answers with.
+#[derive(Deserialize, Debug)]
+pub struct UapiResponse {
+    /// 1 on success, 0 on failure.
+    pub status: u8,
+    #[serde(default)]
+    pub errors: Option<Vec<String>>,
+    #[serde(default)]
+    pub messages: Option<Vec<String>>,
+    #[serde(default)]
+    pub warnings: Option<Vec<String>>,
+    #[serde(default)]
+    pub data: Value,
+}
+
+#[derive(Debug)]
+pub enum CpanelError {
+    /// cPanel could not be reached or did not answer in time.
+    Request(reqwest::Error),
+    /// cPanel answered with an HTTP error, usually a rejected token.
+    Http(StatusCode),
+    /// The body was not a UAPI result.
+    Parse(String),
+    /// The function ran and failed.
+    Failed(Vec<String>),
+}
+
+impl fmt::Display for CpanelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpanelError::Request(e) if e.is_timeout() => f.write_str("cPanel timed out"),
+            CpanelError::Request(e) => write!(f, "cannot reach cPanel: {}", e),
+            CpanelError::Http(status) => write!(f, "cPanel answered HTTP {}", status.as_u16()),
+            CpanelError::Parse(e) => write!(f, "unreadable cPanel response: {}", e),
+            CpanelError::Failed(errors) if errors.is_empty() => {
+                f.write_str("cPanel reported failure")
+            }
+            CpanelError::Failed(errors) => f.write_str(&errors.join("; ")),
+        }
+    }
+}
+
+/// Calls UAPI functions with the configured API token, reusing connections
+/// between calls.
+pub struct CpanelClient {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl CpanelClient {
+    pub fn new(config: &CpanelConfig) -> Result<CpanelClient, String> {
+        let mut token = HeaderValue::from_str(config.access_token.expose())
+            .map_err(|_| "cpanel.access_token is not a valid header value".to_string())?;
+        token.set_sensitive(true);
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, token);
+
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .timeout(config.timeout)
+            .build()
+            .map_err(|e| format!("cannot build cPanel client: {}", e))?;
+        Ok(CpanelClient {
+            client,
+            url: config.url.clone(),
+        })
+    }
+
+    /// Calls `module::function`, sending `params` as a form body so that
+    /// passwords stay out of URLs and access logs.
+    pub async fn call(
+        &self,
+        module: &str,
+        function: &str,
+        params: &[(&str, &str)],
+    ) -> Result<UapiResponse, CpanelError> {
+        let res = self.send(module, function, params).await;
+        metrics().observe_cpanel(function, res.is_ok());
+        res
+    }
+
+    async fn send(
+        &self,
+        module: &str,
+        function: &str,
+        params: &[(&str, &str)],
+    ) -> Result<UapiResponse, CpanelError> {
+        let res = self
+            .client
+            .post(format!("{}/execute/{}/{}", self.url, module, function))
+            .form(params)
+            .send()
+            .await
+            .map_err(CpanelError::Request)?;
+        let status = res.status();
+        if !status.is_success() {
+            return Err(CpanelError::Http(status));
+        }
+        let body = res.bytes().await.map_err(CpanelError::Request)?;
+        let response: UapiResponse =
+            serde_json::from_slice(&body).map_err(|e| CpanelError::Parse(e.to_string()))?;
+
+        for message in response.messages.iter().flatten() {
+            info!(function, message = %message, "cPanel message");
+        }
+        for warning in response.warnings.iter().flatten() {
+            warn!(function, warning = %warning, "cPanel warning");
+        }
+        if response.status != 1 {
+            return Err(CpanelError::Failed(response.errors.unwrap_or_default()));
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use rocket::tokio::net::TcpListener;
+    use rocket::tokio::task::JoinHandle;
+    use rocket::tokio::time::sleep;
+
+    use super::*;
+
+    /// Answers a single request with `body` as JSON after `delay`, and hands
+    /// back the request it read.
+    async fn mock(body: &'static str, delay: Duration) -> (CpanelClient, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = rocket::tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            loop {
+                let read = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..read]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length = text[..end]
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length: "))
+                        .map_or(0, |length| length.parse::<usize>().unwrap());
+                    if request.len() >= end + 4 + length {
+                        break;
+                    }
+                }
+            }
+            sleep(delay).await;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            // The client is gone when it timed out
+            let _ = stream.write_all(response.as_bytes()).await;
+            String::from_utf8(request).unwrap()
+        });
+
+        let config = CpanelConfig {
+            url,
+            access_token: serde_json::from_value(serde_json::json!("cpanel user:TOKEN")).unwrap(),
+            timeout: Duration::from_millis(500),
+        };
+        (CpanelClient::new(&config).unwrap(), server)
+    }
+
+    #[rocket::async_test]
+    async fn call_succeeds() {
+        let (client, server) = mock(
+            r#"{"status":1,"errors":null,"messages":["created"],"data":{"id":7}}"#,
+            Duration::ZERO,
+        )
+        .await;
+
+        let response = client
+            .call(
+                "Email",
+                "add_pop",
+                &[("email", "jo"), ("password", "s3cret&")],
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status, 1);
+        assert_eq!(response.data["id"], 7);
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /execute/Email/add_pop HTTP/1.1\r\n"));
+        assert!(request.contains("authorization: cpanel user:TOKEN\r\n"));
+        assert!(request.ends_with("email=jo&password=s3cret%26"));
+    }
+
+    #[rocket::async_test]
+    async fn call_reports_uapi_errors() {
+        let (client, _server) = mock(
+            r#"{"status":0,"errors":["The account jo already exists."],"data":null}"#,
+            Duration::ZERO,
+        )
+        .await;
+
+        match client.call("Email", "add_pop", &[]).await {
+            Err(CpanelError::Failed(errors)) => {
+                assert_eq!(errors, vec!["The account jo already exists."])
+            }
+            other => panic!("expected a UAPI failure, got {:?}", other),
+        }
+    }
+
+    #[rocket::async_test]
+    async fn call_rejects_non_json_bodies() {
+        let (client, _server) = mock("<html>Login</html>", Duration::ZERO).await;
+
+        assert!(matches!(
+            client.call("Email", "add_pop", &[]).await,
+            Err(CpanelError::Parse(_))
+        ));
+    }
+
+    #[rocket::async_test]
+    async fn call_times_out() {
+        let (client, _server) = mock(r#"{"status":1}"#, Duration::from_secs(2)).await;
+
+        match client.call("Email", "add_pop", &[]).await {
+            Err(e @ CpanelError::Request(_)) => assert_eq!(e.to_string(), "cPanel timed out"),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+}
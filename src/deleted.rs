@@ -0,0 +1,176 @@
+#![allow(non_snake_case)]
+
+use std::collections::HashSet;
+
+use ldap3::controls::RawControl;
+use ldap3::{Mod, Scope, SearchEntry};
+use serde::{Deserialize, Serialize};
+use tracing::{info_span, Instrument};
+
+use crate::directory::Connection;
+use crate::errors::APIErrors;
+use crate::metrics::ldap_timed;
+use crate::timeout::Operation;
+use crate::util::{escape_bytes, format_guid, parse_guid};
+
+// LDAP_SERVER_SHOW_DELETED_OID, makes tombstoned and recycled objects visible
+const SHOW_DELETED_OID: &str = "1.2.840.113556.1.4.417";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeletedUser {
+    pub objectGUID: String,
+    pub distinguishedName: Option<String>,
+    pub cn: Option<String>,
+    pub sAMAccountName: Option<String>,
+    pub userPrincipalName: Option<String>,
+    pub lastKnownParent: Option<String>,
+    pub lastKnownRDN: Option<String>,
+    pub whenChanged: Option<String>,
+}
+
+impl DeletedUser {
+    pub async fn fetch_all_deleted(ldap: &mut Connection) -> Result<Vec<DeletedUser>, APIErrors> {
+        let base_dn = deleted_objects_dn(ldap).await?;
+
+        let (rs, _res) = ldap_timed(
+            "search",
+            ldap.op(Operation::Search)
+                .with_controls(show_deleted())
+                .search(
+                    base_dn.as_str(),
+                    Scope::OneLevel,
+                    "(&(objectClass=user)(isDeleted=TRUE))",
+                    vec!["*"],
+                ),
+        )
+        .instrument(info_span!("ldap.search", base = %base_dn))
+        .await
+        .map_err(APIErrors::from)?
+        .success()
+        .map_err(|_| APIErrors::InternalError)?;
+
+        Ok(rs
+            .into_iter()
+            .filter_map(|entry| DeletedUser::from_entry(SearchEntry::construct(entry)))
+            .collect())
+    }
+
+    pub async fn fetch_deleted(
+        ldap: &mut Connection,
+        guid: &str,
+    ) -> Result<DeletedUser, APIErrors> {
+        let guid_bytes = parse_guid(guid).ok_or(APIErrors::EntryNotFound)?;
+        let base_dn = deleted_objects_dn(ldap).await?;
+        let filter = format!(
+            "(&(objectClass=user)(isDeleted=TRUE)(objectGUID={}))",
+            escape_bytes(&guid_bytes)
+        );
+
+        let (rs, _res) = ldap_timed(
+            "search",
+            ldap.op(Operation::Search)
+                .with_controls(show_deleted())
+                .search(base_dn.as_str(), Scope::OneLevel, &filter, vec!["*"]),
+        )
+        .instrument(info_span!("ldap.search", base = %base_dn))
+        .await
+        .map_err(APIErrors::from)?
+        .success()
+        .map_err(|_| APIErrors::InternalError)?;
+
+        let entry = rs.into_iter().next().ok_or(APIErrors::EntryNotFound)?;
+        DeletedUser::from_entry(SearchEntry::construct(entry)).ok_or(APIErrors::EntryNotFound)
+    }
+
+    /// Reanimates a deleted user by removing `isDeleted` and moving it back
+    /// under its last known parent. Returns the DN the user was restored to.
+    pub async fn restore(ldap: &mut Connection, guid: &str) -> Result<String, APIErrors> {
+        let deleted = Self::fetch_deleted(ldap, guid).await?;
+
+        let current_dn = deleted.distinguishedName.ok_or(APIErrors::EntryNotFound)?;
+        let parent = deleted.lastKnownParent.ok_or(APIErrors::UpdateError)?;
+        let rdn = deleted
+            .lastKnownRDN
+            .or(deleted.cn.map(|cn| strip_deleted_mangling(&cn)))
+            .ok_or(APIErrors::UpdateError)?;
+        let restored_dn = format!("CN={},{}", ldap3::dn_escape(rdn), parent);
+
+        let mods: Vec<Mod<&str>> = vec![
+            Mod::Delete("isDeleted", HashSet::new()),
+            Mod::Replace("distinguishedName", HashSet::from([restored_dn.as_str()])),
+        ];
+
+        let res = ldap_timed(
+            "modify",
+            ldap.op(Operation::Modify)
+                .with_controls(show_deleted())
+                .modify(current_dn.as_str(), mods),
+        )
+        .instrument(info_span!("ldap.modify", dn = %current_dn))
+        .await
+        .map_err(APIErrors::from)?;
+
+        match res.success() {
+            Ok(_) => Ok(restored_dn),
+            Err(ldap3::LdapError::LdapResult { result }) if result.rc == 68 => {
+                Err(APIErrors::EntryExists)
+            }
+            Err(_) => Err(APIErrors::UpdateError),
+        }
+    }
+
+    fn from_entry(entry: SearchEntry) -> Option<DeletedUser> {
+        let guid = entry.bin_attrs.get("objectGUID")?.first()?;
+        let first = |name: &str| entry.attrs.get(name).and_then(|v| v.first().cloned());
+
+        Some(DeletedUser {
+            objectGUID: format_guid(guid)?,
+            distinguishedName: Some(entry.dn.clone()),
+            cn: first("cn"),
+            sAMAccountName: first("sAMAccountName"),
+            userPrincipalName: first("userPrincipalName"),
+            lastKnownParent: first("lastKnownParent"),
+            lastKnownRDN: first("msDS-LastKnownRDN"),
+            whenChanged: first("whenChanged"),
+        })
+    }
+}
+
+pub fn show_deleted() -> RawControl {
+    RawControl {
+        ctype: SHOW_DELETED_OID.to_string(),
+        crit: true,
+        val: None,
+    }
+}
+
+pub async fn deleted_objects_dn(ldap: &mut Connection) -> Result<String, APIErrors> {
+    let (rs, _res) = ldap_timed(
+        "search",
+        ldap.op(Operation::Search).search(
+            "",
+            Scope::Base,
+            "(objectClass=*)",
+            vec!["defaultNamingContext"],
+        ),
+    )
+    .instrument(info_span!("ldap.search", base = "rootDSE"))
+    .await
+    .map_err(APIErrors::from)?
+    .success()
+    .map_err(|_| APIErrors::InternalError)?;
+
+    let entry = SearchEntry::construct(rs.into_iter().next().ok_or(APIErrors::InternalError)?);
+    let naming_context = entry
+        .attrs
+        .get("defaultNamingContext")
+        .and_then(|v| v.first())
+        .ok_or(APIErrors::InternalError)?;
+
+    Ok(format!("CN=Deleted Objects,{}", naming_context))
+}
+
+// Deleted objects get "\0ADEL:<guid>" appended to their RDN
+fn strip_deleted_mangling(cn: &str) -> String {
+    cn.split('\n').next().unwrap_or(cn).to_string()
+}
@@ -0,0 +1,546 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use ldap3::adapters::{Adapter, EntriesOnly, PagedResults};
+use ldap3::controls::RawControl;
+use ldap3::{
+    drive, Ldap, LdapConnAsync, LdapConnSettings, LdapError, LdapResult, Scope, SearchEntry,
+};
+use native_tls::{Certificate, Identity, Protocol, TlsConnector};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::tokio::sync::{Mutex, MutexGuard};
+use rocket::tokio::time::{sleep, timeout, timeout_at};
+use rocket::Request;
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::config::{BindMechanism, LdapConfig, Secret, TlsVersion};
+use crate::errors::APIErrors;
+use crate::flavor::{self, DirectoryFlavor};
+use crate::metrics::{ldap_timed, metrics};
+use crate::servers::{candidates, FailedServers};
+use crate::timeout::{Operation, Timeouts};
+use crate::ServerState;
+
+/// Prefix under which every directory's routes are mounted, as in
+/// `/d/<directory>/users`. The default directory is also mounted at `/`.
+pub const MOUNT_PREFIX: &str = "/d/";
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Entries fetched per page of `Connection::search_paged`.
+const PAGE_SIZE: i32 = 500;
+
+/// A named directory profile and its pool of bound connections.
+pub struct Directory {
+    pub name: String,
+    pub config: LdapConfig,
+    pub flavor: Box<dyn DirectoryFlavor>,
+    settings: LdapConnSettings,
+    connections: Vec<Mutex<Connection>>,
+    next: AtomicUsize,
+    failed: FailedServers,
+    active: std::sync::Mutex<Option<String>>,
+}
+
+/// A bound connection, the server it is bound to and the timeouts of the
+/// request using it.
+pub struct Connection {
+    pub ldap: Ldap,
+    pub server: String,
+    pub timeouts: Timeouts,
+}
+
+impl Connection {
+    /// The handle to make an operation of the given kind with, bounded by its
+    /// timeout.
+    pub fn op(&mut self, operation: Operation) -> &mut Ldap {
+        self.ldap.with_timeout(self.timeouts.get(operation))
+    }
+
+    /// Whether an entry exists at `dn`.
+    pub async fn entry_exists(&mut self, dn: &str) -> Result<bool, APIErrors> {
+        let res = ldap_timed(
+            "search",
+            self.op(Operation::Search)
+                .search(dn, Scope::Base, "(objectClass=*)", vec!["1.1"]),
+        )
+        .instrument(info_span!("ldap.search", base = dn))
+        .await?;
+        match res.1.rc {
+            0 => Ok(!res.0.is_empty()),
+            // noSuchObject
+            32 => Ok(false),
+            _ => Err(APIErrors::InternalError),
+        }
+    }
+
+    /// A search fetched a page at a time and collected, for result sets
+    /// past the server's size limit.
+    pub async fn search_paged(
+        &mut self,
+        base: &str,
+        scope: Scope,
+        filter: &str,
+        attributes: Vec<&'static str>,
+        controls: Vec<RawControl>,
+    ) -> Result<Vec<SearchEntry>, LdapError> {
+        let adapters: Vec<Box<dyn Adapter<&'static str, Vec<&'static str>>>> = vec![
+            Box::new(EntriesOnly::new()),
+            Box::new(PagedResults::new(PAGE_SIZE)),
+        ];
+        let span = info_span!("ldap.search", base = base);
+        async {
+            let mut stream = self
+                .op(Operation::Search)
+                .with_controls(controls)
+                .streaming_search_with(adapters, base, scope, filter, attributes)
+                .await?;
+            let mut entries = Vec::new();
+            while let Some(entry) = stream.next().await? {
+                entries.push(SearchEntry::construct(entry));
+            }
+            stream.finish().await.success()?;
+            Ok(entries)
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+impl Deref for Connection {
+    type Target = Ldap;
+
+    fn deref(&self) -> &Ldap {
+        &self.ldap
+    }
+}
+
+impl DerefMut for Connection {
+    fn deref_mut(&mut self) -> &mut Ldap {
+        &mut self.ldap
+    }
+}
+
+impl Directory {
+    pub async fn connect(name: &str, config: &LdapConfig) -> Result<Directory, ConnectError> {
+        let mut directory = Directory {
+            name: name.to_string(),
+            config: config.clone(),
+            settings: connection_settings(config)?,
+            flavor: flavor::for_schema(config.flavor),
+            connections: Vec::with_capacity(config.pool_size),
+            next: AtomicUsize::new(0),
+            failed: FailedServers::default(),
+            active: std::sync::Mutex::new(None),
+        };
+        for _ in 0..config.pool_size {
+            let connection = directory.connect_any().await?;
+            directory.connections.push(Mutex::new(connection));
+        }
+
+        Ok(directory)
+    }
+
+    pub fn mount_point(&self) -> String {
+        format!("{}{}", MOUNT_PREFIX, self.name)
+    }
+
+    /// The server this directory most recently connected to.
+    pub fn active_server(&self) -> Option<String> {
+        self.active.lock().unwrap().clone()
+    }
+
+    /// Connects to the first server which answers, in priority order, and
+    /// holds down those which fail.
+    async fn connect_any(&self) -> Result<Connection, ConnectError> {
+        let servers = candidates(&self.config, &self.failed)
+            .await
+            .map_err(ConnectError::Discovery)?;
+
+        let mut last_error = None;
+        for server in servers {
+            match establish_ldap_connection(&server, &self.config, &self.settings).await {
+                Ok(ldap) => {
+                    self.failed.mark_ok(&server);
+                    let mut active = self.active.lock().unwrap();
+                    if active.as_deref() != Some(server.as_str()) {
+                        if active.is_some() {
+                            warn!(directory = %self.name, server = %server, "failed over to another server");
+                        }
+                        metrics().set_active_server(&self.name, active.as_deref(), &server);
+                        *active = Some(server.clone());
+                    }
+                    return Ok(Connection {
+                        ldap,
+                        server,
+                        timeouts: (&self.config.timeouts).into(),
+                    });
+                }
+                Err(e) => {
+                    warn!(directory = %self.name, server = %server, error = %e, "cannot use server");
+                    self.failed.mark_failed(&server);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| ConnectError::Discovery("no servers".to_string())))
+    }
+
+    /// Takes the first idle pooled connection, or queues on the next one in
+    /// turn when all of them are busy, for at most `limit`. The connection is
+    /// not checked.
+    pub async fn checkout(&self, limit: Duration) -> Result<MutexGuard<'_, Connection>, APIErrors> {
+        let start = Instant::now();
+        let first = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.connections.len();
+
+        let idle = (0..count)
+            .map(|i| &self.connections[(first + i) % count])
+            .find_map(|conn| conn.try_lock().ok());
+        let ldap = match idle {
+            Some(ldap) => ldap,
+            None => timeout(limit, self.connections[first % count].lock())
+                .await
+                .map_err(|_| {
+                    warn!(directory = %self.name, "timed out waiting for a pooled connection");
+                    APIErrors::Timeout
+                })?,
+        };
+
+        metrics().observe_lock_wait(&self.name, start.elapsed());
+        Ok(ldap)
+    }
+
+    /// Checks out a connection and makes sure it is still bound, failing over
+    /// to another server or reconnecting until the directory answers again.
+    /// Waiting for the connection and for the directory together take at
+    /// most the longest of `timeouts`, which then bound operations on it.
+    pub async fn lock_ldap(
+        &self,
+        timeouts: Timeouts,
+    ) -> Result<MutexGuard<'_, Connection>, APIErrors> {
+        let deadline = Instant::now() + timeouts.longest();
+        let mut ldap = self.checkout(timeouts.longest()).await?;
+        let reconnect = async {
+            while let Err(e) = self.check_connection(&mut ldap).await {
+                error!(directory = %self.name, error = %e, "reconnecting failed");
+                sleep(RECONNECT_DELAY).await;
+            }
+        };
+        if timeout_at(deadline.into(), reconnect).await.is_err() {
+            warn!(directory = %self.name, "timed out waiting for the directory to answer");
+            return Err(APIErrors::Timeout);
+        }
+        ldap.timeouts = timeouts;
+        Ok(ldap)
+    }
+
+    async fn check_connection(&self, connection: &mut Connection) -> Result<(), ConnectError> {
+        match bind(&mut connection.ldap, &connection.server, &self.config)
+            .instrument(info_span!("ldap.rebind", directory = %self.name))
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                warn!(directory = %self.name, server = %connection.server, error = %e, "LDAP connection lost, reconnecting");
+                metrics()
+                    .ldap_reconnects
+                    .with_label_values(&[self.name.as_str()])
+                    .inc();
+                self.failed.mark_failed(&connection.server);
+                *connection = self.connect_any().await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Why a connection to a directory could not be established.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The TLS settings could not be turned into a connector.
+    TlsConfig(String),
+    /// The TLS handshake failed, usually on certificate verification.
+    Handshake(String),
+    Connect(LdapError),
+    Bind(LdapError),
+    /// No server could be found through DNS.
+    Discovery(String),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::TlsConfig(e) => write!(f, "invalid TLS settings: {}", e),
+            ConnectError::Handshake(e) => write!(f, "TLS handshake failed: {}", e),
+            ConnectError::Connect(e) => write!(f, "cannot connect: {}", e),
+            ConnectError::Bind(e) => write!(f, "bind failed: {}", e),
+            ConnectError::Discovery(e) => write!(f, "server discovery failed: {}", e),
+        }
+    }
+}
+
+impl From<LdapError> for ConnectError {
+    fn from(e: LdapError) -> Self {
+        match e {
+            LdapError::NativeTLS { source } => ConnectError::Handshake(source.to_string()),
+            e => ConnectError::Connect(e),
+        }
+    }
+}
+
+fn read_file(name: &str, path: &Path) -> Result<Vec<u8>, ConnectError> {
+    std::fs::read(path)
+        .map_err(|e| ConnectError::TlsConfig(format!("{} {}: {}", name, path.display(), e)))
+}
+
+/// Builds the connection settings for a directory from its TLS options.
+pub fn connection_settings(config: &LdapConfig) -> Result<LdapConnSettings, ConnectError> {
+    let tls = &config.tls;
+    let mut builder = TlsConnector::builder();
+    builder.min_protocol_version(Some(match tls.min_version {
+        TlsVersion::Tls10 => Protocol::Tlsv10,
+        TlsVersion::Tls11 => Protocol::Tlsv11,
+        TlsVersion::Tls12 => Protocol::Tlsv12,
+    }));
+
+    if let Some(path) = &tls.ca_file {
+        let pem = read_file("CA bundle", path)?;
+        let certificate = Certificate::from_pem(&pem)
+            .map_err(|e| ConnectError::TlsConfig(format!("CA bundle {}: {}", path.display(), e)))?;
+        builder.add_root_certificate(certificate);
+    }
+    if let (Some(cert_path), Some(key_path)) = (&tls.client_cert_file, &tls.client_key_file) {
+        let cert = read_file("client certificate", cert_path)?;
+        let key = read_file("client key", key_path)?;
+        let identity = Identity::from_pkcs8(&cert, &key).map_err(|e| {
+            ConnectError::TlsConfig(format!(
+                "client certificate {} and key {}: {}",
+                cert_path.display(),
+                key_path.display(),
+                e
+            ))
+        })?;
+        builder.identity(identity);
+    }
+    if tls.insecure_skip_verify {
+        warn!(servers = %config.describe_servers(), "TLS certificate verification is disabled");
+        builder.danger_accept_invalid_certs(true);
+    }
+
+    let connector = builder
+        .build()
+        .map_err(|e| ConnectError::TlsConfig(e.to_string()))?;
+    Ok(LdapConnSettings::new()
+        .set_conn_timeout(Timeouts::from(&config.timeouts).get(Operation::Bind))
+        .set_connector(connector)
+        .set_starttls(tls.starttls))
+}
+
+pub async fn establish_ldap_connection(
+    server: &str,
+    config: &LdapConfig,
+    settings: &LdapConnSettings,
+) -> Result<Ldap, ConnectError> {
+    // Establish a connection with the LDAP server
+    let (conn, mut ldap) = LdapConnAsync::with_settings(settings.clone(), server).await?;
+
+    drive!(conn);
+    info!(server = %server, "connected to LDAP server");
+
+    bind(&mut ldap, server, config)
+        .await
+        .map_err(ConnectError::Bind)?;
+
+    Ok(ldap)
+}
+
+/// Binds with the configured mechanism. A failed SASL bind falls back to a
+/// simple bind when a password is configured.
+async fn bind(ldap: &mut Ldap, server: &str, config: &LdapConfig) -> Result<(), LdapError> {
+    let timeout = Timeouts::from(&config.timeouts).get(Operation::Bind);
+    let sasl = match config.bind.mechanism {
+        BindMechanism::Simple => None,
+        BindMechanism::External => Some(
+            ldap_timed("bind", ldap.with_timeout(timeout).sasl_external_bind())
+                .instrument(info_span!("ldap.bind", mechanism = "EXTERNAL"))
+                .await,
+        ),
+        BindMechanism::Gssapi => Some(gssapi_bind(ldap, server, config).await),
+    };
+
+    if let Some(res) = sasl {
+        match res.and_then(|res| res.success()) {
+            Ok(_) => return Ok(()),
+            Err(e) if config.password.is_some() => {
+                warn!(server = %server, error = %e, "SASL bind failed, falling back to simple bind")
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let password = config.password.as_ref().map(Secret::expose).unwrap_or("");
+    ldap_timed(
+        "bind",
+        ldap.with_timeout(timeout)
+            .simple_bind(&config.username, password),
+    )
+    .instrument(info_span!("ldap.bind", mechanism = "simple"))
+    .await?
+    .success()?;
+    Ok(())
+}
+
+#[cfg(feature = "gssapi")]
+async fn gssapi_bind(
+    ldap: &mut Ldap,
+    server: &str,
+    config: &LdapConfig,
+) -> Result<LdapResult, LdapError> {
+    let host = match &config.bind.spn_host {
+        Some(host) => host.clone(),
+        None => reqwest::Url::parse(server)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default(),
+    };
+    let timeout = Timeouts::from(&config.timeouts).get(Operation::Bind);
+    ldap_timed("bind", ldap.with_timeout(timeout).sasl_gssapi_bind(&host))
+        .instrument(info_span!("ldap.bind", mechanism = "GSSAPI"))
+        .await
+}
+
+#[cfg(not(feature = "gssapi"))]
+async fn gssapi_bind(
+    _ldap: &mut Ldap,
+    _server: &str,
+    _config: &LdapConfig,
+) -> Result<LdapResult, LdapError> {
+    Err(LdapError::AdapterInit(
+        "built without the gssapi feature".to_string(),
+    ))
+}
+
+/// Resolves the directory a request is for from the point its route was
+/// mounted at: `/d/<name>` for a named directory, `/` for the default.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r Directory {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let state = match request.rocket().state::<ServerState>() {
+            Some(state) => state,
+            None => return Outcome::Error((Status::InternalServerError, ())),
+        };
+        let base = request
+            .route()
+            .map(|route| route.uri.base().to_string())
+            .unwrap_or_default();
+
+        let name = base
+            .strip_prefix(MOUNT_PREFIX)
+            .unwrap_or(state.config.default_directory.as_str());
+        match state.directories.get(name) {
+            Some(directory) => Outcome::Success(directory.as_ref()),
+            None => Outcome::Forward(Status::NotFound),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Binds against the OpenLDAP and MIT KDC containers in `testing/sasl`,
+    //! which have to be started first; see its `docker-compose.yml`.
+
+    use figment::providers::{Format, Toml};
+    use figment::Figment;
+    use ldap3::exop::{WhoAmI, WhoAmIResp};
+
+    use super::*;
+    use crate::config::Config;
+
+    const OUT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testing/sasl/out");
+
+    /// The identity the server sees after binding with the `ldap` section.
+    async fn who_am_i(ldap: &str) -> String {
+        let config = Config::from_figment(&Figment::from(Toml::string(ldap)))
+            .unwrap_or_else(|errors| panic!("invalid configuration: {:?}", errors));
+        let config = &config.directories["default"];
+        let server = &config.servers[0].url;
+        let settings = connection_settings(config).unwrap();
+        let mut ldap = establish_ldap_connection(server, config, &settings)
+            .await
+            .unwrap();
+        let (exop, _res) = ldap.extended(WhoAmI).await.unwrap().success().unwrap();
+        exop.parse::<WhoAmIResp>().authzid
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs the containers in testing/sasl"]
+    async fn sasl_external_bind() {
+        let identity = who_am_i(&format!(
+            r#"
+            [ldap]
+            server = "ldaps://localhost:3636"
+            base_dn = "dc=example,dc=test"
+            bind.mechanism = "external"
+            tls.starttls = false
+            tls.ca_file = "{out}/ca.pem"
+            tls.client_cert_file = "{out}/ldap-api.pem"
+            tls.client_key_file = "{out}/ldap-api.key"
+            "#,
+            out = OUT
+        ))
+        .await;
+        assert_eq!(identity, "dn:cn=ldap-api,dc=example,dc=test");
+    }
+
+    /// Needs `KRB5_CONFIG` and `KRB5_CLIENT_KTNAME` set in the environment,
+    /// as `main` sets the latter before the runtime starts.
+    #[cfg(feature = "gssapi")]
+    #[rocket::async_test]
+    #[ignore = "needs the containers in testing/sasl"]
+    async fn sasl_gssapi_bind() {
+        let identity = who_am_i(&format!(
+            r#"
+            [ldap]
+            server = "ldap://localhost:3389"
+            base_dn = "dc=example,dc=test"
+            bind.mechanism = "gssapi"
+            bind.keytab = "{out}/ldap-api.keytab"
+            bind.spn_host = "ldap.example.test"
+            tls.ca_file = "{out}/ca.pem"
+            "#,
+            out = OUT
+        ))
+        .await;
+        assert_eq!(identity, "dn:cn=ldap-api,dc=example,dc=test");
+    }
+
+    /// Without TLS there is no certificate for EXTERNAL, so the refused
+    /// SASL bind falls back to the password.
+    #[rocket::async_test]
+    #[ignore = "needs the containers in testing/sasl"]
+    async fn sasl_falls_back_to_simple_bind() {
+        let identity = who_am_i(&format!(
+            r#"
+            [ldap]
+            server = "ldap://localhost:3389"
+            base_dn = "dc=example,dc=test"
+            username = "cn=ldap-api,dc=example,dc=test"
+            password = "ldap-api-password"
+            bind.mechanism = "external"
+            tls.starttls = false
+            tls.client_cert_file = "{out}/ldap-api.pem"
+            tls.client_key_file = "{out}/ldap-api.key"
+            "#,
+            out = OUT
+        ))
+        .await;
+        assert_eq!(identity, "dn:cn=ldap-api,dc=example,dc=test");
+    }
+}
@@ -1,9 +1,39 @@
+use ldap3::LdapError;
+
 pub enum APIErrors {
     EntryExists,
     EntryNotFound,
     ConnectionError,
     InternalError,
-    AddError,
+    /// The add, or setting up the new entry after it, failed; with the
+    /// result code when the directory answered.
+    AddError(Option<u32>),
     DeleteError,
     UpdateError,
+    Timeout,
 }
+
+impl From<LdapError> for APIErrors {
+    fn from(e: LdapError) -> Self {
+        match e {
+            LdapError::Timeout { .. } => APIErrors::Timeout,
+            LdapError::LdapResult { .. } => APIErrors::InternalError,
+            _ => APIErrors::ConnectionError,
+        }
+    }
+}
+
+impl APIErrors {
+    /// The LDAP result code behind the error, for the audit log.
+    pub fn result_code(&self) -> Option<u32> {
+        match self {
+            APIErrors::EntryExists => Some(68),
+            APIErrors::EntryNotFound => Some(32),
+            APIErrors::AddError(rc) => *rc,
+            _ => None,
+        }
+    }
+}
+
+/// Why a request guard turned a request away, for the error catchers.
+pub struct Rejection(pub Option<String>);
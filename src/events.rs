@@ -0,0 +1,94 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use rocket::tokio::sync::broadcast;
+use tracing::warn;
+
+use crate::directory::Connection;
+use crate::watcher::highest_usn;
+use crate::webhooks::{Event, Webhooks};
+
+/// Events held for `GET /events` subscribers which fall behind.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Hands every event to the webhooks and to the `GET /events` subscribers.
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+    webhooks: Arc<Webhooks>,
+    /// Directories with a change feed running. Writes to the others are not
+    /// noted, as nothing would ever look them up.
+    watched: Mutex<HashSet<String>>,
+    /// The server's highest committed USN just after the API changed an
+    /// entry, by directory and lowercased DN, with the server it was read
+    /// on. The change feed leaves out changes no newer than that.
+    writes: Mutex<HashMap<(String, String), (String, u64)>>,
+}
+
+impl EventBus {
+    pub fn new(webhooks: Arc<Webhooks>) -> Arc<EventBus> {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Arc::new(EventBus {
+            sender,
+            webhooks,
+            watched: Mutex::new(HashSet::new()),
+            writes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn publish(&self, event: Event) {
+        // Having no subscribers is not an error
+        let _ = self.sender.send(event.clone());
+        self.webhooks.emit(event);
+    }
+
+    /// Publishes a change the API just made on `ldap`, noting how far the
+    /// server had got so that the change feed does not report it again.
+    pub async fn publish_write(&self, ldap: &mut Connection, event: Event) {
+        if self.watched.lock().unwrap().contains(&event.directory) {
+            match highest_usn(ldap).await {
+                Ok(usn) => {
+                    let key = (event.directory.clone(), event.dn.to_lowercase());
+                    self.writes
+                        .lock()
+                        .unwrap()
+                        .insert(key, (ldap.server.clone(), usn));
+                }
+                Err(e) => {
+                    warn!(dn = %event.dn, error = %e, "cannot note the change for the change feed, which will report it again")
+                }
+            }
+        }
+        self.publish(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// Starts noting the API's writes to `directory`.
+    pub fn watch(&self, directory: &str) {
+        self.watched.lock().unwrap().insert(directory.to_string());
+    }
+
+    /// Whether a change to the entry at `usn` on `server` is one the API
+    /// made. USNs are local to each server, so one read elsewhere says
+    /// nothing and the change is reported.
+    pub fn written_by_api(&self, directory: &str, dn: &str, server: &str, usn: u64) -> bool {
+        self.writes
+            .lock()
+            .unwrap()
+            .get(&(directory.to_string(), dn.to_lowercase()))
+            .is_some_and(|(written_on, highest)| written_on == server && usn <= *highest)
+    }
+
+    /// Forgets the writes to `directory` which a poll of `server` up to
+    /// `highest` has gone past, or which were read on another server.
+    pub fn forget_writes(&self, directory: &str, server: &str, highest: u64) {
+        self.writes
+            .lock()
+            .unwrap()
+            .retain(|(written_to, _), (written_on, usn)| {
+                written_to != directory || (written_on == server && *usn > highest)
+            });
+    }
+}
@@ -0,0 +1,242 @@
+use std::path::Path;
+use std::pin::Pin;
+
+use ldap3::adapters::{Adapter, EntriesOnly, PagedResults};
+use ldap3::{LdapError, ResultEntry, Scope, SearchEntry, SearchStream};
+use rocket::futures::Stream;
+use rocket::http::ContentType;
+use rocket::response::stream::TextStream;
+use rocket::tokio::fs::{self, File};
+use rocket::tokio::io::{AsyncWriteExt, BufWriter};
+use rocket::FromForm;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::directory::{Connection, Directory};
+use crate::jobs::JobHandle;
+use crate::ldif;
+use crate::timeout::Operation;
+use crate::user::{UserAccount, USER_ATTRIBUTES};
+
+/// The body of an export, borrowing the directory it reads from.
+pub type ExportStream<'r> = TextStream<Pin<Box<dyn Stream<Item = String> + Send + 'r>>>;
+
+/// An export answered in the response, with its content type.
+pub type ExportBody<'r> = (ContentType, ExportStream<'r>);
+
+/// Entries fetched per page of the export search.
+const PAGE_SIZE: i32 = 500;
+
+/// Separates the values of multi-valued attributes in CSV cells.
+const CSV_VALUE_SEPARATOR: &str = ";";
+
+#[derive(FromForm, Debug, Default)]
+pub struct ExportOptions {
+    /// Runs the export as a job, which keeps the result.
+    #[field(name = "async")]
+    pub background: Option<bool>,
+}
+
+/// What an export job reports: how far it got and where to download the
+/// export, which is written to `jobs.artifact_dir` as it goes.
+#[derive(Serialize, Debug)]
+pub struct ExportResult {
+    pub content_type: String,
+    pub count: usize,
+    pub artifact: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+    Ldif,
+}
+
+impl ExportFormat {
+    pub fn parse(format: &str) -> Option<ExportFormat> {
+        match format.to_ascii_lowercase().as_str() {
+            "csv" => Some(ExportFormat::Csv),
+            "ndjson" | "jsonl" => Some(ExportFormat::Ndjson),
+            "ldif" => Some(ExportFormat::Ldif),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        match self {
+            ExportFormat::Csv => ContentType::CSV,
+            ExportFormat::Ndjson => ContentType::new("application", "x-ndjson"),
+            ExportFormat::Ldif => ContentType::new("text", "x-ldif"),
+        }
+    }
+}
+
+/// Turns the entries of a user search into one of the export formats, one
+/// entry at a time.
+pub struct Exporter {
+    format: ExportFormat,
+    columns: Vec<String>,
+}
+
+impl Exporter {
+    pub fn new(format: ExportFormat, directory: &Directory) -> Exporter {
+        let columns = match format {
+            ExportFormat::Csv => UserAccount::field_names()
+                .into_iter()
+                .filter(|f| f != "mailbox")
+                .chain(directory.config.attributes.fields().map(str::to_string))
+                .collect(),
+            _ => vec![],
+        };
+        Exporter { format, columns }
+    }
+
+    /// What comes before the first entry.
+    pub fn header(&self) -> Option<String> {
+        match self.format {
+            ExportFormat::Csv => Some(csv_line(&self.columns)),
+            ExportFormat::Ndjson => None,
+            ExportFormat::Ldif => Some(ldif::VERSION_LINE.to_string()),
+        }
+    }
+
+    pub fn entry(&self, entry: ResultEntry, directory: &Directory) -> String {
+        let entry = SearchEntry::construct(entry);
+        if self.format == ExportFormat::Ldif {
+            return ldif::entry_record(&entry);
+        }
+
+        let account = UserAccount::from_entry(entry, directory);
+        let fields = match serde_json::to_value(&account) {
+            Ok(Value::Object(fields)) => fields,
+            _ => Default::default(),
+        };
+        match self.format {
+            ExportFormat::Csv => {
+                let cells: Vec<String> = self
+                    .columns
+                    .iter()
+                    .map(|column| cell(fields.get(column).unwrap_or(&Value::Null)))
+                    .collect();
+                csv_line(&cells)
+            }
+            _ => format!("{}\n", Value::Object(fields)),
+        }
+    }
+
+    /// Marks an export cut short. The status line has long gone out, so
+    /// this is the only sign of failure a client gets; CSV has no room for
+    /// one.
+    pub fn failure(&self, reason: &str) -> Option<String> {
+        match self.format {
+            ExportFormat::Csv => None,
+            ExportFormat::Ndjson => Some(format!("{}\n", serde_json::json!({ "error": reason }))),
+            ExportFormat::Ldif => Some(format!("# export failed: {}\n", reason)),
+        }
+    }
+}
+
+/// Starts a paged search over the same users and attributes as
+/// `GET /users`.
+pub async fn search(
+    ldap: &mut Connection,
+    directory: &Directory,
+) -> Result<SearchStream<'static, &'static str, Vec<&'static str>>, LdapError> {
+    let adapters: Vec<Box<dyn Adapter<&'static str, Vec<&'static str>>>> = vec![
+        Box::new(EntriesOnly::new()),
+        Box::new(PagedResults::new(PAGE_SIZE)),
+    ];
+    ldap.op(Operation::Search)
+        .streaming_search_with(
+            adapters,
+            &directory.config.base_dn,
+            Scope::Subtree,
+            directory.flavor.user_filter(),
+            USER_ATTRIBUTES.to_vec(),
+        )
+        .await
+}
+
+/// Runs an export for a job, writing it to `path` one entry at a time. The
+/// number of users is only known at the end, so progress counts those
+/// exported so far. A cancelled export keeps what it had; a failed one is
+/// removed.
+pub async fn run(
+    ldap: &mut Connection,
+    directory: &Directory,
+    format: ExportFormat,
+    job: &JobHandle,
+    path: &Path,
+) -> Result<ExportResult, String> {
+    let written = write(ldap, directory, format, job, path).await;
+    if written.is_err() {
+        let _ = fs::remove_file(path).await;
+    }
+    written
+}
+
+async fn write(
+    ldap: &mut Connection,
+    directory: &Directory,
+    format: ExportFormat,
+    job: &JobHandle,
+    path: &Path,
+) -> Result<ExportResult, String> {
+    let cannot_write = |e: std::io::Error| format!("cannot write {}: {}", path.display(), e);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await.map_err(cannot_write)?;
+    }
+    let mut file = BufWriter::new(File::create(path).await.map_err(cannot_write)?);
+
+    let exporter = Exporter::new(format, directory);
+    let mut result = ExportResult {
+        content_type: format.content_type().to_string(),
+        count: 0,
+        artifact: format!("/jobs/{}/artifact", job.id),
+    };
+    if let Some(header) = exporter.header() {
+        file.write_all(header.as_bytes())
+            .await
+            .map_err(cannot_write)?;
+    }
+    let mut entries = search(ldap, directory).await.map_err(|e| e.to_string())?;
+    while let Some(entry) = entries.next().await.map_err(|e| e.to_string())? {
+        file.write_all(exporter.entry(entry, directory).as_bytes())
+            .await
+            .map_err(cannot_write)?;
+        result.count += 1;
+        if !job.progress(result.count, result.count, &result).await {
+            break;
+        }
+    }
+    let res = entries.finish().await;
+    if res.rc != 0 && !job.is_cancelled() {
+        return Err(res.to_string());
+    }
+    file.flush().await.map_err(cannot_write)?;
+    Ok(result)
+}
+
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Array(values) => values
+            .iter()
+            .map(cell)
+            .collect::<Vec<_>>()
+            .join(CSV_VALUE_SEPARATOR),
+        other => other.to_string(),
+    }
+}
+
+fn csv_line(cells: &[String]) -> String {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    // Writing to memory cannot fail
+    let _ = writer.write_record(cells);
+    writer
+        .into_inner()
+        .map(|line| String::from_utf8_lossy(&line).into_owned())
+        .unwrap_or_default()
+}
@@ -0,0 +1,478 @@
+use std::collections::HashSet;
+
+use ldap3::exop::PasswordModify;
+use ldap3::{LdapError, Mod, Scope, SearchEntry};
+use tracing::{info_span, Instrument};
+
+use crate::config::SchemaFlavor;
+use crate::directory::Connection;
+use crate::metrics::ldap_timed;
+use crate::timeout::Operation;
+use crate::user::UserParams;
+use crate::util::{escape_bytes, format_guid, parse_guid};
+
+/// `userAccountControl` flags (MS-ADTS 2.2.16).
+const UAC_ACCOUNTDISABLE: u32 = 0x0002;
+const UAC_NORMAL_ACCOUNT: u32 = 0x0200;
+const UAC_DONT_EXPIRE_PASSWORD: u32 = 0x10000;
+
+/// The ppolicy overlay treats this lock time as "locked until unlocked by an
+/// administrator".
+const PPOLICY_PERMANENT_LOCK: &str = "000001010000Z";
+
+/// Everything that differs between the directory servers we talk to. The
+/// REST API is written against this trait so the same routes work whichever
+/// schema a directory uses.
+#[rocket::async_trait]
+pub trait DirectoryFlavor: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Filter matching every user entry.
+    fn user_filter(&self) -> &'static str;
+
+    /// Attribute callers identify users by in `/users/<uname>`.
+    fn login_attribute(&self) -> &'static str;
+
+    /// The value of `login_attribute` for a user about to be created.
+    fn login_name<'a>(&self, user: &'a UserParams) -> &'a str;
+
+    fn new_user_dn(&self, user: &UserParams, container: &str) -> String;
+
+    /// Attributes of a new user entry, without its password.
+    fn new_user_attributes(&self, user: &UserParams) -> Vec<(String, Vec<String>)>;
+
+    /// Attributes replaced once a new user has its password, to make the
+    /// account usable.
+    fn activation_attributes(&self) -> Vec<(String, Vec<String>)> {
+        vec![]
+    }
+
+    /// Attribute the password ends up in, as shown in the audit log.
+    fn password_attribute(&self) -> &'static str;
+
+    /// The entry's immutable identifier, formatted as a GUID string.
+    fn guid(&self, entry: &SearchEntry) -> Option<String>;
+
+    /// Attribute to request so that `guid` can be read.
+    fn guid_attribute(&self) -> &'static str;
+
+    fn supports_recycle_bin(&self) -> bool {
+        false
+    }
+
+    /// Whether entries carry `uSNChanged`, which the change feed polls on.
+    fn supports_change_feed(&self) -> bool {
+        false
+    }
+
+    /// Filter matching the entry with the given `guid`, if it is well formed.
+    fn guid_filter(&self, guid: &str) -> Option<String>;
+
+    /// Whether a user entry, read with its operational attributes, can log
+    /// in.
+    fn is_enabled(&self, entry: &SearchEntry) -> bool;
+
+    /// The `userPrincipalName` and `sAMAccountName` of a new user logging in
+    /// as `login`.
+    fn account_names(&self, login: &str) -> (String, String);
+
+    /// Attributes of the SCIM enterprise user extension, with the LDAP
+    /// attributes they are kept in.
+    fn enterprise_attributes(&self) -> &'static [(&'static str, &'static str)];
+
+    /// Filter matching every group entry. Members are DNs in `member`.
+    fn group_filter(&self) -> &'static str;
+
+    fn new_group_dn(&self, name: &str, container: &str) -> String;
+
+    /// Attributes of a new group with the given members. Fails when the
+    /// schema cannot hold such a group.
+    fn new_group_attributes(
+        &self,
+        name: &str,
+        members: &[String],
+    ) -> Result<Vec<(String, Vec<String>)>, String>;
+
+    async fn set_password(
+        &self,
+        ldap: &mut Connection,
+        dn: &str,
+        password: &str,
+    ) -> Result<(), LdapError>;
+
+    async fn set_enabled(
+        &self,
+        ldap: &mut Connection,
+        dn: &str,
+        enabled: bool,
+    ) -> Result<(), LdapError>;
+
+    /// Filter finding a user by login name, with the name escaped.
+    fn login_filter(&self, uname: &str) -> String {
+        format!(
+            "(&{}({}={}))",
+            self.user_filter(),
+            self.login_attribute(),
+            ldap3::ldap_escape(uname)
+        )
+    }
+}
+
+pub fn for_schema(schema: SchemaFlavor) -> Box<dyn DirectoryFlavor> {
+    match schema {
+        SchemaFlavor::ActiveDirectory => Box::new(ActiveDirectory),
+        SchemaFlavor::Openldap => Box::new(InetOrgPerson),
+    }
+}
+
+fn single(name: &str, value: &str) -> (String, Vec<String>) {
+    (name.to_string(), vec![value.to_string()])
+}
+
+async fn modify(ldap: &mut Connection, dn: &str, mods: Vec<Mod<String>>) -> Result<(), LdapError> {
+    ldap_timed("modify", ldap.op(Operation::Modify).modify(dn, mods))
+        .instrument(info_span!("ldap.modify", dn = dn))
+        .await?
+        .success()?;
+    Ok(())
+}
+
+/// Active Directory: `user` objects, logins by `userPrincipalName`, passwords
+/// written to `unicodePwd` and accounts switched through
+/// `userAccountControl`.
+pub struct ActiveDirectory;
+
+#[rocket::async_trait]
+impl DirectoryFlavor for ActiveDirectory {
+    fn name(&self) -> &'static str {
+        "active_directory"
+    }
+
+    fn user_filter(&self) -> &'static str {
+        "(&(objectCategory=person)(objectClass=user))"
+    }
+
+    fn login_attribute(&self) -> &'static str {
+        "userPrincipalName"
+    }
+
+    fn login_name<'a>(&self, user: &'a UserParams) -> &'a str {
+        &user.userPrincipalName
+    }
+
+    fn new_user_dn(&self, user: &UserParams, container: &str) -> String {
+        format!("CN={},{}", ldap3::dn_escape(&user.cn), container)
+    }
+
+    fn new_user_attributes(&self, user: &UserParams) -> Vec<(String, Vec<String>)> {
+        vec![
+            (
+                "objectClass".to_string(),
+                ["top", "person", "organizationalPerson", "user"]
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect(),
+            ),
+            single("cn", &user.cn),
+            single("givenName", &user.givenName),
+            single("sn", &user.sn),
+            single("displayName", &user.givenName),
+            single("userPrincipalName", &user.userPrincipalName),
+            single("sAMAccountName", &user.sAMAccountName),
+            single("mail", &user.mail),
+        ]
+    }
+
+    fn activation_attributes(&self) -> Vec<(String, Vec<String>)> {
+        let flags = UAC_NORMAL_ACCOUNT | UAC_DONT_EXPIRE_PASSWORD;
+        vec![single("userAccountControl", &flags.to_string())]
+    }
+
+    fn password_attribute(&self) -> &'static str {
+        "unicodePwd"
+    }
+
+    fn guid(&self, entry: &SearchEntry) -> Option<String> {
+        format_guid(entry.bin_attrs.get("objectGUID")?.first()?)
+    }
+
+    fn guid_attribute(&self) -> &'static str {
+        "objectGUID"
+    }
+
+    fn supports_recycle_bin(&self) -> bool {
+        true
+    }
+
+    fn supports_change_feed(&self) -> bool {
+        true
+    }
+
+    fn guid_filter(&self, guid: &str) -> Option<String> {
+        Some(format!("(objectGUID={})", escape_bytes(&parse_guid(guid)?)))
+    }
+
+    fn is_enabled(&self, entry: &SearchEntry) -> bool {
+        let flags: u32 = entry
+            .attrs
+            .get("userAccountControl")
+            .and_then(|v| v.first()?.parse().ok())
+            .unwrap_or(0);
+        flags & UAC_ACCOUNTDISABLE == 0
+    }
+
+    fn account_names(&self, login: &str) -> (String, String) {
+        // sAMAccountName holds at most 20 characters
+        let local = login.split('@').next().unwrap_or(login);
+        (login.to_string(), local.chars().take(20).collect())
+    }
+
+    fn enterprise_attributes(&self) -> &'static [(&'static str, &'static str)] {
+        &[
+            ("employeeNumber", "employeeNumber"),
+            ("department", "department"),
+            ("organization", "company"),
+            ("division", "division"),
+        ]
+    }
+
+    fn group_filter(&self) -> &'static str {
+        "(objectClass=group)"
+    }
+
+    fn new_group_dn(&self, name: &str, container: &str) -> String {
+        format!("CN={},{}", ldap3::dn_escape(name), container)
+    }
+
+    fn new_group_attributes(
+        &self,
+        name: &str,
+        members: &[String],
+    ) -> Result<Vec<(String, Vec<String>)>, String> {
+        let mut attrs = vec![
+            (
+                "objectClass".to_string(),
+                vec!["top".to_string(), "group".to_string()],
+            ),
+            single("cn", name),
+            single("sAMAccountName", name),
+        ];
+        if !members.is_empty() {
+            attrs.push(("member".to_string(), members.to_vec()));
+        }
+        Ok(attrs)
+    }
+
+    async fn set_password(
+        &self,
+        ldap: &mut Connection,
+        dn: &str,
+        password: &str,
+    ) -> Result<(), LdapError> {
+        // unicodePwd takes the quoted password as UTF-16LE
+        let values: Vec<u8> = format!("\"{}\"", password)
+            .encode_utf16()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let mods = vec![Mod::Replace(
+            "unicodePwd".as_bytes().to_vec(),
+            HashSet::from([values]),
+        )];
+        ldap_timed("modify", ldap.op(Operation::Modify).modify(dn, mods))
+            .instrument(info_span!("ldap.modify", dn = dn))
+            .await?
+            .success()?;
+        Ok(())
+    }
+
+    async fn set_enabled(
+        &self,
+        ldap: &mut Connection,
+        dn: &str,
+        enabled: bool,
+    ) -> Result<(), LdapError> {
+        let (rs, _res) = ldap_timed(
+            "search",
+            ldap.op(Operation::Search).search(
+                dn,
+                Scope::Base,
+                "(objectClass=*)",
+                vec!["userAccountControl"],
+            ),
+        )
+        .instrument(info_span!("ldap.search", base = dn))
+        .await?
+        .success()?;
+
+        let current = rs
+            .into_iter()
+            .next()
+            .map(SearchEntry::construct)
+            .and_then(|entry| entry.attrs.get("userAccountControl")?.first()?.parse().ok())
+            .unwrap_or(UAC_NORMAL_ACCOUNT | UAC_DONT_EXPIRE_PASSWORD);
+        let flags: u32 = if enabled {
+            current & !UAC_ACCOUNTDISABLE
+        } else {
+            current | UAC_ACCOUNTDISABLE
+        };
+
+        modify(
+            ldap,
+            dn,
+            vec![Mod::Replace(
+                "userAccountControl".to_string(),
+                HashSet::from([flags.to_string()]),
+            )],
+        )
+        .await
+    }
+}
+
+/// OpenLDAP and 389-DS with the RFC 2307 / inetOrgPerson schema: logins by
+/// `uid`, passwords set with the Password Modify extended operation
+/// (RFC 3062) and accounts locked through the ppolicy overlay.
+pub struct InetOrgPerson;
+
+#[rocket::async_trait]
+impl DirectoryFlavor for InetOrgPerson {
+    fn name(&self) -> &'static str {
+        "openldap"
+    }
+
+    fn user_filter(&self) -> &'static str {
+        "(objectClass=inetOrgPerson)"
+    }
+
+    fn login_attribute(&self) -> &'static str {
+        "uid"
+    }
+
+    fn login_name<'a>(&self, user: &'a UserParams) -> &'a str {
+        &user.sAMAccountName
+    }
+
+    fn new_user_dn(&self, user: &UserParams, container: &str) -> String {
+        format!(
+            "uid={},{}",
+            ldap3::dn_escape(&user.sAMAccountName),
+            container
+        )
+    }
+
+    fn new_user_attributes(&self, user: &UserParams) -> Vec<(String, Vec<String>)> {
+        vec![
+            (
+                "objectClass".to_string(),
+                ["top", "person", "organizationalPerson", "inetOrgPerson"]
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect(),
+            ),
+            single("uid", &user.sAMAccountName),
+            single("cn", &user.cn),
+            single("givenName", &user.givenName),
+            single("sn", &user.sn),
+            single("displayName", &user.displayName),
+            single("mail", &user.mail),
+        ]
+    }
+
+    fn password_attribute(&self) -> &'static str {
+        "userPassword"
+    }
+
+    fn guid(&self, entry: &SearchEntry) -> Option<String> {
+        entry.attrs.get("entryUUID")?.first().cloned()
+    }
+
+    fn guid_attribute(&self) -> &'static str {
+        "entryUUID"
+    }
+
+    fn guid_filter(&self, guid: &str) -> Option<String> {
+        uuid::Uuid::parse_str(guid).ok()?;
+        Some(format!("(entryUUID={})", guid))
+    }
+
+    fn is_enabled(&self, entry: &SearchEntry) -> bool {
+        !entry.attrs.contains_key("pwdAccountLockedTime")
+    }
+
+    fn account_names(&self, login: &str) -> (String, String) {
+        (login.to_string(), login.to_string())
+    }
+
+    fn enterprise_attributes(&self) -> &'static [(&'static str, &'static str)] {
+        &[
+            ("employeeNumber", "employeeNumber"),
+            ("department", "departmentNumber"),
+            ("organization", "o"),
+        ]
+    }
+
+    fn group_filter(&self) -> &'static str {
+        "(objectClass=groupOfNames)"
+    }
+
+    fn new_group_dn(&self, name: &str, container: &str) -> String {
+        format!("cn={},{}", ldap3::dn_escape(name), container)
+    }
+
+    fn new_group_attributes(
+        &self,
+        name: &str,
+        members: &[String],
+    ) -> Result<Vec<(String, Vec<String>)>, String> {
+        if members.is_empty() {
+            return Err("a groupOfNames needs at least one member".to_string());
+        }
+        Ok(vec![
+            (
+                "objectClass".to_string(),
+                vec!["top".to_string(), "groupOfNames".to_string()],
+            ),
+            single("cn", name),
+            ("member".to_string(), members.to_vec()),
+        ])
+    }
+
+    async fn set_password(
+        &self,
+        ldap: &mut Connection,
+        dn: &str,
+        password: &str,
+    ) -> Result<(), LdapError> {
+        let exop = PasswordModify {
+            user_id: Some(dn),
+            old_pass: None,
+            new_pass: Some(password),
+        };
+        ldap_timed("passmod", ldap.op(Operation::Modify).extended(exop))
+            .instrument(info_span!("ldap.passmod", dn = dn))
+            .await?
+            .success()?;
+        Ok(())
+    }
+
+    async fn set_enabled(
+        &self,
+        ldap: &mut Connection,
+        dn: &str,
+        enabled: bool,
+    ) -> Result<(), LdapError> {
+        let lock_time = if enabled {
+            Mod::Delete("pwdAccountLockedTime".to_string(), HashSet::new())
+        } else {
+            Mod::Replace(
+                "pwdAccountLockedTime".to_string(),
+                HashSet::from([PPOLICY_PERMANENT_LOCK.to_string()]),
+            )
+        };
+
+        match modify(ldap, dn, vec![lock_time]).await {
+            // Enabling an account that was never locked
+            Err(LdapError::LdapResult { result }) if enabled && result.rc == 16 => Ok(()),
+            res => res,
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use std::time::{Duration, Instant};
+
+use ldap3::Scope;
+use rocket::tokio::time::timeout;
+use serde::Serialize;
+use tracing::{info_span, Instrument};
+
+use crate::config::Config;
+use crate::directory::Directory;
+use crate::metrics::ldap_timed;
+use crate::ServerState;
+
+#[derive(Serialize, Debug)]
+pub struct DependencyStatus {
+    pub name: String,
+    pub healthy: bool,
+    pub detail: String,
+    pub latency_ms: u128,
+    /// For directories, the server currently in use.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub ready: bool,
+    pub dependencies: Vec<DependencyStatus>,
+}
+
+/// What the readiness checks need, set up once at startup.
+#[derive(Clone)]
+pub struct HealthChecks {
+    limit: Duration,
+    /// The cPanel URL and a client bounded by `limit`, when cPanel is
+    /// configured.
+    mailbox: Option<(String, reqwest::Client)>,
+}
+
+impl HealthChecks {
+    pub fn new(config: &Config) -> Result<HealthChecks, String> {
+        let limit = Duration::from_millis(config.health.readiness_timeout_ms);
+        let mailbox = match &config.cpanel {
+            Some(cpanel) => {
+                let client = reqwest::Client::builder()
+                    .timeout(limit)
+                    .build()
+                    .map_err(|e| format!("cannot build the mailbox health check client: {}", e))?;
+                Some((cpanel.url.clone(), client))
+            }
+            None => None,
+        };
+        Ok(HealthChecks { limit, mailbox })
+    }
+}
+
+impl Readiness {
+    pub async fn check(state: &ServerState) -> Readiness {
+        let checks = &state.health;
+        let mut dependencies = Vec::new();
+        for directory in state.directories.values() {
+            dependencies.push(check_ldap(directory, checks.limit).await);
+        }
+        dependencies.push(check_mailbox(checks.mailbox.as_ref(), checks.limit).await);
+
+        Readiness {
+            ready: dependencies.iter().all(|d| d.healthy),
+            dependencies,
+        }
+    }
+}
+
+/// Reads the rootDSE on a pooled connection, which every server answers
+/// once the connection is up. Both waiting for the connection and the read
+/// itself are bounded by `limit`.
+async fn check_ldap(directory: &Directory, limit: Duration) -> DependencyStatus {
+    let start = Instant::now();
+    let result = timeout(limit, async {
+        let mut ldap = directory.checkout(limit).await.ok()?;
+        Some(
+            ldap_timed(
+                "search",
+                ldap.with_timeout(limit).search(
+                    "",
+                    Scope::Base,
+                    "(objectClass=*)",
+                    vec!["supportedLDAPVersion"],
+                ),
+            )
+            .instrument(info_span!("ldap.search", base = "rootDSE"))
+            .await,
+        )
+    })
+    .await;
+
+    let (healthy, detail) = match result {
+        Ok(Some(Ok(res))) => match res.success() {
+            Ok((entries, _)) if entries.is_empty() => (false, "no rootDSE returned".to_string()),
+            Ok(_) => (true, "rootDSE read".to_string()),
+            Err(e) => (false, e.to_string()),
+        },
+        Ok(Some(Err(e))) => (false, e.to_string()),
+        Ok(None) | Err(_) => (false, format!("timed out after {}ms", limit.as_millis())),
+    };
+
+    DependencyStatus {
+        name: format!("ldap:{}", directory.name),
+        healthy,
+        detail,
+        latency_ms: start.elapsed().as_millis(),
+        server: directory.active_server(),
+    }
+}
+
+/// Checks the mailbox provider answers HTTP at all; any response counts.
+async fn check_mailbox(
+    mailbox: Option<&(String, reqwest::Client)>,
+    limit: Duration,
+) -> DependencyStatus {
+    let start = Instant::now();
+    let (cpanel_url, client) = match mailbox {
+        Some((url, client)) => (url, client),
+        None => {
+            return DependencyStatus {
+                name: "mailbox".to_string(),
+                healthy: true,
+                detail: "not configured".to_string(),
+                latency_ms: 0,
+                server: None,
+            }
+        }
+    };
+
+    let (healthy, detail) = match client.head(cpanel_url).send().await {
+        Ok(res) => (true, format!("HTTP {}", res.status().as_u16())),
+        Err(e) if e.is_timeout() => (false, format!("timed out after {}ms", limit.as_millis())),
+        Err(e) => (false, e.to_string()),
+    };
+
+    DependencyStatus {
+        name: "mailbox".to_string(),
+        healthy,
+        detail,
+        latency_ms: start.elapsed().as_millis(),
+        server: None,
+    }
+}
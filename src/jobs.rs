@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use redb::{Database, ReadableTable, TableDefinition};
+use rocket::futures::FutureExt;
+use rocket::tokio::sync::{Mutex, Semaphore};
+use rocket::tokio::task::spawn_blocking;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{error, info, info_span, Instrument};
+
+use crate::config::JobsConfig;
+
+/// Jobs by ID, as JSON.
+const JOBS: TableDefinition<&str, &[u8]> = TableDefinition::new("jobs");
+
+/// How often a running job's progress is written to the store.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, JobStatus::Queued | JobStatus::Running)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct JobProgress {
+    pub done: usize,
+    pub total: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    pub id: String,
+    pub kind: String,
+    pub directory: String,
+    pub actor: String,
+    pub request_id: String,
+    pub status: JobStatus,
+    pub created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub progress: JobProgress,
+    /// The results so far while running, the final ones once finished.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Job {
+    fn finish(&mut self, status: JobStatus) {
+        self.status = status;
+        self.finished_at = Some(Utc::now());
+    }
+}
+
+/// Who asked for a job, for the record.
+pub struct JobOwner {
+    pub actor: String,
+    pub request_id: String,
+}
+
+/// A failed operation on an embedded store, described.
+pub struct StoreError(pub String);
+
+impl<E: Into<redb::Error>> From<E> for StoreError {
+    fn from(e: E) -> Self {
+        StoreError(e.into().to_string())
+    }
+}
+
+/// The jobs, kept in an embedded database so they outlive restarts.
+#[derive(Clone)]
+struct JobStore {
+    db: Arc<Database>,
+}
+
+impl JobStore {
+    fn open(path: &Path) -> Result<JobStore, StoreError> {
+        let db = Database::create(path)?;
+        let txn = db.begin_write()?;
+        txn.open_table(JOBS)?;
+        txn.commit()?;
+        Ok(JobStore { db: Arc::new(db) })
+    }
+
+    fn save_blocking(&self, job: &Job) -> Result<(), StoreError> {
+        let value = serde_json::to_vec(job).unwrap_or_default();
+        let txn = self.db.begin_write()?;
+        txn.open_table(JOBS)?
+            .insert(job.id.as_str(), value.as_slice())?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    fn load_blocking(&self, id: &str) -> Result<Option<Job>, StoreError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(JOBS)?;
+        let job = table
+            .get(id)?
+            .and_then(|value| serde_json::from_slice(value.value()).ok());
+        Ok(job)
+    }
+
+    fn all_blocking(&self) -> Result<Vec<Job>, StoreError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(JOBS)?;
+        let mut jobs = Vec::new();
+        for entry in table.iter()? {
+            if let Ok(job) = serde_json::from_slice(entry?.1.value()) {
+                jobs.push(job);
+            }
+        }
+        Ok(jobs)
+    }
+
+    async fn save(&self, job: &Job) -> Result<(), String> {
+        let (store, job) = (self.clone(), job.clone());
+        spawn_blocking(move || store.save_blocking(&job))
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.0)
+    }
+
+    async fn load(&self, id: &str) -> Result<Option<Job>, String> {
+        let (store, id) = (self.clone(), id.to_string());
+        spawn_blocking(move || store.load_blocking(&id))
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.0)
+    }
+}
+
+struct Tracked {
+    job: Job,
+    saved: Instant,
+}
+
+/// A job's view of itself while it runs: where it reports progress and
+/// learns of cancellation.
+#[derive(Clone)]
+pub struct JobHandle {
+    pub id: String,
+    store: JobStore,
+    tracked: Arc<Mutex<Tracked>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl JobHandle {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Records that `done` of `total` items are through, with the results so
+    /// far. Returns false once the job has been cancelled and should stop.
+    pub async fn progress<T: Serialize + Sync>(
+        &self,
+        done: usize,
+        total: usize,
+        partial: &T,
+    ) -> bool {
+        let mut tracked = self.tracked.lock().await;
+        tracked.job.progress = JobProgress { done, total };
+        if tracked.saved.elapsed() >= SAVE_INTERVAL {
+            tracked.job.result = serde_json::to_value(partial).ok();
+            save(&self.store, &tracked.job).await;
+            tracked.saved = Instant::now();
+        }
+        !self.is_cancelled()
+    }
+}
+
+async fn save(store: &JobStore, job: &Job) {
+    if let Err(e) = store.save(job).await {
+        error!(job = %job.id, error = %e, "failed to save job");
+    }
+}
+
+/// Runs long operations in the background, a few at a time, and keeps track
+/// of them.
+pub struct Jobs {
+    store: JobStore,
+    slots: Semaphore,
+    active: std::sync::Mutex<HashMap<String, JobHandle>>,
+}
+
+impl Jobs {
+    /// Opens the store. Jobs which were queued or running when the service
+    /// last stopped are marked failed, as their input is gone.
+    pub fn open(config: &JobsConfig) -> Result<Arc<Jobs>, String> {
+        let store = JobStore::open(&config.store_path).map_err(|e| {
+            format!(
+                "cannot open job store {}: {}",
+                config.store_path.display(),
+                e.0
+            )
+        })?;
+        let jobs = store.all_blocking().map_err(|e| e.0)?;
+        for mut job in jobs.into_iter().filter(|j| !j.status.is_finished()) {
+            job.finish(JobStatus::Failed);
+            job.error = Some("interrupted by a restart".to_string());
+            store.save_blocking(&job).map_err(|e| e.0)?;
+            info!(job = %job.id, "marked interrupted job failed");
+        }
+        Ok(Arc::new(Jobs {
+            store,
+            slots: Semaphore::new(config.max_running),
+            active: std::sync::Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Queues `work` and returns the job as first stored. The work gets a
+    /// handle to report progress on and returns the job's result.
+    pub async fn submit<F, Fut>(
+        self: &Arc<Self>,
+        kind: &str,
+        directory: &str,
+        owner: JobOwner,
+        work: F,
+    ) -> Result<Job, String>
+    where
+        F: FnOnce(JobHandle) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Value, String>> + Send + 'static,
+    {
+        let job = Job {
+            id: uuid::Uuid::new_v4().to_string(),
+            kind: kind.to_string(),
+            directory: directory.to_string(),
+            actor: owner.actor,
+            request_id: owner.request_id,
+            status: JobStatus::Queued,
+            created_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+            progress: JobProgress::default(),
+            result: None,
+            error: None,
+        };
+        self.store.save(&job).await?;
+
+        let handle = JobHandle {
+            id: job.id.clone(),
+            store: self.store.clone(),
+            tracked: Arc::new(Mutex::new(Tracked {
+                job: job.clone(),
+                saved: Instant::now(),
+            })),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        };
+        self.active
+            .lock()
+            .unwrap()
+            .insert(job.id.clone(), handle.clone());
+
+        let span = info_span!("job", id = %job.id, kind = %job.kind);
+        let jobs = self.clone();
+        rocket::tokio::spawn(async move { jobs.run(handle, work).await }.instrument(span));
+        info!(job = %job.id, kind, "job queued");
+        Ok(job)
+    }
+
+    async fn run<F, Fut>(&self, handle: JobHandle, work: F)
+    where
+        F: FnOnce(JobHandle) -> Fut,
+        Fut: Future<Output = Result<Value, String>>,
+    {
+        // The semaphore is never closed
+        let _slot = self.slots.acquire().await.unwrap();
+        let id = {
+            let mut tracked = handle.tracked.lock().await;
+            if tracked.job.status != JobStatus::Queued {
+                // Cancelled while queued
+                self.active.lock().unwrap().remove(&tracked.job.id);
+                return;
+            }
+            tracked.job.status = JobStatus::Running;
+            tracked.job.started_at = Some(Utc::now());
+            save(&self.store, &tracked.job).await;
+            tracked.job.id.clone()
+        };
+        info!("job started");
+
+        let outcome = AssertUnwindSafe(work(handle.clone()))
+            .catch_unwind()
+            .await
+            .unwrap_or_else(|_| Err("job panicked".to_string()));
+
+        let mut tracked = handle.tracked.lock().await;
+        let job = &mut tracked.job;
+        match outcome {
+            Ok(result) => {
+                job.result = Some(result);
+                job.finish(match handle.is_cancelled() {
+                    true => JobStatus::Cancelled,
+                    false => JobStatus::Succeeded,
+                });
+            }
+            Err(e) => {
+                job.error = Some(e);
+                job.finish(JobStatus::Failed);
+            }
+        }
+        save(&self.store, job).await;
+        info!(status = ?job.status, "job finished");
+        self.active.lock().unwrap().remove(&id);
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<Job>, String> {
+        let handle = self.active.lock().unwrap().get(id).cloned();
+        match handle {
+            Some(handle) => Ok(Some(handle.tracked.lock().await.job.clone())),
+            None => self.store.load(id).await,
+        }
+    }
+
+    /// Asks a job to stop. A queued job is cancelled at once; a running one
+    /// stops after the item it is on. Finished jobs are returned unchanged.
+    pub async fn cancel(&self, id: &str) -> Result<Option<Job>, String> {
+        let handle = self.active.lock().unwrap().get(id).cloned();
+        let handle = match handle {
+            Some(handle) => handle,
+            None => return self.store.load(id).await,
+        };
+        handle.cancelled.store(true, Ordering::Relaxed);
+        let mut tracked = handle.tracked.lock().await;
+        if tracked.job.status == JobStatus::Queued {
+            tracked.job.finish(JobStatus::Cancelled);
+            save(&self.store, &tracked.job).await;
+        }
+        info!(job = %id, "job cancellation requested");
+        Ok(Some(tracked.job.clone()))
+    }
+}
@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ldap3::SearchEntry;
+
+/// Lines are folded past this many characters, as RFC 2849 recommends.
+const LINE_WIDTH: usize = 76;
+
+pub const VERSION_LINE: &str = "version: 1\n\n";
+
+/// An entry as an LDIF content record, followed by the blank line which
+/// ends it.
+pub fn entry_record(entry: &SearchEntry) -> String {
+    let mut out = String::new();
+    write_line(&mut out, "dn", entry.dn.as_bytes());
+    for (attribute, values) in sorted(&entry.attrs) {
+        for value in values {
+            write_line(&mut out, attribute, value.as_bytes());
+        }
+    }
+    for (attribute, values) in sorted(&entry.bin_attrs) {
+        for value in values {
+            write_line(&mut out, attribute, value);
+        }
+    }
+    out.push('\n');
+    out
+}
+
+fn sorted<V>(attrs: &HashMap<String, V>) -> Vec<(&String, &V)> {
+    let mut attrs: Vec<_> = attrs.iter().collect();
+    attrs.sort_by(|a, b| a.0.cmp(b.0));
+    attrs
+}
+
+/// Writes `name: value`, or `name:: <base64>` for values which are not safe
+/// strings.
+fn write_line(out: &mut String, name: &str, value: &[u8]) {
+    let line = match std::str::from_utf8(value) {
+        Ok(text) if is_safe(text) => format!("{}: {}", name, text),
+        _ => format!("{}:: {}", name, STANDARD.encode(value)),
+    };
+    fold(out, &line);
+}
+
+/// SAFE-STRING of RFC 2849: ASCII without NUL, CR or LF, not starting with
+/// a space, `:` or `<`, and, so that it survives, not ending with a space.
+fn is_safe(value: &str) -> bool {
+    value
+        .bytes()
+        .all(|b| b.is_ascii() && b != 0 && b != b'\r' && b != b'\n')
+        && !value.starts_with([' ', ':', '<'])
+        && !value.ends_with(' ')
+}
+
+/// Continuation lines start with a single space.
+fn fold(out: &mut String, line: &str) {
+    let mut rest = line;
+    let mut width = LINE_WIDTH;
+    while rest.len() > width {
+        // Lines are ASCII unless a DN or value held UTF-8, so split on a
+        // character boundary
+        let mut at = width;
+        while !rest.is_char_boundary(at) {
+            at -= 1;
+        }
+        out.push_str(&rest[..at]);
+        out.push_str("\n ");
+        rest = &rest[at..];
+        width = LINE_WIDTH - 1;
+    }
+    out.push_str(rest);
+    out.push('\n');
+}
+
+/// The attributes of an added entry, each with its values.
+pub type Attributes = Vec<(String, Vec<Vec<u8>>)>;
+
+/// One change record. Records without a `changetype` are content records,
+/// taken as adds.
+#[derive(Debug, Clone)]
+pub struct LdifRecord {
+    /// Line the record starts on, counting from 1.
+    pub line: usize,
+    pub dn: String,
+    pub change: Change,
+}
+
+#[derive(Debug, Clone)]
+pub enum Change {
+    Add(Attributes),
+    Delete,
+    Modify(Vec<Modification>),
+    ModRdn {
+        new_rdn: String,
+        delete_old_rdn: bool,
+        new_superior: Option<String>,
+    },
+}
+
+impl Change {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Change::Add(_) => "add",
+            Change::Delete => "delete",
+            Change::Modify(_) => "modify",
+            Change::ModRdn { .. } => "modrdn",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModOp {
+    Add,
+    Delete,
+    Replace,
+    Increment,
+}
+
+impl ModOp {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ModOp::Add => "add",
+            ModOp::Delete => "delete",
+            ModOp::Replace => "replace",
+            ModOp::Increment => "increment",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Modification {
+    pub op: ModOp,
+    pub attribute: String,
+    pub values: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new(line: usize, message: impl Into<String>) -> ParseError {
+        ParseError {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+/// Parses an LDIF file (RFC 2849) into its records. Each record is parsed
+/// on its own, so a malformed one does not hide the others.
+pub fn parse(text: &str) -> Vec<Result<LdifRecord, ParseError>> {
+    let mut records = group(text).into_iter().peekable();
+
+    // An optional version line opens the file
+    if let Some(first) = records.peek_mut() {
+        if let Some((line, spec)) = first.first() {
+            if spec.to_ascii_lowercase().starts_with("version:") {
+                let (line, version) = (*line, spec["version:".len()..].trim().to_string());
+                first.remove(0);
+                if version != "1" {
+                    return vec![Err(ParseError::new(
+                        line,
+                        format!("unsupported LDIF version {}", version),
+                    ))];
+                }
+            }
+        }
+    }
+
+    records
+        .filter(|lines| !lines.is_empty())
+        .map(|lines| parse_record(&lines))
+        .collect()
+}
+
+/// Splits the text into records of unfolded lines, each with the number of
+/// the line it starts on. Comments are dropped.
+fn group(text: &str) -> Vec<Vec<(usize, String)>> {
+    let mut records: Vec<Vec<(usize, String)>> = vec![vec![]];
+    let mut in_comment = false;
+    for (index, line) in text.lines().enumerate() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let record = records.last_mut().unwrap();
+        if let Some(continuation) = line.strip_prefix(' ') {
+            if in_comment {
+                continue;
+            }
+            if let Some((_, last)) = record.last_mut() {
+                last.push_str(continuation);
+                continue;
+            }
+        }
+        in_comment = line.starts_with('#');
+        if in_comment {
+            continue;
+        }
+        if line.is_empty() {
+            if !record.is_empty() {
+                records.push(vec![]);
+            }
+            continue;
+        }
+        record.push((index + 1, line.to_string()));
+    }
+    records
+}
+
+/// Splits `name: value`, `name:: <base64>` or `name:< <url>`.
+fn split(line: usize, spec: &str) -> Result<(String, Vec<u8>), ParseError> {
+    let (name, rest) = spec
+        .split_once(':')
+        .ok_or_else(|| ParseError::new(line, format!("expected \"name: value\", got {}", spec)))?;
+    if name.is_empty() {
+        return Err(ParseError::new(line, "missing attribute name"));
+    }
+    let value = if let Some(encoded) = rest.strip_prefix(':') {
+        STANDARD
+            .decode(encoded.trim())
+            .map_err(|e| ParseError::new(line, format!("{}: invalid base64: {}", name, e)))?
+    } else if rest.starts_with('<') {
+        return Err(ParseError::new(
+            line,
+            format!("{}: values read from URLs are not supported", name),
+        ));
+    } else {
+        rest.trim_start_matches(' ').as_bytes().to_vec()
+    };
+    Ok((name.to_string(), value))
+}
+
+fn text(line: usize, name: &str, value: Vec<u8>) -> Result<String, ParseError> {
+    String::from_utf8(value).map_err(|_| ParseError::new(line, format!("{} must be UTF-8", name)))
+}
+
+fn parse_record(lines: &[(usize, String)]) -> Result<LdifRecord, ParseError> {
+    let start = lines[0].0;
+    let mut lines = lines.iter().peekable();
+
+    let (line, spec) = lines.next().unwrap();
+    let (name, value) = split(*line, spec)?;
+    if !name.eq_ignore_ascii_case("dn") {
+        return Err(ParseError::new(*line, "a record must start with dn"));
+    }
+    let dn = text(*line, "dn", value)?;
+
+    let mut change_type = None;
+    if let Some((line, spec)) = lines.peek() {
+        let (name, value) = split(*line, spec)?;
+        if name.eq_ignore_ascii_case("control") {
+            return Err(ParseError::new(*line, "controls are not supported"));
+        }
+        if name.eq_ignore_ascii_case("changetype") {
+            change_type = Some((*line, text(*line, "changetype", value)?));
+            lines.next();
+        }
+    }
+
+    let rest: Vec<&(usize, String)> = lines.collect();
+    let change = match change_type {
+        None => Change::Add(attributes(&rest)?),
+        Some((line, change_type)) => match change_type.trim().to_ascii_lowercase().as_str() {
+            "add" => Change::Add(attributes(&rest)?),
+            "delete" => match rest.first() {
+                Some((line, _)) => {
+                    return Err(ParseError::new(*line, "a delete record takes no lines"))
+                }
+                None => Change::Delete,
+            },
+            "modify" => Change::Modify(modifications(line, &rest)?),
+            "modrdn" | "moddn" => mod_rdn(line, &rest)?,
+            other => {
+                return Err(ParseError::new(
+                    line,
+                    format!("unknown changetype {}", other),
+                ))
+            }
+        },
+    };
+    if let Change::Add(attrs) = &change {
+        if attrs.is_empty() {
+            return Err(ParseError::new(start, "an add record needs attributes"));
+        }
+    }
+
+    Ok(LdifRecord {
+        line: start,
+        dn,
+        change,
+    })
+}
+
+/// Attribute lines, with the values of repeated attributes gathered.
+fn attributes(lines: &[&(usize, String)]) -> Result<Attributes, ParseError> {
+    let mut attrs: Attributes = Vec::new();
+    for (line, spec) in lines {
+        let (name, value) = split(*line, spec)?;
+        match attrs
+            .iter_mut()
+            .find(|(existing, _)| existing.eq_ignore_ascii_case(&name))
+        {
+            Some((_, values)) => values.push(value),
+            None => attrs.push((name, vec![value])),
+        }
+    }
+    Ok(attrs)
+}
+
+fn modifications(
+    start: usize,
+    lines: &[&(usize, String)],
+) -> Result<Vec<Modification>, ParseError> {
+    let mut mods = Vec::new();
+    let mut lines = lines.iter();
+    while let Some((line, spec)) = lines.next() {
+        let (op, attribute) = split(*line, spec)?;
+        let op = match op.to_ascii_lowercase().as_str() {
+            "add" => ModOp::Add,
+            "delete" => ModOp::Delete,
+            "replace" => ModOp::Replace,
+            "increment" => ModOp::Increment,
+            other => {
+                return Err(ParseError::new(
+                    *line,
+                    format!("unknown modification {}", other),
+                ))
+            }
+        };
+        let attribute = text(*line, "attribute", attribute)?.trim().to_string();
+
+        let mut values = Vec::new();
+        // The last block may end with the record rather than "-"
+        for (line, spec) in lines.by_ref() {
+            if spec == "-" {
+                break;
+            }
+            let (name, value) = split(*line, spec)?;
+            if !name.eq_ignore_ascii_case(&attribute) {
+                return Err(ParseError::new(
+                    *line,
+                    format!("expected a value of {}, got {}", attribute, name),
+                ));
+            }
+            values.push(value);
+        }
+        if op == ModOp::Increment && values.len() != 1 {
+            return Err(ParseError::new(*line, "increment takes exactly one value"));
+        }
+        mods.push(Modification {
+            op,
+            attribute,
+            values,
+        });
+    }
+    if mods.is_empty() {
+        return Err(ParseError::new(
+            start,
+            "a modify record needs modifications",
+        ));
+    }
+    Ok(mods)
+}
+
+fn mod_rdn(start: usize, lines: &[&(usize, String)]) -> Result<Change, ParseError> {
+    let mut new_rdn = None;
+    let mut delete_old_rdn = None;
+    let mut new_superior = None;
+    for (line, spec) in lines {
+        let (name, value) = split(*line, spec)?;
+        match name.to_ascii_lowercase().as_str() {
+            "newrdn" => new_rdn = Some(text(*line, "newrdn", value)?),
+            "deleteoldrdn" => {
+                delete_old_rdn = match value.as_slice() {
+                    b"0" => Some(false),
+                    b"1" => Some(true),
+                    _ => return Err(ParseError::new(*line, "deleteoldrdn must be 0 or 1")),
+                }
+            }
+            "newsuperior" => new_superior = Some(text(*line, "newsuperior", value)?),
+            other => {
+                return Err(ParseError::new(
+                    *line,
+                    format!("unexpected {} in a modrdn record", other),
+                ))
+            }
+        }
+    }
+    match (new_rdn, delete_old_rdn) {
+        (Some(new_rdn), Some(delete_old_rdn)) => Ok(Change::ModRdn {
+            new_rdn,
+            delete_old_rdn,
+            new_superior,
+        }),
+        _ => Err(ParseError::new(
+            start,
+            "a modrdn record needs newrdn and deleteoldrdn",
+        )),
+    }
+}
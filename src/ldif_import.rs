@@ -0,0 +1,443 @@
+use std::collections::{HashMap, HashSet};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ldap3::{LdapError, LdapResult, Mod};
+use rocket::data::{self, Data, FromData, ToByteUnit};
+use rocket::http::Status;
+use rocket::Request;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::{info_span, Instrument};
+
+use crate::audit::{AuditChange, Auditor};
+use crate::directory::{Connection, Directory};
+use crate::errors::{APIErrors, Rejection};
+use crate::events::EventBus;
+use crate::jobs::JobHandle;
+use crate::ldif::{self, Change, LdifRecord, ModOp, ParseError};
+use crate::metrics::ldap_timed;
+use crate::timeout::Operation;
+use crate::util::parent_dn;
+use crate::webhooks::{Event, EventKind};
+
+#[derive(FromForm, Debug, Default, Clone, Copy)]
+pub struct LdifOptions {
+    pub dry_run: Option<bool>,
+    pub continue_on_error: Option<bool>,
+    /// Runs the import as a job.
+    #[field(name = "async")]
+    pub background: Option<bool>,
+}
+
+/// Attributes of a group which list its members.
+const MEMBERSHIP_ATTRIBUTES: [&str; 3] = ["member", "uniqueMember", "memberUid"];
+
+/// Largest LDIF file accepted unless the `ldif` limit says otherwise.
+const DEFAULT_LIMIT_MIB: u64 = 10;
+
+/// The records of an LDIF upload, each parsed on its own.
+pub struct LdifDocument(pub Vec<Result<LdifRecord, ParseError>>);
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for LdifDocument {
+    type Error = String;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let reject = |status: Status, reason: String| {
+            req.local_cache(|| Rejection(Some(reason.clone())));
+            data::Outcome::Error((status, reason))
+        };
+
+        let limit = req
+            .limits()
+            .get("ldif")
+            .unwrap_or(DEFAULT_LIMIT_MIB.mebibytes());
+        match data.open(limit).into_string().await {
+            Ok(body) if body.is_complete() => {
+                data::Outcome::Success(LdifDocument(ldif::parse(&body)))
+            }
+            Ok(_) => reject(
+                Status::PayloadTooLarge,
+                format!("LDIF files are limited to {}", limit),
+            ),
+            Err(e) => reject(Status::BadRequest, e.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordStatus {
+    /// Dry run: the record would apply.
+    Valid,
+    Applied,
+    Failed,
+    /// Not tried, as an earlier record failed.
+    Skipped,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RecordResult {
+    /// 1-based position among the records.
+    pub record: usize,
+    /// Line the record starts on.
+    pub line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dn: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub changetype: Option<&'static str>,
+    pub status: RecordStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result_code: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LdifReport {
+    pub dry_run: bool,
+    /// Records in the document.
+    pub total: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    /// Records never reached because the job was cancelled.
+    pub unprocessed: usize,
+    pub records: Vec<RecordResult>,
+}
+
+/// Validates and, unless `dry_run`, applies every record in order on one
+/// connection. Like `ldapmodify`, the first failure stops the rest unless
+/// `continue_on_error` is set. Run as a job, the report stops at the record
+/// the job was cancelled on.
+///
+/// A dry run checks each record against the directory as the records before
+/// it would leave it: entries added, deleted or renamed earlier count, but
+/// the children of a renamed entry do not move with it.
+pub async fn apply(
+    ldap: &mut Connection,
+    directory: &Directory,
+    events: &EventBus,
+    auditor: &Auditor,
+    document: LdifDocument,
+    options: LdifOptions,
+    job: Option<&JobHandle>,
+) -> LdifReport {
+    let dry_run = options.dry_run.unwrap_or(false);
+    let continue_on_error = options.continue_on_error.unwrap_or(false);
+    let total = document.0.len();
+    let mut planned: HashMap<String, bool> = HashMap::new();
+    let mut stopped = false;
+    let mut records = Vec::with_capacity(document.0.len());
+
+    for (index, record) in document.0.into_iter().enumerate() {
+        if let Some(job) = job {
+            if !job.progress(index, total, &records).await {
+                break;
+            }
+        }
+        let mut result = RecordResult {
+            record: index + 1,
+            line: 0,
+            dn: None,
+            changetype: None,
+            status: RecordStatus::Failed,
+            result_code: None,
+            error: None,
+        };
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                result.line = e.line;
+                result.error = Some(e.message);
+                stopped = !continue_on_error;
+                records.push(result);
+                continue;
+            }
+        };
+        result.line = record.line;
+        result.dn = Some(record.dn.clone());
+        result.changetype = Some(record.change.name());
+
+        if stopped {
+            result.status = RecordStatus::Skipped;
+            records.push(result);
+            continue;
+        }
+
+        if dry_run {
+            match check(ldap, &mut planned, &record).await {
+                Ok(()) => result.status = RecordStatus::Valid,
+                Err(e) => result.error = Some(e),
+            }
+        } else {
+            let res = execute(ldap, &record).await;
+            let result_code = match &res {
+                Ok(res) => Some(res.rc),
+                Err(LdapError::LdapResult { result }) => Some(result.rc),
+                Err(_) => None,
+            };
+            auditor
+                .record(
+                    &format!("ldif.{}", record.change.name()),
+                    Some(record.dn.clone()),
+                    None,
+                    audit_changes(&record.change),
+                    result_code,
+                )
+                .await;
+            result.result_code = result_code;
+            match res {
+                Ok(res) if res.rc == 0 => {
+                    if let Some(data) = membership_changes(&record.change) {
+                        let event = Event::new(
+                            EventKind::GroupMembershipChanged,
+                            &directory.name,
+                            auditor,
+                            &record.dn,
+                            None,
+                            data,
+                        );
+                        events.publish_write(ldap, event).await;
+                    }
+                    result.status = RecordStatus::Applied;
+                }
+                Ok(res) => result.error = Some(describe_result(&res)),
+                Err(e) => result.error = Some(describe(e.into())),
+            }
+        }
+        if result.status == RecordStatus::Failed {
+            stopped = !continue_on_error;
+        }
+        records.push(result);
+    }
+    if let Some(job) = job {
+        job.progress(records.len(), total, &records).await;
+    }
+
+    let succeeded = records
+        .iter()
+        .filter(|r| matches!(r.status, RecordStatus::Valid | RecordStatus::Applied))
+        .count();
+    LdifReport {
+        dry_run,
+        total,
+        succeeded,
+        failed: records.len() - succeeded,
+        unprocessed: total - records.len(),
+        records,
+    }
+}
+
+/// Whether a record would apply, going by which entries exist.
+async fn check(
+    ldap: &mut Connection,
+    planned: &mut HashMap<String, bool>,
+    record: &LdifRecord,
+) -> Result<(), String> {
+    let dn = record.dn.as_str();
+    match &record.change {
+        Change::Add(_) => {
+            if exists(ldap, planned, dn).await? {
+                return Err("entry already exists".to_string());
+            }
+            if let Some(parent) = parent_dn(dn) {
+                if !exists(ldap, planned, parent).await? {
+                    return Err(format!("parent {} does not exist", parent));
+                }
+            }
+            planned.insert(dn.to_lowercase(), true);
+        }
+        Change::Delete => {
+            require(ldap, planned, dn).await?;
+            planned.insert(dn.to_lowercase(), false);
+        }
+        Change::Modify(_) => require(ldap, planned, dn).await?,
+        Change::ModRdn {
+            new_rdn,
+            new_superior,
+            ..
+        } => {
+            require(ldap, planned, dn).await?;
+            let superior = match new_superior {
+                Some(superior) => {
+                    if !exists(ldap, planned, superior).await? {
+                        return Err(format!("new superior {} does not exist", superior));
+                    }
+                    Some(superior.as_str())
+                }
+                None => parent_dn(dn),
+            };
+            let new_dn = match superior {
+                Some(superior) => format!("{},{}", new_rdn, superior),
+                None => new_rdn.clone(),
+            };
+            if exists(ldap, planned, &new_dn).await? {
+                return Err(format!("{} already exists", new_dn));
+            }
+            planned.insert(dn.to_lowercase(), false);
+            planned.insert(new_dn.to_lowercase(), true);
+        }
+    }
+    Ok(())
+}
+
+async fn exists(
+    ldap: &mut Connection,
+    planned: &HashMap<String, bool>,
+    dn: &str,
+) -> Result<bool, String> {
+    match planned.get(&dn.to_lowercase()) {
+        Some(exists) => Ok(*exists),
+        None => ldap.entry_exists(dn).await.map_err(describe),
+    }
+}
+
+async fn require(
+    ldap: &mut Connection,
+    planned: &HashMap<String, bool>,
+    dn: &str,
+) -> Result<(), String> {
+    match exists(ldap, planned, dn).await? {
+        true => Ok(()),
+        false => Err("entry does not exist".to_string()),
+    }
+}
+
+async fn execute(ldap: &mut Connection, record: &LdifRecord) -> Result<LdapResult, LdapError> {
+    let dn = record.dn.as_str();
+    match &record.change {
+        Change::Add(attrs) => {
+            let attrs: Vec<(Vec<u8>, HashSet<Vec<u8>>)> = attrs
+                .iter()
+                .map(|(name, values)| (name.as_bytes().to_vec(), values.iter().cloned().collect()))
+                .collect();
+            ldap_timed("add", ldap.op(Operation::Add).add(dn, attrs))
+                .instrument(info_span!("ldap.add", dn))
+                .await
+        }
+        Change::Delete => {
+            ldap_timed("delete", ldap.op(Operation::Delete).delete(dn))
+                .instrument(info_span!("ldap.delete", dn))
+                .await
+        }
+        Change::Modify(mods) => {
+            let mods: Vec<Mod<Vec<u8>>> = mods
+                .iter()
+                .map(|m| {
+                    let attribute = m.attribute.as_bytes().to_vec();
+                    let values = m.values.iter().cloned().collect();
+                    match m.op {
+                        ModOp::Add => Mod::Add(attribute, values),
+                        ModOp::Delete => Mod::Delete(attribute, values),
+                        ModOp::Replace => Mod::Replace(attribute, values),
+                        ModOp::Increment => Mod::Increment(attribute, m.values[0].clone()),
+                    }
+                })
+                .collect();
+            ldap_timed("modify", ldap.op(Operation::Modify).modify(dn, mods))
+                .instrument(info_span!("ldap.modify", dn))
+                .await
+        }
+        Change::ModRdn {
+            new_rdn,
+            delete_old_rdn,
+            new_superior,
+        } => {
+            ldap_timed(
+                "modifydn",
+                ldap.op(Operation::Modify).modifydn(
+                    dn,
+                    new_rdn,
+                    *delete_old_rdn,
+                    new_superior.as_deref(),
+                ),
+            )
+            .instrument(info_span!("ldap.modifydn", dn))
+            .await
+        }
+    }
+}
+
+/// The membership changes a modify record makes, as an event's data.
+fn membership_changes(change: &Change) -> Option<Value> {
+    let mods = match change {
+        Change::Modify(mods) => mods,
+        _ => return None,
+    };
+    let changes: Vec<Value> = mods
+        .iter()
+        .filter(|m| {
+            MEMBERSHIP_ATTRIBUTES
+                .iter()
+                .any(|a| a.eq_ignore_ascii_case(&m.attribute))
+        })
+        .map(|m| {
+            json!({
+                "attribute": m.attribute,
+                "operation": m.op.name(),
+                "values": audit_values(&m.values),
+            })
+        })
+        .collect();
+    match changes.is_empty() {
+        true => None,
+        false => Some(json!({ "changes": changes })),
+    }
+}
+
+fn audit_changes(change: &Change) -> Vec<AuditChange> {
+    match change {
+        Change::Add(attrs) => attrs
+            .iter()
+            .map(|(name, values)| AuditChange::new(name, "add", audit_values(values)))
+            .collect(),
+        Change::Delete => vec![],
+        Change::Modify(mods) => mods
+            .iter()
+            .map(|m| AuditChange::new(&m.attribute, m.op.name(), audit_values(&m.values)))
+            .collect(),
+        Change::ModRdn {
+            new_rdn,
+            new_superior,
+            ..
+        } => {
+            let mut changes = vec![AuditChange::new("rdn", "replace", vec![new_rdn.clone()])];
+            if let Some(superior) = new_superior {
+                changes.push(AuditChange::new(
+                    "superior",
+                    "replace",
+                    vec![superior.clone()],
+                ));
+            }
+            changes
+        }
+    }
+}
+
+/// Values as text, with binary ones in base64.
+fn audit_values(values: &[Vec<u8>]) -> Vec<String> {
+    values
+        .iter()
+        .map(|value| match std::str::from_utf8(value) {
+            Ok(text) => text.to_string(),
+            Err(_) => STANDARD.encode(value),
+        })
+        .collect()
+}
+
+fn describe_result(res: &LdapResult) -> String {
+    match res.text.is_empty() {
+        true => format!("directory returned result code {}", res.rc),
+        false => format!("directory returned result code {}: {}", res.rc, res.text),
+    }
+}
+
+fn describe(e: APIErrors) -> String {
+    match e {
+        APIErrors::Timeout => "directory operation timed out",
+        APIErrors::ConnectionError => "directory connection failed",
+        _ => "directory operation failed",
+    }
+    .to_string()
+}
@@ -0,0 +1,424 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use rand::distributions::{Alphanumeric, DistString};
+use serde::Serialize;
+use tracing::{error, info};
+
+use crate::config::{Config, CpanelConfig, MailboxDeletion, MailboxKind};
+use crate::cpanel::{CpanelClient, CpanelError};
+
+/// Template used when a request does not name one.
+pub const DEFAULT_TEMPLATE: &str = "default";
+
+/// Length of the passwords generated for mailboxes.
+const GENERATED_PASSWORD_LEN: usize = 20;
+
+/// Where a mailbox lives.
+#[derive(Debug, Clone)]
+pub struct MailboxAddress {
+    pub user: String,
+    pub domain: String,
+}
+
+impl fmt::Display for MailboxAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.user, self.domain)
+    }
+}
+
+/// A mailbox to provision for a new user.
+pub struct NewMailbox {
+    pub address: MailboxAddress,
+    pub quota_mb: u64,
+    pub password: String,
+}
+
+/// What was done to a user's mailbox, as returned to the caller.
+#[derive(Serialize, Debug, Clone)]
+pub struct Mailbox {
+    pub provider: String,
+    pub address: String,
+    pub action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota_mb: Option<u64>,
+    /// Only set when the password was generated rather than given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// Why the action failed. The directory change it went with stands.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum MailboxError {
+    /// The provider could not be reached or gave no usable answer.
+    Unavailable(String),
+    /// The provider refused the operation.
+    Refused(String),
+}
+
+impl fmt::Display for MailboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailboxError::Unavailable(reason) | MailboxError::Refused(reason) => {
+                f.write_str(reason)
+            }
+        }
+    }
+}
+
+impl From<CpanelError> for MailboxError {
+    fn from(e: CpanelError) -> Self {
+        match e {
+            CpanelError::Failed(_) => MailboxError::Refused(e.to_string()),
+            _ => MailboxError::Unavailable(e.to_string()),
+        }
+    }
+}
+
+/// A mail system users can get a mailbox on.
+#[rocket::async_trait]
+pub trait MailboxProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether the provider manages mailboxes at all. Nothing is reported or
+    /// audited for those that do not.
+    fn provisions(&self) -> bool {
+        true
+    }
+
+    async fn create(&self, mailbox: &NewMailbox) -> Result<(), MailboxError>;
+
+    /// Stops the mailbox accepting logins; mail is still delivered.
+    async fn suspend(&self, address: &MailboxAddress) -> Result<(), MailboxError>;
+
+    async fn unsuspend(&self, address: &MailboxAddress) -> Result<(), MailboxError>;
+
+    async fn delete(&self, address: &MailboxAddress) -> Result<(), MailboxError>;
+
+    async fn set_password(
+        &self,
+        address: &MailboxAddress,
+        password: &str,
+    ) -> Result<(), MailboxError>;
+}
+
+/// Mailboxes on a cPanel account, through UAPI.
+pub struct Cpanel {
+    client: CpanelClient,
+}
+
+impl Cpanel {
+    pub fn new(config: &CpanelConfig) -> Result<Cpanel, String> {
+        Ok(Cpanel {
+            client: CpanelClient::new(config)?,
+        })
+    }
+
+    /// Calls an `Email` UAPI function.
+    async fn call(&self, function: &str, params: &[(&str, &str)]) -> Result<(), MailboxError> {
+        self.client.call("Email", function, params).await?;
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl MailboxProvider for Cpanel {
+    fn name(&self) -> &'static str {
+        "cpanel"
+    }
+
+    async fn create(&self, mailbox: &NewMailbox) -> Result<(), MailboxError> {
+        let quota = mailbox.quota_mb.to_string();
+        self.call(
+            "add_pop",
+            &[
+                ("email", mailbox.address.user.as_str()),
+                ("password", mailbox.password.as_str()),
+                ("domain", mailbox.address.domain.as_str()),
+                ("quota", quota.as_str()),
+            ],
+        )
+        .await
+    }
+
+    async fn suspend(&self, address: &MailboxAddress) -> Result<(), MailboxError> {
+        let email = address.to_string();
+        self.call("suspend_login", &[("email", email.as_str())])
+            .await
+    }
+
+    async fn unsuspend(&self, address: &MailboxAddress) -> Result<(), MailboxError> {
+        let email = address.to_string();
+        self.call("unsuspend_login", &[("email", email.as_str())])
+            .await
+    }
+
+    async fn delete(&self, address: &MailboxAddress) -> Result<(), MailboxError> {
+        self.call(
+            "delete_pop",
+            &[
+                ("email", address.user.as_str()),
+                ("domain", address.domain.as_str()),
+            ],
+        )
+        .await
+    }
+
+    async fn set_password(
+        &self,
+        address: &MailboxAddress,
+        password: &str,
+    ) -> Result<(), MailboxError> {
+        self.call(
+            "passwd_pop",
+            &[
+                ("email", address.user.as_str()),
+                ("password", password),
+                ("domain", address.domain.as_str()),
+            ],
+        )
+        .await
+    }
+}
+
+/// Provisions nothing, for users who get their mail elsewhere.
+pub struct NoMailbox;
+
+#[rocket::async_trait]
+impl MailboxProvider for NoMailbox {
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    fn provisions(&self) -> bool {
+        false
+    }
+
+    async fn create(&self, _mailbox: &NewMailbox) -> Result<(), MailboxError> {
+        Ok(())
+    }
+
+    async fn suspend(&self, _address: &MailboxAddress) -> Result<(), MailboxError> {
+        Ok(())
+    }
+
+    async fn unsuspend(&self, _address: &MailboxAddress) -> Result<(), MailboxError> {
+        Ok(())
+    }
+
+    async fn delete(&self, _address: &MailboxAddress) -> Result<(), MailboxError> {
+        Ok(())
+    }
+
+    async fn set_password(
+        &self,
+        _address: &MailboxAddress,
+        _password: &str,
+    ) -> Result<(), MailboxError> {
+        Ok(())
+    }
+}
+
+/// A change to the mailbox of an existing user.
+pub enum MailboxChange<'a> {
+    Suspend,
+    Unsuspend,
+    /// The user was deleted: deletes or suspends the mailbox as the
+    /// template says.
+    Remove,
+    /// Sets the given password, or a generated one.
+    SetPassword(Option<&'a str>),
+}
+
+/// How users created from one template get their mailbox.
+#[derive(Clone)]
+pub struct Template {
+    pub provider: Arc<dyn MailboxProvider>,
+    /// Mail domain; the domain of the user's `mail` when unset.
+    pub domain: Option<String>,
+    pub quota_mb: u64,
+    pub on_delete: MailboxDeletion,
+}
+
+impl Template {
+    /// The mailbox of a user logging in as `login` with address `mail`.
+    pub fn address(&self, login: &str, mail: Option<&str>) -> Option<MailboxAddress> {
+        let user = login.split('@').next().unwrap_or(login);
+        let domain = match &self.domain {
+            Some(domain) => domain.as_str(),
+            None => mail?.rsplit_once('@')?.1,
+        };
+        if user.is_empty() || domain.is_empty() {
+            return None;
+        }
+        Some(MailboxAddress {
+            user: user.to_string(),
+            domain: domain.to_string(),
+        })
+    }
+
+    /// Provisions the mailbox of a new user, with the requested password or
+    /// a generated one. `None` when the provider manages no mailboxes.
+    pub async fn create(
+        &self,
+        login: &str,
+        mail: Option<&str>,
+        password: Option<&str>,
+    ) -> Option<Mailbox> {
+        if !self.provider.provisions() {
+            return None;
+        }
+        let address = match self.address(login, mail) {
+            Some(address) => address,
+            None => return Some(self.no_address(login, "create")),
+        };
+        let mailbox = NewMailbox {
+            address,
+            quota_mb: self.quota_mb,
+            password: match password {
+                Some(password) => password.to_string(),
+                None => generate_password(),
+            },
+        };
+        let res = self.provider.create(&mailbox).await;
+        Some(self.report(
+            &mailbox.address,
+            "create",
+            Some(self.quota_mb),
+            password.is_none().then_some(mailbox.password),
+            res,
+        ))
+    }
+
+    /// Applies a change to the mailbox of an existing user. `None` when the
+    /// provider manages no mailboxes.
+    pub async fn change(
+        &self,
+        login: &str,
+        mail: Option<&str>,
+        change: MailboxChange<'_>,
+    ) -> Option<Mailbox> {
+        if !self.provider.provisions() {
+            return None;
+        }
+        let action = match change {
+            MailboxChange::Suspend => "suspend",
+            MailboxChange::Unsuspend => "unsuspend",
+            MailboxChange::Remove => match self.on_delete {
+                MailboxDeletion::Delete => "delete",
+                MailboxDeletion::Suspend => "suspend",
+            },
+            MailboxChange::SetPassword(_) => "set_password",
+        };
+        let address = match self.address(login, mail) {
+            Some(address) => address,
+            None => return Some(self.no_address(login, action)),
+        };
+
+        let provider = self.provider.as_ref();
+        let mut generated = None;
+        let res = match change {
+            MailboxChange::Suspend => provider.suspend(&address).await,
+            MailboxChange::Unsuspend => provider.unsuspend(&address).await,
+            MailboxChange::Remove => match self.on_delete {
+                MailboxDeletion::Delete => provider.delete(&address).await,
+                MailboxDeletion::Suspend => provider.suspend(&address).await,
+            },
+            MailboxChange::SetPassword(Some(password)) => {
+                provider.set_password(&address, password).await
+            }
+            MailboxChange::SetPassword(None) => {
+                let password = generated.insert(generate_password());
+                provider.set_password(&address, password).await
+            }
+        };
+        Some(self.report(&address, action, None, generated, res))
+    }
+
+    fn report(
+        &self,
+        address: &MailboxAddress,
+        action: &'static str,
+        quota_mb: Option<u64>,
+        password: Option<String>,
+        res: Result<(), MailboxError>,
+    ) -> Mailbox {
+        let provider = self.provider.name();
+        let error = match res {
+            Ok(()) => {
+                info!(address = %address, provider, action, "mailbox updated");
+                None
+            }
+            Err(e) => {
+                error!(address = %address, provider, action, error = %e, "mailbox update failed");
+                Some(e.to_string())
+            }
+        };
+        Mailbox {
+            provider: provider.to_string(),
+            address: address.to_string(),
+            action,
+            quota_mb,
+            // A password the mailbox did not get is of no use to the caller
+            password: password.filter(|_| error.is_none()),
+            error,
+        }
+    }
+
+    fn no_address(&self, login: &str, action: &'static str) -> Mailbox {
+        Mailbox {
+            provider: self.provider.name().to_string(),
+            address: String::new(),
+            action,
+            quota_mb: None,
+            password: None,
+            error: Some(format!("no mail domain known for {}", login)),
+        }
+    }
+}
+
+pub fn generate_password() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), GENERATED_PASSWORD_LEN)
+}
+
+/// The configured user templates, by name.
+#[derive(Clone)]
+pub struct Templates(BTreeMap<String, Template>);
+
+impl Templates {
+    pub fn from_config(config: &Config) -> Result<Templates, String> {
+        let cpanel: Option<Arc<dyn MailboxProvider>> = match &config.cpanel {
+            Some(c) => Some(Arc::new(Cpanel::new(c)?)),
+            None => None,
+        };
+        let none: Arc<dyn MailboxProvider> = Arc::new(NoMailbox);
+
+        let templates = config
+            .templates
+            .iter()
+            .map(|(name, template)| {
+                let provider = match (template.mailbox, &cpanel) {
+                    (MailboxKind::Cpanel, Some(cpanel)) => cpanel.clone(),
+                    // Rejected by config validation
+                    (MailboxKind::Cpanel, None) | (MailboxKind::None, _) => none.clone(),
+                };
+                let template = Template {
+                    provider,
+                    domain: template.mail_domain.clone(),
+                    quota_mb: template.mailbox_quota_mb,
+                    on_delete: template.mailbox_on_delete,
+                };
+                (name.clone(), template)
+            })
+            .collect();
+        Ok(Templates(templates))
+    }
+
+    /// The named template, or the default one.
+    pub fn get(&self, name: Option<&str>) -> Option<&Template> {
+        self.0.get(name.unwrap_or(DEFAULT_TEMPLATE))
+    }
+}
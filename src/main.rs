@@ -1,250 +1,1772 @@
 #[macro_use]
 extern crate rocket;
-use std::{sync::Arc, vec};
-
-use ldap3::{drive, Ldap, LdapConnAsync, LdapConnSettings, LdapError};
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc, vec};
 
+use audit::{AuditChange, AuditFilter, AuditLog, AuditRecord, Auditor};
+use auth::{Admin, Caller};
+use bulk::{BulkOptions, BulkReport, BulkUsers};
+use config::Config;
+use cors::Preflight;
+use deleted::DeletedUser;
+use directory::Directory;
 use dotenv::dotenv;
+use events::EventBus;
+use export::{ExportBody, ExportFormat, ExportOptions, Exporter};
+use health::{HealthChecks, Readiness};
+use jobs::{Job, JobHandle, JobOwner, JobStatus, Jobs};
+use ldap3::LdapError;
+use ldif_import::{LdifDocument, LdifOptions, LdifReport};
+use mailbox::{Mailbox, MailboxChange, Template, Templates};
+use metrics::{ldap_timed, metrics};
+use openapi::OpenApiDocument;
+use request_id::RequestId;
 use response::ApiResponse;
 use rocket::{
-    fairing::{Fairing, Info, Kind},
-    http::Header,
+    fs::NamedFile,
+    http::ContentType,
+    response::stream::{stream, Event as StreamEvent, EventStream, TextStream},
     serde::json::Json,
-    tokio::sync::Mutex,
-    Data, Request, Response, State,
+    tokio::{select, sync::broadcast::error::RecvError},
+    Build, Either, Rocket, Shutdown, State,
 };
-use user::{UserAccount, UserParams};
+use scim::{ListQuery, ScimBase, ScimBody, ScimClient, ScimError, ScimResponse};
+use timeout::{Operation, Timeouts};
+use tracing::{error, info, info_span, Instrument};
+use user::{MailIdentity, PasswordReset, UserAccount, UserParams};
+use util::result_code;
+use webhooks::{DeadLetter, Event, EventKind, Replay, Webhooks};
 
+pub mod attributes;
+pub mod audit;
 pub mod auth;
+pub mod bulk;
+pub mod config;
+pub mod cors;
+pub mod cpanel;
+pub mod deleted;
+pub mod directory;
 pub mod errors;
+pub mod events;
+pub mod export;
+pub mod flavor;
+pub mod health;
+pub mod jobs;
+pub mod ldif;
+pub mod ldif_import;
+pub mod mailbox;
+pub mod metrics;
+pub mod openapi;
+pub mod request_id;
 pub mod response;
+pub mod scim;
+pub mod scim_filter;
+pub mod servers;
+pub mod telemetry;
+pub mod timeout;
 pub mod user;
+pub mod util;
+pub mod watcher;
+pub mod webhooks;
 
 #[derive(Clone)]
 pub struct ServerState {
-    pub ldap_server: String,
-    pub username: String,
-    pub password: String,
-    pub ldap: Arc<Mutex<Ldap>>,
+    pub config: Arc<Config>,
+    pub directories: BTreeMap<String, Arc<Directory>>,
+    pub templates: Templates,
+    pub jobs: Arc<Jobs>,
+    pub webhooks: Arc<Webhooks>,
+    pub events: Arc<EventBus>,
+    pub health: HealthChecks,
 }
 
-pub struct CORS;
+#[get("/users")]
+#[tracing::instrument(name = "request", skip_all, fields(request_id = %request_id, directory = %directory.name))]
+pub async fn get_all_users(
+    directory: &Directory,
+    timeouts: Timeouts,
+    request_id: RequestId,
+) -> ApiResponse<Vec<UserAccount>> {
+    let mut ldap = match directory.lock_ldap(timeouts).await {
+        Ok(ldap) => ldap,
+        Err(_) => return gateway_timeout(),
+    };
+    match UserAccount::fetch_all_users(&mut ldap, directory).await {
+        Ok(users) => ApiResponse::new("Success".to_string(), rocket::http::Status::Ok, Some(users)),
+        Err(errors::APIErrors::Timeout) => gateway_timeout(),
+        Err(_) => ApiResponse::new(
+            "Error Fetching Users".to_string(),
+            rocket::http::Status::InternalServerError,
+            None,
+        ),
+    }
+}
 
-#[rocket::async_trait]
-impl Fairing for CORS {
-    fn info(&self) -> Info {
-        Info {
-            name: "Add CORS headers to responses",
-            kind: Kind::Response,
+#[get("/users/export?<format>&<options..>")]
+#[tracing::instrument(name = "request", skip(directory, state), fields(request_id = %request_id, directory = %directory.name))]
+pub async fn export_users<'r>(
+    format: Option<&str>,
+    options: ExportOptions,
+    directory: &'r Directory,
+    state: &State<ServerState>,
+    caller: Caller,
+    timeouts: Timeouts,
+    request_id: RequestId,
+) -> Result<Either<ExportBody<'r>, ApiResponse<Job>>, ApiResponse<()>> {
+    let format = match format.map(ExportFormat::parse) {
+        None => ExportFormat::Ndjson,
+        Some(Some(format)) => format,
+        Some(None) => {
+            return Err(ApiResponse::new(
+                "format must be one of csv, ndjson or ldif".to_string(),
+                rocket::http::Status::BadRequest,
+                None,
+            ))
         }
+    };
+    if options.background.unwrap_or(false) {
+        let owner = JobOwner {
+            actor: caller.identity,
+            request_id: request_id.0.clone(),
+        };
+        let target = state.directories[&directory.name].clone();
+        let artifact_dir = state.config.jobs.artifact_dir.clone();
+        let work = move |job: JobHandle| async move {
+            let mut ldap = target
+                .lock_ldap(timeouts)
+                .await
+                .map_err(|_| "timed out waiting for the directory".to_string())?;
+            let path = artifact_dir.join(&job.id);
+            let result = export::run(&mut ldap, &target, format, &job, &path).await?;
+            info!(count = result.count, "export finished");
+            serde_json::to_value(result).map_err(|e| e.to_string())
+        };
+        let submitted = state
+            .jobs
+            .submit("export", &directory.name, owner, work)
+            .await;
+        return Ok(Either::Right(job_accepted(submitted)));
     }
 
-    async fn on_response<'r>(&self, _request: &'r Request<'_>, response: &mut Response<'r>) {
-        response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
-        response.set_header(Header::new(
-            "Access-Control-Allow-Methods",
-            "POST, GET, PATCH, OPTIONS, DELETE",
-        ));
-        response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
-        response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
-    }
-}
+    let exporter = Exporter::new(format, directory);
 
-async fn check_connection(state: &ServerState) -> Result<(), LdapError> {
-    let mut ldap = state.ldap.lock().await;
-    match ldap.simple_bind(&state.username, &state.password).await {
-        Ok(_) => Ok(()),
+    let mut ldap = match directory.lock_ldap(timeouts).await {
+        Ok(ldap) => ldap,
+        Err(_) => return Err(gateway_timeout()),
+    };
+    let mut entries = match export::search(&mut ldap, directory)
+        .instrument(info_span!("ldap.search", base = %directory.config.base_dn))
+        .await
+    {
+        Ok(entries) => entries,
+        Err(LdapError::Timeout { .. }) => return Err(gateway_timeout()),
         Err(_) => {
-            *ldap = establish_ldap_connection().await?;
-            Ok(())
+            return Err(ApiResponse::new(
+                "Error Exporting Users".to_string(),
+                rocket::http::Status::InternalServerError,
+                None,
+            ))
+        }
+    };
+
+    let stream = stream! {
+        // The connection stays checked out until the export is done
+        let _ldap = ldap;
+        if let Some(header) = exporter.header() {
+            yield header;
+        }
+        let mut count = 0;
+        loop {
+            match entries.next().await {
+                Ok(Some(entry)) => {
+                    count += 1;
+                    yield exporter.entry(entry, directory);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    error!(error = %e, count, "export failed");
+                    if let Some(failure) = exporter.failure(&e.to_string()) {
+                        yield failure;
+                    }
+                    break;
+                }
+            }
+        }
+        let res = entries.finish().await;
+        if res.rc != 0 {
+            error!(rc = res.rc, count, "export search failed");
+            if let Some(failure) = exporter.failure(&res.to_string()) {
+                yield failure;
+            }
+        } else {
+            info!(count, "export finished");
+        }
+    };
+    Ok(Either::Left((
+        format.content_type(),
+        TextStream(Box::pin(stream)),
+    )))
+}
+
+#[post("/users", format = "json", data = "<user>")]
+#[tracing::instrument(name = "request", skip_all, fields(request_id = %request_id, directory = %directory.name))]
+pub async fn create_user(
+    user: Json<UserParams>,
+    directory: &Directory,
+    state: &State<ServerState>,
+    auditor: Auditor,
+    timeouts: Timeouts,
+    request_id: RequestId,
+) -> ApiResponse<UserAccount> {
+    let user_data = user.into_inner();
+    let template = match find_template(state, user_data.template.as_deref()) {
+        Ok(template) => template,
+        Err(response) => return response,
+    };
+    let flavor = directory.flavor.as_ref();
+    let (user_dn, attrs) = match state
+        .config
+        .password_policy
+        .check(&user_data.password)
+        .and_then(|()| user_data.dn(directory))
+        .and_then(|dn| Ok((dn, user_data.ldap_attributes(directory)?)))
+    {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            return ApiResponse::new(e, rocket::http::Status::UnprocessableEntity, None);
+        }
+    };
+    let changes = user_data.audit_changes(flavor, &attrs);
+    let login = flavor.login_name(&user_data).to_string();
+    let mut ldap = match directory.lock_ldap(timeouts).await {
+        Ok(ldap) => ldap,
+        Err(_) => return gateway_timeout(),
+    };
+    let new_user =
+        UserAccount::create_new_user(&mut ldap, directory, template, user_data, attrs).await;
+
+    match new_user {
+        Ok(user) => {
+            let guid = UserAccount::get_guid(&mut ldap, flavor, user_dn.as_str()).await;
+            auditor
+                .record("user.create", Some(user_dn.clone()), guid, changes, Some(0))
+                .await;
+            if let Some(mailbox) = &user.mailbox {
+                auditor.record_mailbox(&user_dn, mailbox).await;
+            }
+            let event = Event::new(
+                EventKind::UserCreated,
+                &directory.name,
+                &auditor,
+                &user_dn,
+                Some(&login),
+                webhooks::account_data(&user),
+            );
+            state.events.publish_write(&mut ldap, event).await;
+            ApiResponse::new(
+                with_mailbox("Created", &user.mailbox),
+                rocket::http::Status::Created,
+                Some(user),
+            )
         }
+        Err(e) => match e {
+            errors::APIErrors::EntryExists => {
+                auditor
+                    .record("user.create", Some(user_dn), None, changes, Some(68))
+                    .await;
+                ApiResponse::new(
+                    "User Already Exists".to_string(),
+                    rocket::http::Status::Conflict,
+                    None,
+                )
+            }
+            e => {
+                auditor
+                    .record("user.create", Some(user_dn), None, changes, e.result_code())
+                    .await;
+                match e {
+                    errors::APIErrors::Timeout => gateway_timeout(),
+                    _ => ApiResponse::new(
+                        "Error Creating User".to_string(),
+                        rocket::http::Status::InternalServerError,
+                        None,
+                    ),
+                }
+            }
+        },
     }
 }
 
-async fn establish_ldap_connection() -> Result<Ldap, LdapError> {
-    // Define the LDAP server address
-    let ldap_server = std::env::var("LDAP_SERVER").unwrap();
+#[post("/users/bulk?<options..>", data = "<users>")]
+#[tracing::instrument(name = "request", skip(users, directory, state, auditor), fields(request_id = %request_id, directory = %directory.name))]
+pub async fn bulk_create_users(
+    users: BulkUsers,
+    options: BulkOptions,
+    directory: &Directory,
+    state: &State<ServerState>,
+    auditor: Auditor,
+    timeouts: Timeouts,
+    request_id: RequestId,
+) -> Either<ApiResponse<BulkReport>, ApiResponse<Job>> {
+    let dry_run = options.dry_run.unwrap_or(false);
+    if options.background.unwrap_or(false) {
+        let owner = JobOwner {
+            actor: auditor.actor().to_string(),
+            request_id: request_id.0.clone(),
+        };
+        let target = state.directories[&directory.name].clone();
+        let server = state.inner().clone();
+        let work = move |job: JobHandle| async move {
+            let mut ldap = target
+                .lock_ldap(timeouts)
+                .await
+                .map_err(|_| "timed out waiting for the directory".to_string())?;
+            let report = bulk::import(
+                &mut ldap,
+                &target,
+                &server,
+                &auditor,
+                users,
+                dry_run,
+                Some(&job),
+            )
+            .await;
+            serde_json::to_value(report).map_err(|e| e.to_string())
+        };
+        let submitted = state
+            .jobs
+            .submit("bulk_import", &directory.name, owner, work)
+            .await;
+        return Either::Right(job_accepted(submitted));
+    }
 
-    // Define the domain, username, and password
-    let username = std::env::var("LOGIN_USERNAME").unwrap();
-    let password = std::env::var("LOGIN_PASSWORD").unwrap();
+    let mut ldap = match directory.lock_ldap(timeouts).await {
+        Ok(ldap) => ldap,
+        Err(_) => return Either::Left(gateway_timeout()),
+    };
+    let report = bulk::import(&mut ldap, directory, state, &auditor, users, dry_run, None).await;
+    info!(
+        dry_run,
+        total = report.total,
+        failed = report.failed,
+        "bulk import finished"
+    );
+    let message = if dry_run {
+        "Dry Run Finished"
+    } else {
+        "Import Finished"
+    };
+    Either::Left(ApiResponse::new(
+        message.to_string(),
+        rocket::http::Status::Ok,
+        Some(report),
+    ))
+}
 
-    // Establish a connection with the LDAP server
-    let ldap_conn_settings = LdapConnSettings::new().set_starttls(true);
-    let (conn, mut ldap) = LdapConnAsync::with_settings(ldap_conn_settings, ldap_server.as_str())
-        .await
-        .unwrap();
+#[post("/ldif?<options..>", data = "<document>")]
+#[tracing::instrument(name = "request", skip(document, directory, state, auditor), fields(request_id = %auditor.request_id(), directory = %directory.name, admin = %admin.0.identity))]
+pub async fn apply_ldif(
+    document: LdifDocument,
+    options: LdifOptions,
+    directory: &Directory,
+    state: &State<ServerState>,
+    admin: Admin,
+    auditor: Auditor,
+    timeouts: Timeouts,
+) -> Either<ApiResponse<LdifReport>, ApiResponse<Job>> {
+    let dry_run = options.dry_run.unwrap_or(false);
+    if options.background.unwrap_or(false) {
+        let owner = JobOwner {
+            actor: auditor.actor().to_string(),
+            request_id: auditor.request_id().to_string(),
+        };
+        let target = state.directories[&directory.name].clone();
+        let events = state.events.clone();
+        let work = move |job: JobHandle| async move {
+            let mut ldap = target
+                .lock_ldap(timeouts)
+                .await
+                .map_err(|_| "timed out waiting for the directory".to_string())?;
+            let report = ldif_import::apply(
+                &mut ldap,
+                &target,
+                &events,
+                &auditor,
+                document,
+                options,
+                Some(&job),
+            )
+            .await;
+            serde_json::to_value(report).map_err(|e| e.to_string())
+        };
+        let submitted = state
+            .jobs
+            .submit("ldif_import", &directory.name, owner, work)
+            .await;
+        return Either::Right(job_accepted(submitted));
+    }
 
-    drive!(conn);
+    let mut ldap = match directory.lock_ldap(timeouts).await {
+        Ok(ldap) => ldap,
+        Err(_) => return Either::Left(gateway_timeout()),
+    };
+    let report = ldif_import::apply(
+        &mut ldap,
+        directory,
+        &state.events,
+        &auditor,
+        document,
+        options,
+        None,
+    )
+    .await;
+    info!(
+        dry_run,
+        total = report.total,
+        failed = report.failed,
+        "LDIF import finished"
+    );
+    let message = if dry_run {
+        "Dry Run Finished"
+    } else {
+        "Import Finished"
+    };
+    Either::Left(ApiResponse::new(
+        message.to_string(),
+        rocket::http::Status::Ok,
+        Some(report),
+    ))
+}
 
-    ldap.simple_bind(username.as_str(), password.as_str())
-        .await
-        .unwrap()
-        .success()
-        .unwrap();
+/// 202 with the queued job, or 500 when it could not be stored.
+fn job_accepted(submitted: Result<Job, String>) -> ApiResponse<Job> {
+    match submitted {
+        Ok(job) => ApiResponse::new(
+            "Job Accepted".to_string(),
+            rocket::http::Status::Accepted,
+            Some(job),
+        ),
+        Err(e) => {
+            error!(error = %e, "failed to queue job");
+            ApiResponse::new(
+                "Job Could Not Be Queued".to_string(),
+                rocket::http::Status::InternalServerError,
+                None,
+            )
+        }
+    }
+}
+
+#[get("/jobs/<id>")]
+pub async fn get_job(
+    id: &str,
+    caller: Caller,
+    admin: Option<Admin>,
+    state: &State<ServerState>,
+) -> ApiResponse<Job> {
+    match state.jobs.get(id).await {
+        // Jobs are visible to whoever started them, and to admins
+        Ok(Some(job)) if job.actor == caller.identity || admin.is_some() => {
+            ApiResponse::new("Success".to_string(), rocket::http::Status::Ok, Some(job))
+        }
+        Ok(_) => job_not_found(id),
+        Err(e) => job_store_error(e),
+    }
+}
 
-    Ok(ldap)
+/// What a job wrote, such as an export, for whoever may see the job.
+#[get("/jobs/<id>/artifact")]
+pub async fn get_job_artifact(
+    id: &str,
+    caller: Caller,
+    admin: Option<Admin>,
+    state: &State<ServerState>,
+) -> Result<(ContentType, NamedFile), ApiResponse<()>> {
+    let job = match state.jobs.get(id).await {
+        Ok(Some(job)) if job.actor == caller.identity || admin.is_some() => job,
+        Ok(_) => return Err(job_not_found(id)),
+        Err(e) => return Err(job_store_error(e)),
+    };
+    let content_type = job
+        .result
+        .as_ref()
+        .and_then(|result| result["content_type"].as_str())
+        .and_then(ContentType::parse_flexible);
+    let file = NamedFile::open(state.config.jobs.artifact_dir.join(&job.id)).await;
+    match (content_type, file) {
+        (Some(content_type), Ok(file)) => Ok((content_type, file)),
+        _ => Err(ApiResponse::new(
+            format!("Job {} Has No Artifact", id),
+            rocket::http::Status::NotFound,
+            None,
+        )),
+    }
 }
 
-#[options("/users")]
-pub fn options_users() -> ApiResponse<()> {
+#[delete("/jobs/<id>")]
+pub async fn cancel_job(
+    id: &str,
+    caller: Caller,
+    admin: Option<Admin>,
+    state: &State<ServerState>,
+) -> ApiResponse<Job> {
+    match state.jobs.get(id).await {
+        Ok(Some(job)) if job.actor == caller.identity || admin.is_some() => {}
+        Ok(_) => return job_not_found(id),
+        Err(e) => return job_store_error(e),
+    }
+    match state.jobs.cancel(id).await {
+        Ok(Some(job)) if job.status.is_finished() && job.status != JobStatus::Cancelled => {
+            ApiResponse::new(
+                "Job Already Finished".to_string(),
+                rocket::http::Status::Conflict,
+                Some(job),
+            )
+        }
+        Ok(Some(job)) => ApiResponse::new(
+            "Cancellation Requested".to_string(),
+            rocket::http::Status::Accepted,
+            Some(job),
+        ),
+        Ok(None) => job_not_found(id),
+        Err(e) => job_store_error(e),
+    }
+}
+
+fn job_not_found<T: serde::Serialize>(id: &str) -> ApiResponse<T> {
     ApiResponse::new(
-        "Options for /users".to_string(),
-        rocket::http::Status::Ok,
+        format!("Job {} Not Found", id),
+        rocket::http::Status::NotFound,
         None,
     )
 }
 
-#[options("/users/<_uname>")]
-pub fn options_users_delete(_uname: &str) -> ApiResponse<()> {
+fn job_store_error<T: serde::Serialize>(e: String) -> ApiResponse<T> {
+    error!(error = %e, "job store failed");
     ApiResponse::new(
-        "Options for /users".to_string(),
-        rocket::http::Status::Ok,
+        "Job Store Unavailable".to_string(),
+        rocket::http::Status::InternalServerError,
         None,
     )
 }
 
-#[get("/users")]
-pub async fn get_all_users(state: &State<ServerState>) -> ApiResponse<Vec<UserAccount>> {
-    if let Err(_) = check_connection(&state).await {
+/// Server-sent events for every change, made through the API or found by
+/// the change feed. `directory` and a comma-separated list of `types`
+/// narrow them down. Like the webhooks, the feed is for admins only.
+#[get("/events?<directory>&<types>")]
+pub fn get_events(
+    _admin: Admin,
+    directory: Option<String>,
+    types: Option<String>,
+    state: &State<ServerState>,
+    mut shutdown: Shutdown,
+) -> EventStream![] {
+    let mut events = state.events.subscribe();
+    let types: Option<Vec<String>> =
+        types.map(|types| types.split(',').map(|t| t.trim().to_string()).collect());
+    EventStream! {
         loop {
-            if let Ok(_) = check_connection(&state).await {
-                break;
+            let event = select! {
+                received = events.recv() => match received {
+                    Ok(event) => event,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(missed)) => {
+                        yield StreamEvent::json(&serde_json::json!({ "missed": missed }))
+                            .event("lagged");
+                        continue;
+                    }
+                },
+                _ = &mut shutdown => break,
+            };
+            if directory.as_ref().is_some_and(|d| *d != event.directory) {
+                continue;
+            }
+            if types.as_ref().is_some_and(|t| !t.iter().any(|t| t == event.kind.name())) {
+                continue;
             }
+            yield StreamEvent::json(&event).event(event.kind.name()).id(event.id.clone());
         }
     }
-    let mut ldap = state.ldap.lock().await;
-    let users = UserAccount::fetch_all_users(&mut ldap).await;
-    ApiResponse::new("Success".to_string(), rocket::http::Status::Ok, Some(users))
 }
 
-#[post("/users", format = "json", data = "<user>")]
-pub async fn create_user(
-    user: Json<UserParams>,
+#[get("/webhooks/dead-letters")]
+pub async fn get_dead_letters(
+    _admin: Admin,
     state: &State<ServerState>,
-) -> ApiResponse<UserAccount> {
-    let mut ldap = state.ldap.lock().await;
-    let user_data = user.into_inner();
-    let new_user = UserAccount::create_new_user(&mut ldap, user_data).await;
+) -> ApiResponse<Vec<DeadLetter>> {
+    match state.webhooks.dead_letters().await {
+        Ok(letters) => ApiResponse::new(
+            "Success".to_string(),
+            rocket::http::Status::Ok,
+            Some(letters),
+        ),
+        Err(e) => dead_letter_store_error(e),
+    }
+}
 
-    match new_user {
-        Ok(user) => ApiResponse::new(
-            "Created".to_string(),
-            rocket::http::Status::Created,
-            Some(user),
+#[post("/webhooks/dead-letters/<id>/replay")]
+pub async fn replay_dead_letter(
+    id: &str,
+    _admin: Admin,
+    state: &State<ServerState>,
+) -> ApiResponse<DeadLetter> {
+    match state.webhooks.replay(id).await {
+        Ok(Some(Replay::Delivered(letter))) => ApiResponse::new(
+            "Delivered".to_string(),
+            rocket::http::Status::Ok,
+            Some(letter),
         ),
-        Err(e) => match e {
-            errors::APIErrors::EntryExists => ApiResponse::new(
-                "User Already Exists".to_string(),
-                rocket::http::Status::Conflict,
-                None,
-            ),
-            _ => ApiResponse::new(
-                "Error Creating User".to_string(),
-                rocket::http::Status::InternalServerError,
-                None,
-            ),
-        },
+        Ok(Some(Replay::Failed(letter))) => ApiResponse::new(
+            "Delivery Failed".to_string(),
+            rocket::http::Status::BadGateway,
+            Some(letter),
+        ),
+        Ok(None) => dead_letter_not_found(id),
+        Err(e) => dead_letter_store_error(e),
     }
 }
 
-#[delete("/users/<uname>")]
-pub async fn delete_user(uname: String, state: &State<ServerState>) -> ApiResponse<()> {
-    let mut ldap = state.ldap.lock().await;
-    let user_dn = UserAccount::get_dn_from_uname(&mut ldap, uname.as_str()).await;
-    if user_dn.is_none() {
-        return ApiResponse::new(
+#[delete("/webhooks/dead-letters/<id>")]
+pub async fn discard_dead_letter(
+    id: &str,
+    _admin: Admin,
+    state: &State<ServerState>,
+) -> ApiResponse<()> {
+    match state.webhooks.discard(id).await {
+        Ok(true) => ApiResponse::new("Discarded".to_string(), rocket::http::Status::Ok, None),
+        Ok(false) => dead_letter_not_found(id),
+        Err(e) => dead_letter_store_error(e),
+    }
+}
+
+fn dead_letter_not_found<T: serde::Serialize>(id: &str) -> ApiResponse<T> {
+    ApiResponse::new(
+        format!("Dead Letter {} Not Found", id),
+        rocket::http::Status::NotFound,
+        None,
+    )
+}
+
+fn dead_letter_store_error<T: serde::Serialize>(e: String) -> ApiResponse<T> {
+    error!(error = %e, "webhook dead letter store failed");
+    ApiResponse::new(
+        "Dead Letter Store Unavailable".to_string(),
+        rocket::http::Status::InternalServerError,
+        None,
+    )
+}
+
+#[delete("/users/<uname>?<template>")]
+#[tracing::instrument(name = "request", skip(directory, state, auditor), fields(request_id = %request_id, directory = %directory.name))]
+pub async fn delete_user(
+    uname: String,
+    template: Option<String>,
+    directory: &Directory,
+    state: &State<ServerState>,
+    auditor: Auditor,
+    timeouts: Timeouts,
+    request_id: RequestId,
+) -> ApiResponse<Mailbox> {
+    let template = match find_template(state, template.as_deref()) {
+        Ok(template) => template,
+        Err(response) => return response,
+    };
+    let mut ldap = match directory.lock_ldap(timeouts).await {
+        Ok(ldap) => ldap,
+        Err(_) => return gateway_timeout(),
+    };
+    let (user_dn, identity) =
+        match find_user(&mut ldap, directory, &uname, "Error Deleting User").await {
+            Ok(found) => found,
+            Err(response) => return response,
+        };
+
+    let found = FoundUser {
+        dn: &user_dn,
+        uname: &uname,
+        identity: &identity,
+    };
+    match remove_user(&mut ldap, directory, state, &auditor, template, found).await {
+        Ok(mailbox) => ApiResponse::new(
+            with_mailbox("Deleted", &mailbox),
+            rocket::http::Status::Ok,
+            mailbox,
+        ),
+        Err(LdapError::Timeout { .. }) => gateway_timeout(),
+        Err(_) => ApiResponse::new(
+            "Error Deleting User".to_string(),
+            rocket::http::Status::InternalServerError,
+            None,
+        ),
+    }
+}
+
+/// A user as found by `find_user`.
+struct FoundUser<'a> {
+    dn: &'a str,
+    uname: &'a str,
+    identity: &'a MailIdentity,
+}
+
+/// Deletes the user, then removes its mailbox.
+async fn remove_user(
+    ldap: &mut directory::Connection,
+    directory: &Directory,
+    state: &ServerState,
+    auditor: &Auditor,
+    template: &Template,
+    user: FoundUser<'_>,
+) -> Result<Option<Mailbox>, LdapError> {
+    let guid = UserAccount::get_guid(ldap, directory.flavor.as_ref(), user.dn).await;
+    info!(dn = %user.dn, "deleting user");
+
+    let res = ldap_timed("delete", ldap.op(Operation::Delete).delete(user.dn))
+        .instrument(info_span!("ldap.delete"))
+        .await
+        .and_then(|res| res.success())
+        .map(|_| ());
+    auditor
+        .record(
+            "user.delete",
+            Some(user.dn.to_string()),
+            guid.clone(),
+            vec![],
+            result_code(&res),
+        )
+        .await;
+    res?;
+
+    let event = Event::new(
+        EventKind::UserDeleted,
+        &directory.name,
+        auditor,
+        user.dn,
+        Some(user.uname),
+        guid.map_or(
+            serde_json::Value::Null,
+            |guid| serde_json::json!({ "guid": guid }),
+        ),
+    );
+    state.events.publish_write(ldap, event).await;
+    Ok(change_mailbox(
+        template,
+        auditor,
+        user.dn,
+        user.uname,
+        user.identity,
+        MailboxChange::Remove,
+    )
+    .await)
+}
+
+#[post("/users/<uname>/disable?<template>")]
+#[tracing::instrument(name = "request", skip(directory, state, auditor), fields(request_id = %request_id, directory = %directory.name))]
+pub async fn disable_user(
+    uname: String,
+    template: Option<String>,
+    directory: &Directory,
+    state: &State<ServerState>,
+    auditor: Auditor,
+    timeouts: Timeouts,
+    request_id: RequestId,
+) -> ApiResponse<Mailbox> {
+    set_user_enabled(&uname, template, false, directory, state, auditor, timeouts).await
+}
+
+#[post("/users/<uname>/enable?<template>")]
+#[tracing::instrument(name = "request", skip(directory, state, auditor), fields(request_id = %request_id, directory = %directory.name))]
+pub async fn enable_user(
+    uname: String,
+    template: Option<String>,
+    directory: &Directory,
+    state: &State<ServerState>,
+    auditor: Auditor,
+    timeouts: Timeouts,
+    request_id: RequestId,
+) -> ApiResponse<Mailbox> {
+    set_user_enabled(&uname, template, true, directory, state, auditor, timeouts).await
+}
+
+async fn set_user_enabled(
+    uname: &str,
+    template: Option<String>,
+    enabled: bool,
+    directory: &Directory,
+    state: &State<ServerState>,
+    auditor: Auditor,
+    timeouts: Timeouts,
+) -> ApiResponse<Mailbox> {
+    let (done, failed) = if enabled {
+        ("Enabled", "Error Enabling User")
+    } else {
+        ("Disabled", "Error Disabling User")
+    };
+    let template = match find_template(state, template.as_deref()) {
+        Ok(template) => template,
+        Err(response) => return response,
+    };
+    let mut ldap = match directory.lock_ldap(timeouts).await {
+        Ok(ldap) => ldap,
+        Err(_) => return gateway_timeout(),
+    };
+    let (user_dn, identity) = match find_user(&mut ldap, directory, uname, failed).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+
+    let found = FoundUser {
+        dn: &user_dn,
+        uname,
+        identity: &identity,
+    };
+    match switch_enabled(
+        &mut ldap, directory, state, &auditor, template, found, enabled,
+    )
+    .await
+    {
+        Ok(mailbox) => ApiResponse::new(
+            with_mailbox(done, &mailbox),
+            rocket::http::Status::Ok,
+            mailbox,
+        ),
+        Err(LdapError::Timeout { .. }) => gateway_timeout(),
+        Err(_) => ApiResponse::new(
+            failed.to_string(),
+            rocket::http::Status::InternalServerError,
+            None,
+        ),
+    }
+}
+
+/// Enables or disables the user, then unsuspends or suspends its mailbox.
+async fn switch_enabled(
+    ldap: &mut directory::Connection,
+    directory: &Directory,
+    state: &ServerState,
+    auditor: &Auditor,
+    template: &Template,
+    user: FoundUser<'_>,
+    enabled: bool,
+) -> Result<Option<Mailbox>, LdapError> {
+    let action = if enabled {
+        "user.enable"
+    } else {
+        "user.disable"
+    };
+    let res = directory.flavor.set_enabled(ldap, user.dn, enabled).await;
+    auditor
+        .record(
+            action,
+            Some(user.dn.to_string()),
+            None,
+            vec![],
+            result_code(&res),
+        )
+        .await;
+    res?;
+
+    let (kind, data) = if enabled {
+        (
+            EventKind::UserUpdated,
+            serde_json::json!({ "changed": ["enabled"], "enabled": true }),
+        )
+    } else {
+        (
+            EventKind::UserDisabled,
+            serde_json::json!({ "enabled": false }),
+        )
+    };
+    let event = Event::new(
+        kind,
+        &directory.name,
+        auditor,
+        user.dn,
+        Some(user.uname),
+        data,
+    );
+    state.events.publish_write(ldap, event).await;
+    let change = if enabled {
+        MailboxChange::Unsuspend
+    } else {
+        MailboxChange::Suspend
+    };
+    Ok(change_mailbox(
+        template,
+        auditor,
+        user.dn,
+        user.uname,
+        user.identity,
+        change,
+    )
+    .await)
+}
+
+#[put("/users/<uname>/password", format = "json", data = "<reset>")]
+#[tracing::instrument(name = "request", skip(reset, directory, state, auditor), fields(request_id = %request_id, directory = %directory.name))]
+pub async fn reset_password(
+    uname: String,
+    reset: Json<PasswordReset>,
+    directory: &Directory,
+    state: &State<ServerState>,
+    auditor: Auditor,
+    timeouts: Timeouts,
+    request_id: RequestId,
+) -> ApiResponse<Mailbox> {
+    let reset = reset.into_inner();
+    if let Err(e) = state.config.password_policy.check(&reset.password) {
+        return ApiResponse::new(e, rocket::http::Status::UnprocessableEntity, None);
+    }
+    let template = match find_template(state, reset.template.as_deref()) {
+        Ok(template) => template,
+        Err(response) => return response,
+    };
+    let mut ldap = match directory.lock_ldap(timeouts).await {
+        Ok(ldap) => ldap,
+        Err(_) => return gateway_timeout(),
+    };
+    let (user_dn, identity) =
+        match find_user(&mut ldap, directory, &uname, "Error Changing Password").await {
+            Ok(found) => found,
+            Err(response) => return response,
+        };
+
+    let flavor = directory.flavor.as_ref();
+    let res = flavor
+        .set_password(&mut ldap, &user_dn, &reset.password)
+        .await;
+    let changes = vec![AuditChange::new(
+        flavor.password_attribute(),
+        "replace",
+        vec![reset.password.clone()],
+    )];
+    auditor
+        .record(
+            "user.password",
+            Some(user_dn.clone()),
+            None,
+            changes,
+            result_code(&res),
+        )
+        .await;
+    match res {
+        Ok(()) => {
+            let event = Event::new(
+                EventKind::UserUpdated,
+                &directory.name,
+                &auditor,
+                &user_dn,
+                Some(&uname),
+                serde_json::json!({ "changed": ["password"] }),
+            );
+            state.events.publish_write(&mut ldap, event).await;
+            let change = MailboxChange::SetPassword(reset.mailbox_password.as_deref());
+            let mailbox =
+                change_mailbox(template, &auditor, &user_dn, &uname, &identity, change).await;
+            ApiResponse::new(
+                with_mailbox("Password Changed", &mailbox),
+                rocket::http::Status::Ok,
+                mailbox,
+            )
+        }
+        Err(LdapError::Timeout { .. }) => gateway_timeout(),
+        Err(_) => ApiResponse::new(
+            "Error Changing Password".to_string(),
+            rocket::http::Status::InternalServerError,
+            None,
+        ),
+    }
+}
+
+fn find_template<'a, T: serde::Serialize>(
+    state: &'a ServerState,
+    name: Option<&str>,
+) -> Result<&'a Template, ApiResponse<T>> {
+    state.templates.get(name).ok_or_else(|| {
+        ApiResponse::new(
+            "Unknown Template".to_string(),
+            rocket::http::Status::UnprocessableEntity,
+            None,
+        )
+    })
+}
+
+/// The DN and mail identity of the user logging in as `uname`.
+async fn find_user<T: serde::Serialize>(
+    ldap: &mut directory::Connection,
+    directory: &Directory,
+    uname: &str,
+    failed: &str,
+) -> Result<(String, MailIdentity), ApiResponse<T>> {
+    let failure = |e: errors::APIErrors| match e {
+        errors::APIErrors::Timeout => gateway_timeout(),
+        errors::APIErrors::EntryNotFound => ApiResponse::new(
             "User Not Found".to_string(),
             rocket::http::Status::NotFound,
             None,
+        ),
+        _ => ApiResponse::new(
+            failed.to_string(),
+            rocket::http::Status::InternalServerError,
+            None,
+        ),
+    };
+    let user_dn = match UserAccount::get_dn_from_uname(ldap, directory, uname).await {
+        Ok(Some(user_dn)) => user_dn,
+        Ok(None) => return Err(failure(errors::APIErrors::EntryNotFound)),
+        Err(e) => return Err(failure(e)),
+    };
+    let identity = UserAccount::mail_identity(ldap, &user_dn)
+        .await
+        .map_err(failure)?;
+    Ok((user_dn, identity))
+}
+
+/// Applies a mailbox change for the user at `dn` and audits it.
+async fn change_mailbox(
+    template: &Template,
+    auditor: &Auditor,
+    dn: &str,
+    uname: &str,
+    (upn, mail): &MailIdentity,
+    change: MailboxChange<'_>,
+) -> Option<Mailbox> {
+    let login = upn.as_deref().unwrap_or(uname);
+    let mailbox = template.change(login, mail.as_deref(), change).await?;
+    auditor.record_mailbox(dn, &mailbox).await;
+    Some(mailbox)
+}
+
+/// `message`, noting a mailbox action that failed.
+fn with_mailbox(message: &str, mailbox: &Option<Mailbox>) -> String {
+    match mailbox {
+        Some(Mailbox { error: Some(_), .. }) => format!("{}, Mailbox Update Failed", message),
+        _ => message.to_string(),
+    }
+}
+
+#[get("/scim/v2/Users?<query..>")]
+#[tracing::instrument(name = "request", skip_all, fields(request_id = %request_id, directory = %directory.name))]
+pub async fn scim_get_users(
+    client: Result<ScimClient, ScimError>,
+    query: ListQuery,
+    directory: &Directory,
+    timeouts: Timeouts,
+    request_id: RequestId,
+) -> Result<ScimResponse, ScimError> {
+    let base = client?.base;
+    let mut ldap = directory.lock_ldap(timeouts).await?;
+    scim::list_users(&mut ldap, directory, &base, &query)
+        .await
+        .map(ScimResponse::ok)
+}
+
+#[get("/scim/v2/Users/<id>")]
+#[tracing::instrument(name = "request", skip(client, directory, timeouts), fields(request_id = %request_id, directory = %directory.name))]
+pub async fn scim_get_user(
+    client: Result<ScimClient, ScimError>,
+    id: &str,
+    directory: &Directory,
+    timeouts: Timeouts,
+    request_id: RequestId,
+) -> Result<ScimResponse, ScimError> {
+    let base = client?.base;
+    let mut ldap = directory.lock_ldap(timeouts).await?;
+    scim::get_user(&mut ldap, directory, &base, id)
+        .await
+        .map(ScimResponse::ok)
+}
+
+#[post("/scim/v2/Users", data = "<body>")]
+#[tracing::instrument(name = "request", skip_all, fields(request_id = %auditor.request_id(), directory = %directory.name))]
+pub async fn scim_create_user(
+    client: Result<ScimClient, ScimError>,
+    body: Result<ScimBody, ScimError>,
+    directory: &Directory,
+    state: &State<ServerState>,
+    auditor: Auditor,
+    timeouts: Timeouts,
+) -> Result<ScimResponse, ScimError> {
+    let base = client?.base;
+    let body = body?.0;
+    let mut ldap = directory.lock_ldap(timeouts).await?;
+    let user = scim::create_user(&mut ldap, directory, state, &auditor, &base, &body).await?;
+    Ok(ScimResponse::new(rocket::http::Status::Created, user))
+}
+
+#[put("/scim/v2/Users/<id>", data = "<body>")]
+#[tracing::instrument(name = "request", skip(client, body, directory, state, auditor, timeouts), fields(request_id = %auditor.request_id(), directory = %directory.name))]
+pub async fn scim_replace_user(
+    client: Result<ScimClient, ScimError>,
+    id: &str,
+    body: Result<ScimBody, ScimError>,
+    directory: &Directory,
+    state: &State<ServerState>,
+    auditor: Auditor,
+    timeouts: Timeouts,
+) -> Result<ScimResponse, ScimError> {
+    let base = client?.base;
+    let update = scim::UserUpdate::replacing(directory.flavor.as_ref(), &body?.0)?;
+    let mut ldap = directory.lock_ldap(timeouts).await?;
+    let found = ScimTarget { id, base: &base };
+    update_scim_user(&mut ldap, directory, state, &auditor, found, update)
+        .await
+        .map(ScimResponse::ok)
+}
+
+#[patch("/scim/v2/Users/<id>", data = "<body>")]
+#[tracing::instrument(name = "request", skip(client, body, directory, state, auditor, timeouts), fields(request_id = %auditor.request_id(), directory = %directory.name))]
+pub async fn scim_patch_user(
+    client: Result<ScimClient, ScimError>,
+    id: &str,
+    body: Result<ScimBody, ScimError>,
+    directory: &Directory,
+    state: &State<ServerState>,
+    auditor: Auditor,
+    timeouts: Timeouts,
+) -> Result<ScimResponse, ScimError> {
+    let base = client?.base;
+    let update = scim::UserUpdate::from_patch(directory.flavor.as_ref(), &body?.0)?;
+    let mut ldap = directory.lock_ldap(timeouts).await?;
+    let found = ScimTarget { id, base: &base };
+    update_scim_user(&mut ldap, directory, state, &auditor, found, update)
+        .await
+        .map(ScimResponse::ok)
+}
+
+#[delete("/scim/v2/Users/<id>")]
+#[tracing::instrument(name = "request", skip(client, directory, state, auditor, timeouts), fields(request_id = %auditor.request_id(), directory = %directory.name))]
+pub async fn scim_delete_user(
+    client: Result<ScimClient, ScimError>,
+    id: &str,
+    directory: &Directory,
+    state: &State<ServerState>,
+    auditor: Auditor,
+    timeouts: Timeouts,
+) -> Result<ScimResponse, ScimError> {
+    client?;
+    let template = default_template(state)?;
+    let mut ldap = directory.lock_ldap(timeouts).await?;
+    let entry = scim::find_user(&mut ldap, directory, id).await?;
+    let uname = scim::login(&entry, directory.flavor.as_ref());
+    let identity = UserAccount::mail_identity(&mut ldap, &entry.dn).await?;
+    let found = FoundUser {
+        dn: &entry.dn,
+        uname: &uname,
+        identity: &identity,
+    };
+    remove_user(&mut ldap, directory, state, &auditor, template, found).await?;
+    Ok(ScimResponse::new(
+        rocket::http::Status::NoContent,
+        serde_json::Value::Null,
+    ))
+}
+
+/// The SCIM resource a request is about.
+struct ScimTarget<'a> {
+    id: &'a str,
+    base: &'a str,
+}
+
+/// Applies a PUT or PATCH to a user. Enabling and disabling go through the
+/// same path as `/users/<uname>/enable`, mailbox included.
+async fn update_scim_user(
+    ldap: &mut directory::Connection,
+    directory: &Directory,
+    state: &ServerState,
+    auditor: &Auditor,
+    target: ScimTarget<'_>,
+    mut update: scim::UserUpdate,
+) -> Result<serde_json::Value, ScimError> {
+    let flavor = directory.flavor.as_ref();
+    if let Some(password) = &update.password {
+        state
+            .config
+            .password_policy
+            .check(password)
+            .map_err(ScimError::invalid_value)?;
+    }
+    let entry = scim::find_user(ldap, directory, target.id).await?;
+    update.retain_changes(flavor, &entry);
+    scim::modify_user(ldap, directory, auditor, &entry, &update).await?;
+    let uname = match update.attributes.get(flavor.login_attribute()) {
+        Some(Some(login)) => login.clone(),
+        _ => scim::login(&entry, flavor),
+    };
+
+    if let Some(password) = &update.password {
+        let res = flavor.set_password(ldap, &entry.dn, password).await;
+        let changes = vec![AuditChange::new(
+            flavor.password_attribute(),
+            "replace",
+            vec![password.clone()],
+        )];
+        auditor
+            .record(
+                "user.password",
+                Some(entry.dn.clone()),
+                None,
+                changes,
+                result_code(&res),
+            )
+            .await;
+        res?;
+    }
+    let changed = update.changed();
+    if !changed.is_empty() {
+        let event = Event::new(
+            EventKind::UserUpdated,
+            &directory.name,
+            auditor,
+            &entry.dn,
+            Some(&uname),
+            serde_json::json!({ "changed": changed }),
         );
+        state.events.publish_write(ldap, event).await;
     }
 
-    let user_dn = user_dn.unwrap();
-    println!("Deleting user: {}", user_dn);
+    if let Some(enabled) = update.active {
+        let template = default_template(state)?;
+        let identity = UserAccount::mail_identity(ldap, &entry.dn).await?;
+        let found = FoundUser {
+            dn: &entry.dn,
+            uname: &uname,
+            identity: &identity,
+        };
+        switch_enabled(ldap, directory, state, auditor, template, found, enabled).await?;
+    }
+    scim::get_user(ldap, directory, target.base, target.id).await
+}
 
-    let res = ldap.delete(user_dn.as_str()).await;
-    if res.is_err() {
-        return ApiResponse::new(
-            "Error Deleting User".to_string(),
+fn default_template(state: &ServerState) -> Result<&Template, ScimError> {
+    state.templates.get(None).ok_or_else(|| {
+        ScimError::new(
             rocket::http::Status::InternalServerError,
             None,
-        );
+            "no default template",
+        )
+    })
+}
+
+#[get("/scim/v2/Groups?<query..>")]
+#[tracing::instrument(name = "request", skip_all, fields(request_id = %request_id, directory = %directory.name))]
+pub async fn scim_get_groups(
+    client: Result<ScimClient, ScimError>,
+    query: ListQuery,
+    directory: &Directory,
+    timeouts: Timeouts,
+    request_id: RequestId,
+) -> Result<ScimResponse, ScimError> {
+    let base = client?.base;
+    let mut ldap = directory.lock_ldap(timeouts).await?;
+    scim::list_groups(&mut ldap, directory, &base, &query)
+        .await
+        .map(ScimResponse::ok)
+}
+
+#[get("/scim/v2/Groups/<id>?<query..>")]
+#[tracing::instrument(name = "request", skip(client, query, directory, timeouts), fields(request_id = %request_id, directory = %directory.name))]
+pub async fn scim_get_group(
+    client: Result<ScimClient, ScimError>,
+    id: &str,
+    query: ListQuery,
+    directory: &Directory,
+    timeouts: Timeouts,
+    request_id: RequestId,
+) -> Result<ScimResponse, ScimError> {
+    let base = client?.base;
+    let mut ldap = directory.lock_ldap(timeouts).await?;
+    let with_members = !query.excludes("members");
+    scim::get_group(&mut ldap, directory, &base, id, with_members)
+        .await
+        .map(ScimResponse::ok)
+}
+
+#[post("/scim/v2/Groups", data = "<body>")]
+#[tracing::instrument(name = "request", skip_all, fields(request_id = %auditor.request_id(), directory = %directory.name))]
+pub async fn scim_create_group(
+    client: Result<ScimClient, ScimError>,
+    body: Result<ScimBody, ScimError>,
+    directory: &Directory,
+    state: &State<ServerState>,
+    auditor: Auditor,
+    timeouts: Timeouts,
+) -> Result<ScimResponse, ScimError> {
+    let base = client?.base;
+    let body = body?.0;
+    let mut ldap = directory.lock_ldap(timeouts).await?;
+    let group = scim::create_group(&mut ldap, directory, state, &auditor, &base, &body).await?;
+    Ok(ScimResponse::new(rocket::http::Status::Created, group))
+}
+
+#[put("/scim/v2/Groups/<id>", data = "<body>")]
+#[tracing::instrument(name = "request", skip(client, body, directory, state, auditor, timeouts), fields(request_id = %auditor.request_id(), directory = %directory.name))]
+pub async fn scim_replace_group(
+    client: Result<ScimClient, ScimError>,
+    id: &str,
+    body: Result<ScimBody, ScimError>,
+    directory: &Directory,
+    state: &State<ServerState>,
+    auditor: Auditor,
+    timeouts: Timeouts,
+) -> Result<ScimResponse, ScimError> {
+    let base = client?.base;
+    let changes = scim::group_replacement(&body?.0)?;
+    let mut ldap = directory.lock_ldap(timeouts).await?;
+    scim::update_group(&mut ldap, directory, state, &auditor, &base, id, changes)
+        .await
+        .map(ScimResponse::ok)
+}
+
+#[patch("/scim/v2/Groups/<id>", data = "<body>")]
+#[tracing::instrument(name = "request", skip(client, body, directory, state, auditor, timeouts), fields(request_id = %auditor.request_id(), directory = %directory.name))]
+pub async fn scim_patch_group(
+    client: Result<ScimClient, ScimError>,
+    id: &str,
+    body: Result<ScimBody, ScimError>,
+    directory: &Directory,
+    state: &State<ServerState>,
+    auditor: Auditor,
+    timeouts: Timeouts,
+) -> Result<ScimResponse, ScimError> {
+    let base = client?.base;
+    let changes = scim::group_changes(&body?.0)?;
+    let mut ldap = directory.lock_ldap(timeouts).await?;
+    scim::update_group(&mut ldap, directory, state, &auditor, &base, id, changes)
+        .await
+        .map(ScimResponse::ok)
+}
+
+#[delete("/scim/v2/Groups/<id>")]
+#[tracing::instrument(name = "request", skip(client, directory, auditor, timeouts), fields(request_id = %auditor.request_id(), directory = %directory.name))]
+pub async fn scim_delete_group(
+    client: Result<ScimClient, ScimError>,
+    id: &str,
+    directory: &Directory,
+    auditor: Auditor,
+    timeouts: Timeouts,
+) -> Result<ScimResponse, ScimError> {
+    client?;
+    let mut ldap = directory.lock_ldap(timeouts).await?;
+    scim::delete_group(&mut ldap, directory, &auditor, id).await?;
+    Ok(ScimResponse::new(
+        rocket::http::Status::NoContent,
+        serde_json::Value::Null,
+    ))
+}
+
+#[get("/scim/v2/ServiceProviderConfig")]
+pub fn scim_service_provider_config(base: ScimBase) -> ScimResponse {
+    ScimResponse::ok(scim::service_provider_config(&base.0))
+}
+
+#[get("/scim/v2/ResourceTypes")]
+pub fn scim_resource_types(base: ScimBase) -> ScimResponse {
+    ScimResponse::ok(scim::discovery_list(scim::resource_types(&base.0)))
+}
+
+#[get("/scim/v2/ResourceTypes/<id>")]
+pub fn scim_resource_type(id: &str, base: ScimBase) -> Result<ScimResponse, ScimError> {
+    scim::resource_types(&base.0)
+        .into_iter()
+        .find(|resource| resource["id"] == id)
+        .map(ScimResponse::ok)
+        .ok_or_else(|| ScimError::not_found("ResourceType", id))
+}
+
+#[get("/scim/v2/Schemas")]
+pub fn scim_schemas(base: ScimBase, directory: &Directory) -> ScimResponse {
+    let schemas = scim::schemas(&base.0, directory.flavor.as_ref());
+    ScimResponse::ok(scim::discovery_list(schemas))
+}
+
+#[get("/scim/v2/Schemas/<id>")]
+pub fn scim_schema(
+    id: &str,
+    base: ScimBase,
+    directory: &Directory,
+) -> Result<ScimResponse, ScimError> {
+    scim::schemas(&base.0, directory.flavor.as_ref())
+        .into_iter()
+        .find(|schema| schema["id"] == id)
+        .map(ScimResponse::ok)
+        .ok_or_else(|| ScimError::not_found("Schema", id))
+}
+
+#[get("/deleted-users")]
+#[tracing::instrument(name = "request", skip_all, fields(request_id = %request_id, directory = %directory.name))]
+pub async fn get_deleted_users(
+    directory: &Directory,
+    timeouts: Timeouts,
+    request_id: RequestId,
+) -> ApiResponse<Vec<DeletedUser>> {
+    if !directory.flavor.supports_recycle_bin() {
+        return recycle_bin_unsupported(directory);
     }
-    match res.unwrap().success() {
-        Ok(_) => ApiResponse::new("Deleted".to_string(), rocket::http::Status::Ok, None),
+    let mut ldap = match directory.lock_ldap(timeouts).await {
+        Ok(ldap) => ldap,
+        Err(_) => return gateway_timeout(),
+    };
+    match DeletedUser::fetch_all_deleted(&mut ldap).await {
+        Ok(users) => ApiResponse::new("Success".to_string(), rocket::http::Status::Ok, Some(users)),
+        Err(errors::APIErrors::Timeout) => gateway_timeout(),
         Err(_) => ApiResponse::new(
-            "Error Deleting User".to_string(),
+            "Error Fetching Deleted Users".to_string(),
             rocket::http::Status::InternalServerError,
             None,
         ),
     }
 }
 
-#[launch]
-async fn rocket() -> _ {
-    dotenv().ok();
-
-    let ldap_server = std::env::var("LDAP_SERVER").unwrap();
-    let username = std::env::var("LOGIN_USERNAME").unwrap();
-    let password = std::env::var("LOGIN_PASSWORD").unwrap();
+#[post("/deleted-users/<guid>/restore")]
+#[tracing::instrument(name = "request", skip(directory, state, auditor), fields(request_id = %request_id, directory = %directory.name))]
+pub async fn restore_deleted_user(
+    guid: String,
+    directory: &Directory,
+    state: &State<ServerState>,
+    auditor: Auditor,
+    timeouts: Timeouts,
+    request_id: RequestId,
+) -> ApiResponse<UserAccount> {
+    if !directory.flavor.supports_recycle_bin() {
+        return recycle_bin_unsupported(directory);
+    }
+    if util::parse_guid(&guid).is_none() {
+        return ApiResponse::new(
+            format!("{} is not a GUID", guid),
+            rocket::http::Status::BadRequest,
+            None,
+        );
+    }
+    let mut ldap = match directory.lock_ldap(timeouts).await {
+        Ok(ldap) => ldap,
+        Err(_) => return gateway_timeout(),
+    };
+    let restored_dn = match DeletedUser::restore(&mut ldap, guid.as_str()).await {
+        Ok(dn) => dn,
+        Err(e) => {
+            auditor
+                .record("user.restore", None, Some(guid), vec![], e.result_code())
+                .await;
+            return match e {
+                errors::APIErrors::EntryNotFound => ApiResponse::new(
+                    "Deleted User Not Found".to_string(),
+                    rocket::http::Status::NotFound,
+                    None,
+                ),
+                errors::APIErrors::EntryExists => ApiResponse::new(
+                    "An Object Already Exists At The Original Location".to_string(),
+                    rocket::http::Status::Conflict,
+                    None,
+                ),
+                errors::APIErrors::Timeout => gateway_timeout(),
+                _ => ApiResponse::new(
+                    "Error Restoring User".to_string(),
+                    rocket::http::Status::InternalServerError,
+                    None,
+                ),
+            };
+        }
+    };
 
-    let server_state = ServerState {
-        ldap_server: ldap_server.clone(),
-        username: username.clone(),
-        password: password.clone(),
-        ldap: Arc::new(Mutex::new(establish_ldap_connection().await.unwrap())),
-    };
-
-    rocket::build()
-        .manage(server_state)
-        .attach(ConnectionFairing)
-        .attach(CORS)
-        .register("/", catchers![not_found])
-        .mount(
-            "/",
-            routes![
-                get_all_users,
-                create_user,
-                options_users,
-                options_users_delete,
-                delete_user
-            ],
+    info!(dn = %restored_dn, "restored user");
+    let changes = vec![
+        AuditChange::new("isDeleted", "delete", vec![]),
+        AuditChange::new("distinguishedName", "replace", vec![restored_dn.clone()]),
+    ];
+    auditor
+        .record(
+            "user.restore",
+            Some(restored_dn.clone()),
+            Some(guid.clone()),
+            changes,
+            Some(0),
         )
+        .await;
+
+    let user = UserAccount::fetch_user(&mut ldap, directory, restored_dn.as_str()).await;
+    let mut data = user
+        .as_ref()
+        .map_or(serde_json::json!({}), webhooks::account_data);
+    if let serde_json::Value::Object(fields) = &mut data {
+        fields.insert("restored".to_string(), true.into());
+        fields.insert("guid".to_string(), guid.into());
+    }
+    let event = Event::new(
+        EventKind::UserCreated,
+        &directory.name,
+        &auditor,
+        &restored_dn,
+        None,
+        data,
+    );
+    state.events.publish_write(&mut ldap, event).await;
+    ApiResponse::new("Restored".to_string(), rocket::http::Status::Ok, user)
 }
 
-#[catch(404)]
-fn not_found(req: &rocket::Request) -> ApiResponse<()> {
+fn gateway_timeout<T: serde::Serialize>() -> ApiResponse<T> {
     ApiResponse::new(
-        format!("{} Not Found", req.uri()),
-        rocket::http::Status::NotFound,
+        "Directory Operation Timed Out".to_string(),
+        rocket::http::Status::GatewayTimeout,
+        None,
+    )
+}
+
+fn recycle_bin_unsupported<T: serde::Serialize>(directory: &Directory) -> ApiResponse<T> {
+    ApiResponse::new(
+        format!(
+            "Deleted users are not available on {} directories",
+            directory.flavor.name()
+        ),
+        rocket::http::Status::NotImplemented,
         None,
     )
 }
 
-struct ConnectionFairing;
+#[get("/audit?<filter..>")]
+pub async fn get_audit(
+    _admin: Admin,
+    filter: AuditFilter,
+    audit_log: &State<Arc<AuditLog>>,
+) -> ApiResponse<Vec<AuditRecord>> {
+    match audit_log.query(&filter).await {
+        Ok(records) => ApiResponse::new(
+            "Success".to_string(),
+            rocket::http::Status::Ok,
+            Some(records),
+        ),
+        Err(e) => ApiResponse::new(e, rocket::http::Status::BadRequest, None),
+    }
+}
+
+#[get("/metrics")]
+pub fn get_metrics() -> (ContentType, String) {
+    let content_type = ContentType::new("text", "plain").with_params(("version", "0.0.4"));
+    (content_type, metrics().render())
+}
+
+#[get("/healthz")]
+pub fn healthz() -> ApiResponse<()> {
+    ApiResponse::new("OK".to_string(), rocket::http::Status::Ok, None)
+}
+
+/// Answers `OPTIONS` for every mounted path, CORS preflights included.
+#[options("/<_path..>")]
+pub fn preflight(_path: PathBuf, preflight: Preflight) -> Preflight {
+    preflight
+}
+
+#[get("/openapi.json")]
+pub fn get_openapi(document: &State<OpenApiDocument>) -> (ContentType, String) {
+    (ContentType::JSON, document.0.clone())
+}
+
+#[get("/docs")]
+pub fn get_docs() -> (ContentType, &'static str) {
+    (ContentType::HTML, openapi::DOCS_PAGE)
+}
+
+#[get("/readyz")]
+pub async fn readyz(state: &State<ServerState>) -> ApiResponse<Readiness> {
+    let readiness = Readiness::check(state).await;
+    if readiness.ready {
+        ApiResponse::new(
+            "Ready".to_string(),
+            rocket::http::Status::Ok,
+            Some(readiness),
+        )
+    } else {
+        ApiResponse::new(
+            "Not Ready".to_string(),
+            rocket::http::Status::ServiceUnavailable,
+            Some(readiness),
+        )
+    }
+}
+
+fn main() {
+    dotenv().ok();
+    telemetry::init();
 
-#[rocket::async_trait]
-impl Fairing for ConnectionFairing {
-    fn info(&self) -> Info {
-        Info {
-            name: "LDAP Connection Fairing",
-            kind: Kind::Request,
+    let figment = Config::figment();
+    let config = match Config::from_figment(&figment) {
+        Ok(config) => config,
+        Err(errors) => {
+            for e in &errors {
+                error!("configuration error: {}", e);
+            }
+            std::process::exit(1);
         }
+    };
+
+    // Read by the Kerberos library on GSSAPI binds. Every directory uses the
+    // same keytab, and the environment is only safe to change before the
+    // runtime's threads start.
+    if let Some(keytab) = config
+        .directories
+        .values()
+        .find_map(|directory| directory.bind.keytab.as_ref())
+    {
+        std::env::set_var("KRB5_CLIENT_KTNAME", keytab);
     }
 
-    // Increment the counter for `GET` and `POST` requests.
-    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
-        // get state
-        let state = request.guard::<&State<ServerState>>().await.unwrap();
+    // Dropping a failed launch reports why, as `#[launch]` would
+    let _ = rocket::execute(async move { rocket(figment, config).await.launch().await });
+}
 
-        // check connection
-        if let Err(_) = check_connection(&state).await {
-            loop {
-                if let Ok(_) = check_connection(&state).await {
-                    break;
-                }
+async fn rocket(figment: rocket::figment::Figment, config: Config) -> Rocket<Build> {
+    let mut directories = BTreeMap::new();
+    for (name, directory_config) in &config.directories {
+        match Directory::connect(name, directory_config).await {
+            Ok(directory) => {
+                directories.insert(name.clone(), Arc::new(directory));
             }
+            Err(e) => {
+                error!(directory = %name, servers = %directory_config.describe_servers(), "{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let templates = match Templates::from_config(&config) {
+        Ok(templates) => templates,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let jobs = match Jobs::open(&config.jobs) {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let webhooks = match Webhooks::new(&config.webhooks) {
+        Ok(webhooks) => webhooks,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let health = match HealthChecks::new(&config) {
+        Ok(health) => health,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let events = EventBus::new(webhooks.clone());
+    if config.change_feed.enabled {
+        for directory in directories.values() {
+            watcher::spawn(directory.clone(), events.clone(), &config.change_feed);
         }
     }
+
+    let server_state = ServerState {
+        directories,
+        templates,
+        jobs,
+        webhooks,
+        events,
+        health,
+        config: Arc::new(config),
+    };
+
+    let audit_path = &server_state.config.audit.log_path;
+    let audit_log = match AuditLog::open(audit_path).await {
+        Ok(audit_log) => Arc::new(audit_log),
+        Err(e) => {
+            error!("cannot open audit log {}: {}", audit_path.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut rocket = rocket::custom(figment)
+        .attach(telemetry::RequestTracer)
+        .attach(cors::Cors)
+        .attach(openapi::OpenApi)
+        .register("/", catchers![not_found, default_catcher])
+        .mount("/", directory_routes())
+        .mount("/", server_routes());
+    for directory in server_state.directories.values() {
+        rocket = rocket.mount(directory.mount_point(), directory_routes());
+    }
+
+    rocket.manage(server_state).manage(audit_log)
+}
+
+/// Routes which act on the whole service, mounted once at `/`.
+fn server_routes() -> Vec<rocket::Route> {
+    routes![
+        get_audit,
+        get_job,
+        get_job_artifact,
+        cancel_job,
+        get_dead_letters,
+        replay_dead_letter,
+        discard_dead_letter,
+        get_events,
+        get_metrics,
+        healthz,
+        readyz,
+        get_openapi,
+        get_docs,
+        preflight
+    ]
+}
+
+/// Routes which act on a single directory. They are mounted once per
+/// directory under `/d/<name>` and once at `/` for the default directory.
+fn directory_routes() -> Vec<rocket::Route> {
+    routes![
+        get_all_users,
+        export_users,
+        create_user,
+        bulk_create_users,
+        apply_ldif,
+        delete_user,
+        disable_user,
+        enable_user,
+        reset_password,
+        get_deleted_users,
+        restore_deleted_user,
+        scim_get_users,
+        scim_get_user,
+        scim_create_user,
+        scim_replace_user,
+        scim_patch_user,
+        scim_delete_user,
+        scim_get_groups,
+        scim_get_group,
+        scim_create_group,
+        scim_replace_group,
+        scim_patch_group,
+        scim_delete_group,
+        scim_service_provider_config,
+        scim_resource_types,
+        scim_resource_type,
+        scim_schemas,
+        scim_schema
+    ]
+}
+
+#[catch(default)]
+fn default_catcher(status: rocket::http::Status, req: &rocket::Request) -> ApiResponse<()> {
+    let message = match &req.local_cache(|| errors::Rejection(None)).0 {
+        Some(reason) => reason.clone(),
+        None => status.reason_lossy().to_string(),
+    };
+    ApiResponse::new(message, status, None)
+}
+
+#[catch(404)]
+fn not_found(req: &rocket::Request) -> ApiResponse<()> {
+    ApiResponse::new(
+        format!("{} Not Found", req.uri()),
+        rocket::http::Status::NotFound,
+        None,
+    )
 }
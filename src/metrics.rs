@@ -0,0 +1,221 @@
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use ldap3::result::ExopResult;
+use ldap3::{LdapError, LdapResult, SearchResult};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+pub struct Metrics {
+    registry: Registry,
+    pub http_requests: IntCounterVec,
+    pub http_request_duration: HistogramVec,
+    pub ldap_operation_duration: HistogramVec,
+    pub ldap_reconnects: IntCounterVec,
+    pub ldap_lock_wait: HistogramVec,
+    pub ldap_active_server: IntGaugeVec,
+    pub cpanel_requests: IntCounterVec,
+    pub webhook_deliveries: IntCounterVec,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("ldap_api".to_string()), None).unwrap();
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled"),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time taken to handle HTTP requests",
+            ),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+        let ldap_operation_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "ldap_operation_duration_seconds",
+                "Time taken by LDAP operations",
+            ),
+            &["operation", "result_code"],
+        )
+        .unwrap();
+        let ldap_reconnects = IntCounterVec::new(
+            Opts::new(
+                "ldap_reconnects_total",
+                "Times an LDAP connection had to be re-established",
+            ),
+            &["directory"],
+        )
+        .unwrap();
+        let ldap_lock_wait = HistogramVec::new(
+            HistogramOpts::new(
+                "ldap_lock_wait_seconds",
+                "Time spent waiting for a pooled LDAP connection",
+            )
+            .buckets(vec![
+                0.0001, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0,
+            ]),
+            &["directory"],
+        )
+        .unwrap();
+        let ldap_active_server = IntGaugeVec::new(
+            Opts::new(
+                "ldap_active_server",
+                "1 for the server a directory last connected to",
+            ),
+            &["directory", "server"],
+        )
+        .unwrap();
+        let cpanel_requests = IntCounterVec::new(
+            Opts::new("cpanel_requests_total", "Calls made to the cPanel API"),
+            &["operation", "outcome"],
+        )
+        .unwrap();
+        let webhook_deliveries = IntCounterVec::new(
+            Opts::new(
+                "webhook_deliveries_total",
+                "Webhook delivery attempts, by endpoint",
+            ),
+            &["endpoint", "outcome"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry
+            .register(Box::new(http_request_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(ldap_operation_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(ldap_reconnects.clone()))
+            .unwrap();
+        registry.register(Box::new(ldap_lock_wait.clone())).unwrap();
+        registry
+            .register(Box::new(ldap_active_server.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(cpanel_requests.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(webhook_deliveries.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            http_requests,
+            http_request_duration,
+            ldap_operation_duration,
+            ldap_reconnects,
+            ldap_lock_wait,
+            ldap_active_server,
+            cpanel_requests,
+            webhook_deliveries,
+        }
+    }
+
+    pub fn observe_request(&self, route: &str, method: &str, status: u16, elapsed: Duration) {
+        let status = status.to_string();
+        let labels = [route, method, status.as_str()];
+        self.http_requests.with_label_values(&labels).inc();
+        self.http_request_duration
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_lock_wait(&self, directory: &str, elapsed: Duration) {
+        self.ldap_lock_wait
+            .with_label_values(&[directory])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn set_active_server(&self, directory: &str, previous: Option<&str>, server: &str) {
+        if let Some(previous) = previous {
+            let _ = self
+                .ldap_active_server
+                .remove_label_values(&[directory, previous]);
+        }
+        self.ldap_active_server
+            .with_label_values(&[directory, server])
+            .set(1);
+    }
+
+    pub fn observe_cpanel(&self, operation: &str, success: bool) {
+        let outcome = if success { "success" } else { "failure" };
+        self.cpanel_requests
+            .with_label_values(&[operation, outcome])
+            .inc();
+    }
+
+    pub fn observe_webhook(&self, endpoint: &str, success: bool) {
+        let outcome = if success { "success" } else { "failure" };
+        self.webhook_deliveries
+            .with_label_values(&[endpoint, outcome])
+            .inc();
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+/// Anything an LDAP operation resolves to which carries a result code.
+pub trait ResultCode {
+    fn result_code(&self) -> u32;
+}
+
+impl ResultCode for LdapResult {
+    fn result_code(&self) -> u32 {
+        self.rc
+    }
+}
+
+impl ResultCode for SearchResult {
+    fn result_code(&self) -> u32 {
+        self.1.rc
+    }
+}
+
+impl ResultCode for ExopResult {
+    fn result_code(&self) -> u32 {
+        self.1.rc
+    }
+}
+
+/// Times an LDAP operation, labelling it with the returned result code or
+/// `error` when the operation failed before the server answered.
+pub async fn ldap_timed<T, F>(operation: &str, fut: F) -> Result<T, LdapError>
+where
+    T: ResultCode,
+    F: Future<Output = Result<T, LdapError>>,
+{
+    let start = Instant::now();
+    let res = fut.await;
+    let result_code = match &res {
+        Ok(r) => r.result_code().to_string(),
+        Err(LdapError::LdapResult { result }) => result.rc.to_string(),
+        Err(_) => "error".to_string(),
+    };
+    metrics()
+        .ldap_operation_duration
+        .with_label_values(&[operation, result_code.as_str()])
+        .observe(start.elapsed().as_secs_f64());
+    res
+}
@@ -0,0 +1,967 @@
+use std::collections::BTreeSet;
+
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::{Build, Rocket, Route};
+use serde_json::{json, Map, Value};
+use tracing::error;
+
+use crate::user::{UserAccount, UserParams};
+use crate::ServerState;
+
+/// The OpenAPI document, built once the routes are mounted.
+pub struct OpenApiDocument(pub String);
+
+/// Builds the OpenAPI document from the mounted routes on ignite. A route
+/// with no entry in `route_doc`, or an entry naming a route that is not
+/// mounted, is logged and left out; the tests below keep the two in step.
+pub struct OpenApi;
+
+#[rocket::async_trait]
+impl Fairing for OpenApi {
+    fn info(&self) -> Info {
+        Info {
+            name: "OpenAPI document",
+            kind: Kind::Ignite,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let state = rocket.state::<ServerState>();
+        let routes: Vec<&Route> = rocket.routes().collect();
+        let (document, problems) = document(&routes, state);
+        for problem in problems {
+            error!("OpenAPI document: {}", problem);
+        }
+        Ok(rocket.manage(OpenApiDocument(document.to_string())))
+    }
+}
+
+/// How one route is documented.
+struct Doc {
+    tag: &'static str,
+    summary: &'static str,
+    /// Fields of a `<params..>` query, which the route URI does not name.
+    query: Vec<Value>,
+    request: Option<Value>,
+    responses: Vec<(u16, Value)>,
+    admin: bool,
+    /// Open to admins and to clients with the SCIM bearer token.
+    scim_client: bool,
+}
+
+impl Doc {
+    fn new(tag: &'static str, summary: &'static str) -> Doc {
+        Doc {
+            tag,
+            summary,
+            query: vec![],
+            request: None,
+            responses: vec![],
+            admin: false,
+            scim_client: false,
+        }
+    }
+
+    fn query(mut self, name: &str, schema: Value, description: &str) -> Doc {
+        self.query.push(json!({
+            "name": name,
+            "in": "query",
+            "required": false,
+            "schema": schema,
+            "description": description,
+        }));
+        self
+    }
+
+    fn request(mut self, content: Value) -> Doc {
+        self.request = Some(json!({ "required": true, "content": content }));
+        self
+    }
+
+    fn respond(mut self, status: u16, response: Value) -> Doc {
+        self.responses.push((status, response));
+        self
+    }
+
+    /// Error statuses, answered with the `ApiResponse` envelope.
+    fn errors(mut self, statuses: &[u16]) -> Doc {
+        for status in statuses {
+            self.responses.push((*status, error_response()));
+        }
+        self
+    }
+
+    /// Error statuses, answered with a SCIM error.
+    fn scim_errors(mut self, statuses: &[u16]) -> Doc {
+        for status in statuses {
+            self.responses
+                .push((*status, scim_response("SCIM error", reference("ScimError"))));
+        }
+        self
+    }
+
+    fn admin(mut self) -> Doc {
+        self.admin = true;
+        self.responses.push((403, error_response()));
+        self
+    }
+
+    fn scim_client(mut self) -> Doc {
+        self.scim_client = true;
+        self.scim_errors(&[401])
+    }
+}
+
+fn reference(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn array(items: Value) -> Value {
+    json!({ "type": "array", "items": items })
+}
+
+/// The `ApiResponse` envelope with `data` of the given schema.
+fn envelope(description: &str, data: Value) -> Value {
+    json!({
+        "description": description,
+        "content": {
+            "application/json": {
+                "schema": {
+                    "allOf": [
+                        reference("ApiResponse"),
+                        { "type": "object", "properties": { "data": data } },
+                    ],
+                },
+            },
+        },
+    })
+}
+
+fn error_response() -> Value {
+    envelope(
+        "Error, with `message` saying why",
+        json!({ "type": "null" }),
+    )
+}
+
+fn scim_response(description: &str, schema: Value) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/scim+json": { "schema": schema } },
+    })
+}
+
+fn text_response(description: &str, media_types: &[&str]) -> Value {
+    let content: Map<String, Value> = media_types
+        .iter()
+        .map(|media_type| {
+            (
+                media_type.to_string(),
+                json!({ "schema": { "type": "string" } }),
+            )
+        })
+        .collect();
+    json!({ "description": description, "content": content })
+}
+
+fn json_content(schema: Value) -> Value {
+    json!({ "application/json": { "schema": schema } })
+}
+
+fn scim_content(schema: Value) -> Value {
+    json!({ "application/scim+json": { "schema": schema } })
+}
+
+fn boolean() -> Value {
+    json!({ "type": "boolean" })
+}
+
+fn integer() -> Value {
+    json!({ "type": "integer" })
+}
+
+fn string() -> Value {
+    json!({ "type": "string" })
+}
+
+/// The documentation of the route with the given handler name.
+fn route_doc(name: &str) -> Option<Doc> {
+    let ldap_errors = [500, 504];
+    let user_errors = [404, 422, 500, 504];
+    let scim_errors = [400, 403, 500, 502, 504];
+    let scim_list_query = |doc: Doc| {
+        doc.query(
+            "filter",
+            string(),
+            "SCIM filter, as in `userName eq \"bjensen\"`",
+        )
+        .query(
+            "startIndex",
+            json!({ "type": "integer", "minimum": 1 }),
+            "1-based index of the first result",
+        )
+        .query(
+            "count",
+            json!({ "type": "integer", "maximum": crate::scim::MAX_RESULTS }),
+            "Results per page",
+        )
+        .query(
+            "excludedAttributes",
+            string(),
+            "Comma-separated attributes to leave out; only `members` is honoured",
+        )
+    };
+    let doc = match name {
+        "preflight" => Doc::new(
+            "CORS",
+            "Answer OPTIONS and CORS preflights with the methods served at a path",
+        )
+        .respond(
+            204,
+            json!({ "description": "The methods, in `Allow` and `Access-Control-Allow-Methods`" }),
+        )
+        .errors(&[403, 404, 405]),
+        "get_all_users" => Doc::new("Users", "List users")
+            .respond(200, envelope("Every user", array(reference("UserAccount"))))
+            .errors(&ldap_errors),
+        "export_users" => Doc::new("Users", "Stream every user as CSV, NDJSON or LDIF")
+            .query(
+                "async",
+                boolean(),
+                "Run as a background job, whose result links to the export",
+            )
+            .respond(
+                200,
+                text_response(
+                    "The users, one per line or record",
+                    &["text/csv", "application/x-ndjson", "text/x-ldif"],
+                ),
+            )
+            .respond(202, envelope("Job accepted", reference("Job")))
+            .errors(&[400, 500, 504]),
+        "create_user" => Doc::new("Users", "Create a user")
+            .request(json_content(reference("UserParams")))
+            .respond(201, envelope("Created", reference("UserAccount")))
+            .errors(&[400, 409, 422, 500, 504]),
+        "bulk_create_users" => Doc::new("Users", "Create many users from JSON or CSV")
+            .query("dry_run", boolean(), "Validate without creating")
+            .query(
+                "async",
+                boolean(),
+                "Run as a background job; its result leaves out generated mailbox passwords",
+            )
+            .request(json!({
+                "application/json": { "schema": array(reference("UserParams")) },
+                "text/csv": { "schema": string() },
+            }))
+            .respond(200, envelope("Import finished", reference("BulkReport")))
+            .respond(202, envelope("Job accepted", reference("Job")))
+            .errors(&[400, 413, 415, 500]),
+        "apply_ldif" => Doc::new("Users", "Apply RFC 2849 change records")
+            .query("dry_run", boolean(), "Validate without applying")
+            .query(
+                "continue_on_error",
+                boolean(),
+                "Keep going after a failed record",
+            )
+            .query("async", boolean(), "Run as a background job")
+            .request(json!({ "text/x-ldif": { "schema": string() } }))
+            .respond(200, envelope("Import finished", reference("LdifReport")))
+            .respond(202, envelope("Job accepted", reference("Job")))
+            .errors(&[400, 413, 500])
+            .admin(),
+        "delete_user" => Doc::new("Users", "Delete a user and suspend or delete its mailbox")
+            .respond(200, envelope("Deleted", reference("Mailbox")))
+            .errors(&user_errors),
+        "disable_user" => Doc::new("Users", "Disable a user and suspend its mailbox")
+            .respond(200, envelope("Disabled", reference("Mailbox")))
+            .errors(&user_errors),
+        "enable_user" => Doc::new("Users", "Enable a user and unsuspend its mailbox")
+            .respond(200, envelope("Enabled", reference("Mailbox")))
+            .errors(&user_errors),
+        "reset_password" => Doc::new("Users", "Set a user's password")
+            .request(json_content(reference("PasswordReset")))
+            .respond(200, envelope("Password changed", reference("Mailbox")))
+            .errors(&[400, 404, 422, 500, 504]),
+        "get_deleted_users" => Doc::new("Deleted users", "List users in the recycle bin")
+            .respond(
+                200,
+                envelope("Deleted users", array(reference("DeletedUser"))),
+            )
+            .errors(&[500, 501, 504]),
+        "restore_deleted_user" => Doc::new("Deleted users", "Restore a deleted user")
+            .respond(200, envelope("Restored", reference("UserAccount")))
+            .errors(&[400, 404, 409, 500, 501, 504]),
+        "get_job" => Doc::new("Jobs", "Poll a background job")
+            .respond(200, envelope("The job", reference("Job")))
+            .errors(&[404, 500]),
+        "get_job_artifact" => Doc::new("Jobs", "Download what a job wrote, such as an export")
+            .respond(
+                200,
+                text_response(
+                    "The artifact, in the content type the job's result names",
+                    &["text/csv", "application/x-ndjson", "text/x-ldif"],
+                ),
+            )
+            .errors(&[404, 500]),
+        "cancel_job" => Doc::new("Jobs", "Cancel a background job")
+            .respond(202, envelope("Cancellation requested", reference("Job")))
+            .respond(409, envelope("Job already finished", reference("Job")))
+            .errors(&[404, 500]),
+        "get_events" => Doc::new("Events", "Stream changes as server-sent events")
+            .respond(
+                200,
+                json!({
+                    "description": "One event per change; `lagged` events count those missed",
+                    "content": { "text/event-stream": { "schema": reference("Event") } },
+                }),
+            )
+            .admin(),
+        "get_dead_letters" => Doc::new(
+            "Webhooks",
+            "List webhook deliveries which used up their attempts",
+        )
+        .respond(
+            200,
+            envelope("Dead letters", array(reference("DeadLetter"))),
+        )
+        .errors(&[500])
+        .admin(),
+        "replay_dead_letter" => Doc::new("Webhooks", "Deliver a dead letter again")
+            .respond(200, envelope("Delivered", reference("DeadLetter")))
+            .respond(502, envelope("Delivery failed", reference("DeadLetter")))
+            .errors(&[404, 500])
+            .admin(),
+        "discard_dead_letter" => Doc::new("Webhooks", "Discard a dead letter")
+            .respond(200, envelope("Discarded", json!({ "type": "null" })))
+            .errors(&[404, 500])
+            .admin(),
+        "get_audit" => Doc::new("Audit", "Search the audit log")
+            .query("actor", string(), "Who made the change")
+            .query("action", string(), "As in `user.create`")
+            .query("target", string(), "DN or GUID of the entry changed")
+            .query("request_id", string(), "Request the change was made in")
+            .query(
+                "since",
+                json!({ "type": "string", "format": "date-time" }),
+                "Earliest time, inclusive",
+            )
+            .query(
+                "until",
+                json!({ "type": "string", "format": "date-time" }),
+                "Latest time, inclusive",
+            )
+            .query("success", boolean(), "Only successful or failed changes")
+            .query(
+                "limit",
+                json!({ "type": "integer", "minimum": 1 }),
+                "Most records returned",
+            )
+            .respond(
+                200,
+                envelope("Matching records", array(reference("AuditRecord"))),
+            )
+            .errors(&[400])
+            .admin(),
+        "scim_get_users" => scim_list_query(Doc::new("SCIM", "List or filter users"))
+            .respond(
+                200,
+                scim_response("ListResponse of User", reference("ScimListResponse")),
+            )
+            .scim_errors(&scim_errors)
+            .scim_client(),
+        "scim_get_user" => Doc::new("SCIM", "Read a user")
+            .respond(200, scim_response("User", reference("ScimUser")))
+            .scim_errors(&[404, 500, 502, 504])
+            .scim_client(),
+        "scim_create_user" => Doc::new("SCIM", "Create a user")
+            .request(scim_content(reference("ScimUser")))
+            .respond(201, scim_response("User", reference("ScimUser")))
+            .scim_errors(&[400, 403, 409, 413, 500, 502, 504])
+            .scim_client(),
+        "scim_replace_user" => Doc::new("SCIM", "Replace a user")
+            .request(scim_content(reference("ScimUser")))
+            .respond(200, scim_response("User", reference("ScimUser")))
+            .scim_errors(&[400, 403, 404, 409, 413, 500, 502, 504])
+            .scim_client(),
+        "scim_patch_user" => Doc::new("SCIM", "Patch a user")
+            .request(scim_content(reference("ScimPatchOp")))
+            .respond(200, scim_response("User", reference("ScimUser")))
+            .scim_errors(&[400, 403, 404, 409, 413, 500, 502, 504])
+            .scim_client(),
+        "scim_delete_user" => Doc::new("SCIM", "Delete a user")
+            .respond(204, json!({ "description": "Deleted" }))
+            .scim_errors(&[403, 404, 500, 502, 504])
+            .scim_client(),
+        "scim_get_groups" => scim_list_query(Doc::new("SCIM", "List or filter groups"))
+            .respond(
+                200,
+                scim_response("ListResponse of Group", reference("ScimListResponse")),
+            )
+            .scim_errors(&scim_errors)
+            .scim_client(),
+        "scim_get_group" => Doc::new("SCIM", "Read a group")
+            .query(
+                "excludedAttributes",
+                string(),
+                "`members` to leave out the members",
+            )
+            .respond(200, scim_response("Group", reference("ScimGroup")))
+            .scim_errors(&[403, 404, 500, 502, 504])
+            .scim_client(),
+        "scim_create_group" => Doc::new("SCIM", "Create a group")
+            .request(scim_content(reference("ScimGroup")))
+            .respond(201, scim_response("Group", reference("ScimGroup")))
+            .scim_errors(&[400, 403, 409, 413, 500, 502, 504])
+            .scim_client(),
+        "scim_replace_group" => Doc::new("SCIM", "Replace a group's name and members")
+            .request(scim_content(reference("ScimGroup")))
+            .respond(200, scim_response("Group", reference("ScimGroup")))
+            .scim_errors(&[400, 403, 404, 409, 413, 500, 502, 504])
+            .scim_client(),
+        "scim_patch_group" => Doc::new("SCIM", "Patch a group")
+            .request(scim_content(reference("ScimPatchOp")))
+            .respond(200, scim_response("Group", reference("ScimGroup")))
+            .scim_errors(&[400, 403, 404, 409, 413, 500, 502, 504])
+            .scim_client(),
+        "scim_delete_group" => Doc::new("SCIM", "Delete a group")
+            .respond(204, json!({ "description": "Deleted" }))
+            .scim_errors(&[403, 404, 500, 502, 504])
+            .scim_client(),
+        "scim_service_provider_config" => Doc::new("SCIM", "Features supported").respond(
+            200,
+            scim_response("ServiceProviderConfig", json!({ "type": "object" })),
+        ),
+        "scim_resource_types" => Doc::new("SCIM", "Resource types").respond(
+            200,
+            scim_response(
+                "ListResponse of ResourceType",
+                reference("ScimListResponse"),
+            ),
+        ),
+        "scim_resource_type" => Doc::new("SCIM", "One resource type")
+            .respond(
+                200,
+                scim_response("ResourceType", json!({ "type": "object" })),
+            )
+            .scim_errors(&[404]),
+        "scim_schemas" => Doc::new("SCIM", "Schemas").respond(
+            200,
+            scim_response("ListResponse of Schema", reference("ScimListResponse")),
+        ),
+        "scim_schema" => Doc::new("SCIM", "One schema")
+            .respond(200, scim_response("Schema", json!({ "type": "object" })))
+            .scim_errors(&[404]),
+        "get_metrics" => Doc::new("Operations", "Prometheus metrics").respond(
+            200,
+            text_response("Metrics in the text exposition format", &["text/plain"]),
+        ),
+        "healthz" => Doc::new("Operations", "Liveness")
+            .respond(200, envelope("Alive", json!({ "type": "null" }))),
+        "readyz" => Doc::new(
+            "Operations",
+            "Readiness of the directories and mailbox provider",
+        )
+        .respond(200, envelope("Ready", reference("Readiness")))
+        .respond(503, envelope("Not ready", reference("Readiness"))),
+        "get_openapi" => Doc::new("Operations", "This document").respond(
+            200,
+            text_response("OpenAPI 3.1 document", &["application/json"]),
+        ),
+        "get_docs" => Doc::new("Operations", "Interactive API documentation")
+            .respond(200, text_response("HTML page", &["text/html"])),
+        _ => return None,
+    };
+    Some(doc)
+}
+
+/// Every handler name `route_doc` knows, to find entries for routes that
+/// are gone.
+const DOCUMENTED: &[&str] = &[
+    "preflight",
+    "get_all_users",
+    "export_users",
+    "create_user",
+    "bulk_create_users",
+    "apply_ldif",
+    "delete_user",
+    "disable_user",
+    "enable_user",
+    "reset_password",
+    "get_deleted_users",
+    "restore_deleted_user",
+    "get_job",
+    "get_job_artifact",
+    "cancel_job",
+    "get_events",
+    "get_dead_letters",
+    "replay_dead_letter",
+    "discard_dead_letter",
+    "get_audit",
+    "scim_get_users",
+    "scim_get_user",
+    "scim_create_user",
+    "scim_replace_user",
+    "scim_patch_user",
+    "scim_delete_user",
+    "scim_get_groups",
+    "scim_get_group",
+    "scim_create_group",
+    "scim_replace_group",
+    "scim_patch_group",
+    "scim_delete_group",
+    "scim_service_provider_config",
+    "scim_resource_types",
+    "scim_resource_type",
+    "scim_schemas",
+    "scim_schema",
+    "get_metrics",
+    "healthz",
+    "readyz",
+    "get_openapi",
+    "get_docs",
+];
+
+/// The path of a mounted route in OpenAPI form, with the routes of every
+/// directory's mount point folded into `/d/{directory}`, and the names of
+/// its path and query parameters. Trailing `<params..>` are left to the
+/// route's `Doc`.
+fn route_path(route: &Route) -> (String, Vec<String>, Vec<String>) {
+    let base = route.uri.base();
+    let base = match base.strip_prefix(crate::directory::MOUNT_PREFIX) {
+        Some(_) => "/d/{directory}".to_string(),
+        None => base.trim_end_matches('/').to_string(),
+    };
+    let unmounted = route.uri.unmounted_origin.path().as_str();
+    let mut path_params = Vec::new();
+    let segments: Vec<String> = unmounted
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(
+            |segment| match segment.strip_prefix('<').and_then(|s| s.strip_suffix('>')) {
+                Some(param) => {
+                    let param = param.trim_end_matches("..").trim_start_matches('_');
+                    path_params.push(param.to_string());
+                    format!("{{{}}}", param)
+                }
+                None => segment.to_string(),
+            },
+        )
+        .collect();
+    let query_params = route
+        .uri
+        .unmounted_origin
+        .query()
+        .map(|query| {
+            query
+                .as_str()
+                .split('&')
+                .filter_map(|field| field.strip_prefix('<')?.strip_suffix('>'))
+                .filter(|field| !field.ends_with(".."))
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    (
+        format!("{}/{}", base, segments.join("/")),
+        path_params,
+        query_params,
+    )
+}
+
+fn operation(route: &Route, doc: Doc, path_params: &[String], query_params: &[String]) -> Value {
+    let mut parameters: Vec<Value> = path_params
+        .iter()
+        .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": string() }))
+        .collect();
+    parameters.extend(
+        query_params.iter().map(
+            |name| json!({ "name": name, "in": "query", "required": false, "schema": string() }),
+        ),
+    );
+    parameters.extend(doc.query);
+
+    let responses: Map<String, Value> = doc
+        .responses
+        .into_iter()
+        .map(|(status, response)| (status.to_string(), response))
+        .collect();
+    let security = match (doc.admin, doc.scim_client) {
+        (_, true) => json!([{ "scimBearer": [] }, { "remoteUser": [], "remoteGroups": [] }]),
+        (true, false) => json!([{ "remoteUser": [], "remoteGroups": [] }]),
+        (false, false) => json!([{ "remoteUser": [] }]),
+    };
+    let mut operation = json!({
+        "operationId": route.name.as_deref().unwrap_or_default(),
+        "tags": [doc.tag],
+        "summary": doc.summary,
+        "parameters": parameters,
+        "responses": responses,
+        "security": security,
+    });
+    if let Some(request) = doc.request {
+        operation["requestBody"] = request;
+    }
+    operation
+}
+
+/// The OpenAPI document for the mounted routes, and what keeps it from
+/// matching them.
+fn document(routes: &[&Route], state: Option<&ServerState>) -> (Value, Vec<String>) {
+    let mut problems = Vec::new();
+    let mut paths = Map::new();
+    let mut mounted = BTreeSet::new();
+    let mut directory_routes = false;
+    for route in routes {
+        let name = route.name.as_deref().unwrap_or_default();
+        mounted.insert(name);
+        let Some(doc) = route_doc(name) else {
+            problems.push(format!(
+                "route {} {} ({}) is not documented",
+                route.method, route.uri, name
+            ));
+            continue;
+        };
+        let (path, mut path_params, query_params) = route_path(route);
+        if path.starts_with("/d/{directory}") {
+            directory_routes = true;
+            path_params.insert(0, "directory".to_string());
+        }
+        let method = route.method.as_str().to_lowercase();
+        let item = paths
+            .entry(path)
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+            .unwrap();
+        if !item.contains_key(&method) {
+            item.insert(method, operation(route, doc, &path_params, &query_params));
+        }
+    }
+    for name in DOCUMENTED {
+        if route_doc(name).is_none() {
+            problems.push(format!("{} is listed but has no documentation", name));
+        } else if !mounted.contains(name) {
+            problems.push(format!("{} is documented but not mounted", name));
+        }
+    }
+
+    // The directory a `/d/{directory}` path acts on
+    let names: Vec<&String> = state
+        .map(|state| state.directories.keys().collect())
+        .unwrap_or_default();
+    if directory_routes && !names.is_empty() {
+        for item in paths.values_mut() {
+            for operation in item.as_object_mut().unwrap().values_mut() {
+                for parameter in operation["parameters"].as_array_mut().unwrap() {
+                    if parameter["name"] == "directory" && parameter["in"] == "path" {
+                        parameter["schema"] = json!({ "type": "string", "enum": names });
+                    }
+                }
+            }
+        }
+    }
+
+    let document = json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "LDAP API",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Manages users of Active Directory and OpenLDAP directories. \
+                Routes under `/d/{directory}` act on that directory; the same routes \
+                at `/` act on the default one.",
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas(state),
+            "securitySchemes": {
+                "remoteUser": {
+                    "type": "apiKey",
+                    "in": "header",
+                    "name": "X-Remote-User",
+                    "description": "Set by the authenticating proxy in front of the service, and \
+                        only honoured on connections from `auth.trusted_proxies`. \
+                        Other callers are identified by their address.",
+                },
+                "scimBearer": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "`scim.bearer_token`, for SCIM provisioning clients.",
+                },
+                "remoteGroups": {
+                    "type": "apiKey",
+                    "in": "header",
+                    "name": "X-Remote-Groups",
+                    "description": "Comma-separated groups of the caller, set by the proxy. \
+                        Admin-only routes need a caller or group listed in `auth`.",
+                },
+            },
+        },
+    });
+    (document, problems)
+}
+
+fn object(properties: Value, required: &[&str]) -> Value {
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+/// Fields from the attribute maps of every directory.
+fn mapped_fields(state: Option<&ServerState>) -> BTreeSet<String> {
+    state
+        .map(|state| {
+            state
+                .directories
+                .values()
+                .flat_map(|directory| directory.config.attributes.fields().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn schemas(state: Option<&ServerState>) -> Value {
+    let mapped = mapped_fields(state);
+    let mut account = Map::new();
+    for field in UserAccount::field_names() {
+        let schema = match field.as_str() {
+            "mailbox" => reference("Mailbox"),
+            _ => array(string()),
+        };
+        account.insert(field, schema);
+    }
+    let mut params = Map::new();
+    for field in UserParams::field_names() {
+        let schema = match field.as_str() {
+            "create_mailbox" | "create_cpanel_account" => boolean(),
+            _ => string(),
+        };
+        params.insert(field, schema);
+    }
+    for field in &mapped {
+        account.insert(field.clone(), json!({}));
+        params.insert(field.clone(), json!({}));
+    }
+    let nullable = |schema: Value| json!({ "oneOf": [schema, { "type": "null" }] });
+    let date_time = json!({ "type": "string", "format": "date-time" });
+
+    json!({
+        "ApiResponse": object(json!({
+            "message": string(),
+            "status": integer(),
+            "data": {},
+            "request_id": string(),
+        }), &["message", "status", "data"]),
+        "UserAccount": {
+            "type": "object",
+            "description": "Attributes of a user entry. Mapped fields come from the directory's `attributes`.",
+            "properties": account,
+        },
+        "UserParams": {
+            "type": "object",
+            "properties": params,
+            "required": ["cn", "givenName", "sn", "displayName", "userPrincipalName", "sAMAccountName", "mail", "password"],
+        },
+        "PasswordReset": object(json!({
+            "password": string(),
+            "mailbox_password": string(),
+            "template": string(),
+        }), &["password"]),
+        "Mailbox": object(json!({
+            "provider": string(),
+            "address": string(),
+            "action": string(),
+            "quota_mb": integer(),
+            "password": string(),
+            "error": string(),
+        }), &["provider", "address", "action"]),
+        "BulkReport": object(json!({
+            "dry_run": boolean(),
+            "total": integer(),
+            "succeeded": integer(),
+            "failed": integer(),
+            "unprocessed": integer(),
+            "rows": array(object(json!({
+                "row": integer(),
+                "login": string(),
+                "dn": string(),
+                "status": { "enum": ["valid", "created", "exists", "failed"] },
+                "error": string(),
+                "mailbox": reference("Mailbox"),
+            }), &["row", "status"])),
+        }), &["dry_run", "total", "succeeded", "failed", "unprocessed", "rows"]),
+        "LdifReport": object(json!({
+            "dry_run": boolean(),
+            "total": integer(),
+            "succeeded": integer(),
+            "failed": integer(),
+            "unprocessed": integer(),
+            "records": array(object(json!({
+                "record": integer(),
+                "line": integer(),
+                "dn": string(),
+                "changetype": string(),
+                "status": { "enum": ["valid", "applied", "failed", "skipped"] },
+                "result_code": integer(),
+                "error": string(),
+            }), &["record", "line", "status"])),
+        }), &["dry_run", "total", "succeeded", "failed", "unprocessed", "records"]),
+        "Job": object(json!({
+            "id": string(),
+            "kind": string(),
+            "directory": string(),
+            "actor": string(),
+            "request_id": string(),
+            "status": { "enum": ["queued", "running", "succeeded", "failed", "cancelled"] },
+            "created_at": date_time,
+            "started_at": date_time,
+            "finished_at": date_time,
+            "progress": object(json!({ "done": integer(), "total": integer() }), &["done", "total"]),
+            "result": {},
+            "error": string(),
+        }), &["id", "kind", "directory", "actor", "request_id", "status", "created_at", "progress"]),
+        "Event": object(json!({
+            "id": string(),
+            "type": { "enum": ["user.created", "user.updated", "user.disabled", "user.deleted", "group.membership_changed"] },
+            "occurred_at": date_time,
+            "source": { "enum": ["api", "directory"] },
+            "directory": string(),
+            "actor": string(),
+            "request_id": string(),
+            "dn": string(),
+            "login": string(),
+            "data": {},
+        }), &["id", "type", "occurred_at", "source", "directory", "actor", "request_id", "dn"]),
+        "DeadLetter": object(json!({
+            "id": string(),
+            "endpoint": string(),
+            "event": reference("Event"),
+            "attempts": integer(),
+            "last_error": string(),
+            "failed_at": date_time,
+        }), &["id", "endpoint", "event", "attempts", "last_error", "failed_at"]),
+        "DeletedUser": object(json!({
+            "objectGUID": string(),
+            "distinguishedName": nullable(string()),
+            "cn": nullable(string()),
+            "sAMAccountName": nullable(string()),
+            "userPrincipalName": nullable(string()),
+            "lastKnownParent": nullable(string()),
+            "lastKnownRDN": nullable(string()),
+            "whenChanged": nullable(string()),
+        }), &["objectGUID"]),
+        "AuditRecord": object(json!({
+            "timestamp": date_time,
+            "request_id": string(),
+            "actor": string(),
+            "action": string(),
+            "target_dn": nullable(string()),
+            "target_guid": nullable(string()),
+            "changes": array(object(json!({
+                "attribute": string(),
+                "operation": string(),
+                "values": array(string()),
+            }), &["attribute", "operation", "values"])),
+            "result_code": nullable(integer()),
+            "success": boolean(),
+            "error": string(),
+        }), &["timestamp", "request_id", "actor", "action", "changes", "success"]),
+        "Readiness": object(json!({
+            "ready": boolean(),
+            "dependencies": array(object(json!({
+                "name": string(),
+                "healthy": boolean(),
+                "detail": string(),
+                "latency_ms": integer(),
+                "server": string(),
+            }), &["name", "healthy", "detail", "latency_ms"])),
+        }), &["ready", "dependencies"]),
+        "ScimUser": {
+            "type": "object",
+            "description": "A SCIM User (RFC 7643 section 4.1), with the enterprise extension. See `/scim/v2/Schemas`.",
+            "properties": {
+                "schemas": array(string()),
+                "id": string(),
+                "userName": string(),
+                "name": object(json!({ "givenName": string(), "familyName": string(), "formatted": string() }), &[]),
+                "displayName": string(),
+                "title": string(),
+                "emails": array(object(json!({ "value": string(), "type": string(), "primary": boolean() }), &["value"])),
+                "phoneNumbers": array(object(json!({ "value": string(), "type": string() }), &["value"])),
+                "active": boolean(),
+                "password": { "type": "string", "writeOnly": true },
+                "meta": {},
+            },
+            "required": ["userName"],
+        },
+        "ScimGroup": {
+            "type": "object",
+            "properties": {
+                "schemas": array(string()),
+                "id": string(),
+                "displayName": string(),
+                "members": array(object(json!({
+                    "value": string(),
+                    "display": string(),
+                    "type": string(),
+                    "$ref": string(),
+                }), &["value"])),
+                "meta": {},
+            },
+            "required": ["displayName"],
+        },
+        "ScimPatchOp": object(json!({
+            "schemas": array(string()),
+            "Operations": array(object(json!({
+                "op": { "enum": ["add", "remove", "replace"] },
+                "path": string(),
+                "value": {},
+            }), &["op"])),
+        }), &["schemas", "Operations"]),
+        "ScimListResponse": object(json!({
+            "schemas": array(string()),
+            "totalResults": integer(),
+            "startIndex": integer(),
+            "itemsPerPage": integer(),
+            "Resources": array(json!({ "type": "object" })),
+        }), &["schemas", "totalResults", "Resources"]),
+        "ScimError": object(json!({
+            "schemas": array(string()),
+            "status": string(),
+            "scimType": string(),
+            "detail": string(),
+        }), &["schemas", "status"]),
+    })
+}
+
+/// The page at `/docs`, rendering `/openapi.json` with RapiDoc.
+pub const DOCS_PAGE: &str = r#"<!doctype html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <title>LDAP API</title>
+  <script type="module" src="https://unpkg.com/rapidoc@9.3.4/dist/rapidoc-min.js"
+    integrity="sha384-MDSxszbIJtK/9YakZ3tvi2bK6LaaHnB8+Hd2/fCfih0tLa+Mqlv6HO0bZdrICjjG"
+    crossorigin="anonymous"></script>
+</head>
+<body>
+  <rapi-doc spec-url="openapi.json" render-style="read" show-header="false" allow-try="true"></rapi-doc>
+</body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directory::MOUNT_PREFIX;
+
+    #[test]
+    fn every_route_is_documented() {
+        let rocket = rocket::build()
+            .mount("/", crate::directory_routes())
+            .mount("/", crate::server_routes())
+            .mount(format!("{}main", MOUNT_PREFIX), crate::directory_routes());
+        let routes: Vec<&Route> = rocket.routes().collect();
+
+        let (document, problems) = document(&routes, None);
+        assert!(problems.is_empty(), "{}", problems.join("\n"));
+        assert!(document["paths"]["/d/{directory}/users"]["get"].is_object());
+    }
+}
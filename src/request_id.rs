@@ -0,0 +1,46 @@
+use std::fmt;
+
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+
+const MAX_PROPAGATED_LEN: usize = 128;
+
+/// Identifier shared by every log line and audit record produced while
+/// handling a single request. Taken from an incoming `X-Request-Id` header
+/// when the caller sent a sane one, generated otherwise.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn of(request: &Request<'_>) -> RequestId {
+        request
+            .local_cache(|| match request.headers().get_one("X-Request-Id") {
+                Some(id) if is_valid(id) => RequestId(id.to_string()),
+                _ => RequestId(uuid::Uuid::new_v4().to_string()),
+            })
+            .clone()
+    }
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_PROPAGATED_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RequestId::of(request))
+    }
+}
@@ -5,11 +5,15 @@ use rocket::serde::json::Json;
 use serde::Serialize;
 use rocket::Request;
 
+use crate::request_id::RequestId;
+
 #[derive(Serialize)]
 pub struct ApiResponseInner<T> {
     pub message: String,
     pub status: u16,
     pub data: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
 }
 
 pub struct ApiResponse<T> {
@@ -24,6 +28,7 @@ impl<T: Serialize> ApiResponse<T> {
                 message,
                 status: status.code,
                 data,
+                request_id: None,
             },
             status,
         }
@@ -31,7 +36,8 @@ impl<T: Serialize> ApiResponse<T> {
 }
 
 impl<'r, T: Serialize> Responder<'r, 'static> for ApiResponse<T> {
-    fn respond_to(self, _: &'r Request<'_>) -> Result<'static> {
+    fn respond_to(mut self, request: &'r Request<'_>) -> Result<'static> {
+        self.inner.request_id = Some(RequestId::of(request).0);
         let json = Json(self.inner);
 
         let mut buffer = Vec::new();
@@ -0,0 +1,1959 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use ldap3::{LdapError, LdapResult, Mod, Scope, SearchEntry};
+use rocket::data::{self, Data, FromData, ToByteUnit};
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder};
+use rocket::{Request, Response};
+use serde_json::{json, Map, Value};
+use tracing::{info_span, Instrument};
+
+use crate::audit::{AuditChange, Auditor};
+use crate::auth::Admin;
+use crate::directory::{Connection, Directory};
+use crate::errors::APIErrors;
+use crate::flavor::DirectoryFlavor;
+use crate::mailbox::generate_password;
+use crate::metrics::ldap_timed;
+use crate::scim_filter::{self, CompareOp, Filter, Path};
+use crate::timeout::Operation;
+use crate::user::{UserAccount, UserParams, USER_ATTRIBUTES};
+use crate::util::{parent_dn, result_code};
+use crate::webhooks::{account_data, Event, EventKind};
+use crate::ServerState;
+
+pub const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+pub const ENTERPRISE_USER_SCHEMA: &str =
+    "urn:ietf:params:scim:schemas:extension:enterprise:2.0:User";
+pub const GROUP_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+const LIST_RESPONSE_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+const PATCH_OP_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:PatchOp";
+const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+const SERVICE_PROVIDER_CONFIG_SCHEMA: &str =
+    "urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig";
+const RESOURCE_TYPE_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:ResourceType";
+const SCHEMA_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Schema";
+
+/// Most resources returned in one page of a list.
+pub const MAX_RESULTS: usize = 200;
+
+/// An LDAP filter no entry matches, for IDs that cannot exist.
+const NOTHING: &str = "(!(objectClass=*))";
+
+/// Attributes read from group members.
+const MEMBER_ATTRIBUTES: [&str; 3] = ["cn", "displayName", "objectClass"];
+
+/// A SCIM error (RFC 7644 section 3.12).
+#[derive(Debug)]
+pub struct ScimError {
+    pub status: Status,
+    pub scim_type: Option<&'static str>,
+    pub detail: String,
+}
+
+pub type ScimResult<T> = Result<T, ScimError>;
+
+impl ScimError {
+    pub fn new(status: Status, scim_type: Option<&'static str>, detail: impl Into<String>) -> Self {
+        ScimError {
+            status,
+            scim_type,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn not_found(resource: &str, id: &str) -> Self {
+        ScimError::new(
+            Status::NotFound,
+            None,
+            format!("{} {} not found", resource, id),
+        )
+    }
+
+    pub fn invalid_value(detail: impl Into<String>) -> Self {
+        ScimError::new(Status::BadRequest, Some("invalidValue"), detail)
+    }
+
+    pub fn invalid_path(detail: impl Into<String>) -> Self {
+        ScimError::new(Status::BadRequest, Some("invalidPath"), detail)
+    }
+
+    pub fn invalid_filter(detail: impl Into<String>) -> Self {
+        ScimError::new(Status::BadRequest, Some("invalidFilter"), detail)
+    }
+
+    pub fn invalid_syntax(detail: impl Into<String>) -> Self {
+        ScimError::new(Status::BadRequest, Some("invalidSyntax"), detail)
+    }
+}
+
+impl From<LdapError> for ScimError {
+    fn from(e: LdapError) -> Self {
+        let result = match e {
+            LdapError::Timeout { .. } => {
+                return ScimError::new(Status::GatewayTimeout, None, "directory timed out")
+            }
+            LdapError::LdapResult { result } => result,
+            e => return ScimError::new(Status::BadGateway, None, e.to_string()),
+        };
+        let detail = match result.text.is_empty() {
+            true => format!("directory returned result code {}", result.rc),
+            false => format!(
+                "directory returned result code {}: {}",
+                result.rc, result.text
+            ),
+        };
+        match result.rc {
+            // noSuchObject
+            32 => ScimError::new(Status::NotFound, None, detail),
+            // insufficientAccessRights
+            50 => ScimError::new(Status::Forbidden, None, detail),
+            // entryAlreadyExists
+            68 => ScimError::new(Status::Conflict, Some("uniqueness"), detail),
+            // constraintViolation, invalidAttributeSyntax, objectClassViolation
+            // and the like
+            19 | 21 | 64 | 65 | 67 => ScimError::invalid_value(detail),
+            _ => ScimError::new(Status::InternalServerError, None, detail),
+        }
+    }
+}
+
+impl From<APIErrors> for ScimError {
+    fn from(e: APIErrors) -> Self {
+        match e {
+            APIErrors::Timeout => {
+                ScimError::new(Status::GatewayTimeout, None, "directory timed out")
+            }
+            APIErrors::EntryExists => ScimError::new(
+                Status::Conflict,
+                Some("uniqueness"),
+                "userName is already taken",
+            ),
+            APIErrors::EntryNotFound => ScimError::new(Status::NotFound, None, "entry not found"),
+            APIErrors::ConnectionError => {
+                ScimError::new(Status::BadGateway, None, "directory unavailable")
+            }
+            _ => ScimError::new(
+                Status::InternalServerError,
+                None,
+                "directory request failed",
+            ),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ScimError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut body = json!({
+            "schemas": [ERROR_SCHEMA],
+            "status": self.status.code.to_string(),
+            "detail": self.detail,
+        });
+        if let Some(scim_type) = self.scim_type {
+            body["scimType"] = json!(scim_type);
+        }
+        let mut response = ScimResponse::new(self.status, body).respond_to(request)?;
+        if self.status == Status::Unauthorized {
+            response.set_header(Header::new("WWW-Authenticate", "Bearer"));
+        }
+        Ok(response)
+    }
+}
+
+/// A caller allowed to read and provision users and groups through SCIM:
+/// an admin, or a client sending `scim.bearer_token` as a bearer token.
+/// Handlers take it as a `Result` to turn others away with a SCIM error.
+pub struct ScimClient {
+    /// As in `ScimBase`.
+    pub base: String,
+}
+
+/// Compares secrets in time independent of where they differ.
+fn same_secret(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ScimClient {
+    type Error = ScimError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(state) = request.rocket().state::<ServerState>() else {
+            let error = ScimError::new(Status::InternalServerError, None, "no server state");
+            return Outcome::Error((error.status, error));
+        };
+        let ScimBase(base) = request.guard::<ScimBase>().await.unwrap();
+        let bearer = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if let (Some(bearer), Some(token)) = (bearer, &state.config.scim.bearer_token) {
+            if same_secret(bearer.trim(), token.expose()) {
+                return Outcome::Success(ScimClient { base });
+            }
+        }
+        if request.guard::<Admin>().await.is_success() {
+            return Outcome::Success(ScimClient { base });
+        }
+        let error = ScimError::new(
+            Status::Unauthorized,
+            None,
+            "an admin or the SCIM bearer token is required",
+        );
+        Outcome::Error((error.status, error))
+    }
+}
+
+/// A SCIM resource or message, sent as `application/scim+json`.
+pub struct ScimResponse {
+    status: Status,
+    body: Value,
+}
+
+impl ScimResponse {
+    pub fn new(status: Status, body: Value) -> ScimResponse {
+        ScimResponse { status, body }
+    }
+
+    pub fn ok(body: Value) -> ScimResponse {
+        ScimResponse::new(Status::Ok, body)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ScimResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response.status(self.status);
+        if !self.body.is_null() {
+            let body = self.body.to_string();
+            response
+                .header(ContentType::new("application", "scim+json"))
+                .sized_body(body.len(), std::io::Cursor::new(body));
+        }
+        if let Some(location) = self.body.pointer("/meta/location").and_then(Value::as_str) {
+            response.header(Header::new("Location", location.to_string()));
+        }
+        response.ok()
+    }
+}
+
+/// Where the SCIM endpoints of the requested directory live, as in
+/// `/d/<directory>/scim/v2`, for `meta.location` and `$ref`.
+pub struct ScimBase(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ScimBase {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let path = request.uri().path().as_str();
+        let base = match path.find("/scim/v2") {
+            Some(at) => &path[..at + "/scim/v2".len()],
+            None => "/scim/v2",
+        };
+        Outcome::Success(ScimBase(base.to_string()))
+    }
+}
+
+/// A JSON request body, whatever its content type: SCIM clients send
+/// `application/scim+json`. Handlers take it as a `Result` to answer bad
+/// bodies with a SCIM error.
+pub struct ScimBody(pub Value);
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for ScimBody {
+    type Error = ScimError;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let limit = req.limits().get("json").unwrap_or(1.mebibytes());
+        let text = match data.open(limit).into_string().await {
+            Ok(text) if text.is_complete() => text.into_inner(),
+            Ok(_) => {
+                let detail = format!("body is larger than {}", limit);
+                let error = ScimError::new(Status::PayloadTooLarge, None, detail);
+                return data::Outcome::Error((Status::PayloadTooLarge, error));
+            }
+            Err(e) => {
+                let error = ScimError::invalid_syntax(e.to_string());
+                return data::Outcome::Error((Status::BadRequest, error));
+            }
+        };
+        let error = match serde_json::from_str(&text) {
+            Ok(Value::Object(body)) => {
+                return data::Outcome::Success(ScimBody(Value::Object(body)))
+            }
+            Ok(_) => ScimError::invalid_syntax("body must be a JSON object"),
+            Err(e) => ScimError::invalid_syntax(format!("invalid JSON: {}", e)),
+        };
+        data::Outcome::Error((Status::BadRequest, error))
+    }
+}
+
+/// Query parameters of a list request.
+#[derive(FromForm, Debug, Default)]
+pub struct ListQuery {
+    pub filter: Option<String>,
+    #[field(name = "startIndex")]
+    pub start_index: Option<usize>,
+    pub count: Option<usize>,
+    #[field(name = "excludedAttributes")]
+    pub excluded_attributes: Option<String>,
+}
+
+impl ListQuery {
+    pub fn excludes(&self, attribute: &str) -> bool {
+        self.excluded_attributes.as_deref().is_some_and(|excluded| {
+            excluded
+                .split(',')
+                .any(|a| a.trim().eq_ignore_ascii_case(attribute))
+        })
+    }
+}
+
+/// The page of `items`, which hold every match, that the query asks for,
+/// with the index it starts at. Indexes count from 1.
+pub fn page<T>(query: &ListQuery, items: Vec<T>) -> (usize, Vec<T>) {
+    let start = query.start_index.unwrap_or(1).max(1);
+    let count = query.count.unwrap_or(MAX_RESULTS).min(MAX_RESULTS);
+    let items = items.into_iter().skip(start - 1).take(count).collect();
+    (start, items)
+}
+
+pub fn list_response(total: usize, start: usize, resources: Vec<Value>) -> Value {
+    json!({
+        "schemas": [LIST_RESPONSE_SCHEMA],
+        "totalResults": total,
+        "startIndex": start,
+        "itemsPerPage": resources.len(),
+        "Resources": resources,
+    })
+}
+
+fn first<'a>(entry: &'a SearchEntry, attribute: &str) -> Option<&'a String> {
+    entry.attrs.get(attribute)?.first()
+}
+
+/// A generalized time, as in `whenCreated`, in RFC 3339.
+fn timestamp(value: &str) -> Option<String> {
+    let digits = value.get(..14)?;
+    let time = NaiveDateTime::parse_from_str(digits, "%Y%m%d%H%M%S").ok()?;
+    Some(Utc.from_utc_datetime(&time).to_rfc3339())
+}
+
+fn meta(entry: &SearchEntry, resource_type: &str, location: String) -> Value {
+    let read = |names: [&str; 2]| {
+        names
+            .iter()
+            .find_map(|name| first(entry, name))
+            .and_then(|v| timestamp(v))
+    };
+    let mut meta = json!({ "resourceType": resource_type, "location": location });
+    if let Some(created) = read(["whenCreated", "createTimestamp"]) {
+        meta["created"] = json!(created);
+    }
+    if let Some(modified) = read(["whenChanged", "modifyTimestamp"]) {
+        meta["lastModified"] = json!(modified);
+    }
+    meta
+}
+
+/// Fields that are set only when the entry has a value for them.
+fn insert(object: &mut Map<String, Value>, name: &str, value: Option<&String>) {
+    if let Some(value) = value {
+        object.insert(name.to_string(), json!(value));
+    }
+}
+
+/// A user entry as a SCIM User. `managers` holds the IDs of the managers
+/// found by `manager_ids`.
+pub fn user_resource(
+    entry: &SearchEntry,
+    directory: &Directory,
+    base: &str,
+    managers: &HashMap<String, String>,
+) -> Value {
+    let flavor = directory.flavor.as_ref();
+    let id = flavor.guid(entry).unwrap_or_default();
+    let mut user = Map::new();
+    user.insert("id".to_string(), json!(id));
+    insert(
+        &mut user,
+        "userName",
+        first(entry, flavor.login_attribute()),
+    );
+
+    let mut name = Map::new();
+    insert(&mut name, "givenName", first(entry, "givenName"));
+    insert(&mut name, "familyName", first(entry, "sn"));
+    insert(&mut name, "formatted", first(entry, "cn"));
+    if !name.is_empty() {
+        user.insert("name".to_string(), Value::Object(name));
+    }
+    insert(&mut user, "displayName", first(entry, "displayName"));
+    insert(&mut user, "title", first(entry, "title"));
+    if let Some(mail) = first(entry, "mail") {
+        user.insert(
+            "emails".to_string(),
+            json!([{ "value": mail, "type": "work", "primary": true }]),
+        );
+    }
+    if let Some(phone) = first(entry, "telephoneNumber") {
+        user.insert(
+            "phoneNumbers".to_string(),
+            json!([{ "value": phone, "type": "work" }]),
+        );
+    }
+    user.insert("active".to_string(), json!(flavor.is_enabled(entry)));
+
+    let mut enterprise = Map::new();
+    for (name, attribute) in flavor.enterprise_attributes() {
+        insert(&mut enterprise, name, first(entry, attribute));
+    }
+    if let Some(manager) = first(entry, "manager").and_then(|dn| managers.get(dn)) {
+        enterprise.insert(
+            "manager".to_string(),
+            json!({ "value": manager, "$ref": format!("{}/Users/{}", base, manager) }),
+        );
+    }
+    let mut schemas = vec![USER_SCHEMA];
+    if !enterprise.is_empty() {
+        schemas.push(ENTERPRISE_USER_SCHEMA);
+        user.insert(
+            ENTERPRISE_USER_SCHEMA.to_string(),
+            Value::Object(enterprise),
+        );
+    }
+    user.insert("schemas".to_string(), json!(schemas));
+    user.insert(
+        "meta".to_string(),
+        meta(entry, "User", format!("{}/Users/{}", base, id)),
+    );
+    Value::Object(user)
+}
+
+/// The IDs of the managers of `entries`, by DN.
+pub async fn manager_ids(
+    ldap: &mut Connection,
+    flavor: &dyn DirectoryFlavor,
+    entries: &[SearchEntry],
+) -> HashMap<String, String> {
+    let mut ids = HashMap::new();
+    for dn in entries.iter().filter_map(|entry| first(entry, "manager")) {
+        if !ids.contains_key(dn) {
+            if let Some(id) = UserAccount::get_guid(ldap, flavor, dn).await {
+                ids.insert(dn.clone(), id);
+            }
+        }
+    }
+    ids
+}
+
+/// SCIM user attributes kept in one LDAP attribute each, by their key: the
+/// path in lower case, with extension attributes under `enterprise.`.
+fn user_attributes(flavor: &dyn DirectoryFlavor) -> Vec<(String, &'static str)> {
+    let mut attributes = vec![
+        ("username".to_string(), flavor.login_attribute()),
+        ("name.givenname".to_string(), "givenName"),
+        ("name.familyname".to_string(), "sn"),
+        ("displayname".to_string(), "displayName"),
+        ("title".to_string(), "title"),
+        ("emails".to_string(), "mail"),
+        ("phonenumbers".to_string(), "telephoneNumber"),
+    ];
+    attributes.extend(
+        flavor
+            .enterprise_attributes()
+            .iter()
+            .map(|(name, attribute)| (format!("enterprise.{}", name.to_lowercase()), *attribute)),
+    );
+    attributes
+}
+
+/// Read-only or unsupported attributes, which are accepted and ignored.
+const IGNORED_USER_KEYS: [&str; 6] = [
+    "schemas",
+    "id",
+    "meta",
+    "externalid",
+    "groups",
+    "name.formatted",
+];
+
+/// The key of a path, as in `user_attributes`. The filter of a multi-valued
+/// attribute is dropped, as only one value of each is kept.
+fn user_key(path: &Path) -> ScimResult<String> {
+    let prefix = match path.schema.as_deref() {
+        None => "",
+        Some(schema) if schema.eq_ignore_ascii_case(USER_SCHEMA) => "",
+        Some(schema) if schema.eq_ignore_ascii_case(ENTERPRISE_USER_SCHEMA) => "enterprise.",
+        Some(schema) => {
+            return Err(ScimError::invalid_path(format!(
+                "unknown schema {}",
+                schema
+            )))
+        }
+    };
+    let attribute = path.attribute.to_lowercase();
+    let sub = path.sub_attribute.as_deref().map(str::to_lowercase);
+    let key = match (attribute.as_str(), sub.as_deref()) {
+        ("emails" | "phonenumbers", None | Some("value")) => attribute,
+        ("manager", Some("value")) if !prefix.is_empty() => attribute,
+        (_, None) => attribute,
+        (_, Some(sub)) => format!("{}.{}", attribute, sub),
+    };
+    Ok(format!("{}{}", prefix, key))
+}
+
+/// The key an object member adds to `prefix`.
+fn member_key(prefix: &str, name: &str) -> String {
+    if name.eq_ignore_ascii_case(ENTERPRISE_USER_SCHEMA) {
+        return "enterprise".to_string();
+    }
+    if name.eq_ignore_ascii_case(USER_SCHEMA) {
+        return String::new();
+    }
+    match prefix {
+        "" => name.to_lowercase(),
+        prefix => format!("{}.{}", prefix, name.to_lowercase()),
+    }
+}
+
+/// The value of a multi-valued attribute kept as one LDAP value: the
+/// primary one, or else the first.
+fn primary(value: &Value) -> Value {
+    match value {
+        Value::Array(values) => values
+            .iter()
+            .find(|v| v.get("primary").and_then(Value::as_bool) == Some(true))
+            .or_else(|| values.first())
+            .map(primary)
+            .unwrap_or(Value::Null),
+        Value::Object(object) => object.get("value").cloned().unwrap_or(Value::Null),
+        other => other.clone(),
+    }
+}
+
+/// Splits `value`, given for `key`, into the single attributes it sets.
+fn flatten(key: &str, value: &Value, out: &mut Vec<(String, Value)>) {
+    match (key, value) {
+        ("emails" | "phonenumbers" | "enterprise.manager", value) => {
+            out.push((key.to_string(), primary(value)))
+        }
+        ("" | "name" | "enterprise", Value::Object(object)) => {
+            for (name, value) in object {
+                flatten(&member_key(key, name), value, out);
+            }
+        }
+        _ => out.push((key.to_string(), value.clone())),
+    }
+}
+
+/// Changes to a user, gathered from a request.
+#[derive(Debug, Default)]
+pub struct UserUpdate {
+    /// LDAP attributes and their new values; `None` clears one.
+    pub attributes: BTreeMap<&'static str, Option<String>>,
+    /// ID of the new manager, or `None` to clear it.
+    pub manager: Option<Option<String>>,
+    pub active: Option<bool>,
+    pub password: Option<String>,
+}
+
+impl UserUpdate {
+    /// Sets the attribute under `key`. Unknown keys fail when `strict`, as
+    /// for an explicit PATCH path, and are ignored otherwise.
+    fn set(
+        &mut self,
+        flavor: &dyn DirectoryFlavor,
+        key: &str,
+        value: &Value,
+        strict: bool,
+    ) -> ScimResult<()> {
+        let attribute = match key {
+            "active" | "password" | "enterprise.manager" => None,
+            key if IGNORED_USER_KEYS.contains(&key) => return Ok(()),
+            key => match user_attributes(flavor).into_iter().find(|(k, _)| k == key) {
+                Some((_, attribute)) => Some(attribute),
+                None if strict => {
+                    return Err(ScimError::invalid_path(format!(
+                        "unknown attribute {}",
+                        key
+                    )))
+                }
+                None => return Ok(()),
+            },
+        };
+        let text = match value {
+            Value::Null => None,
+            Value::String(s) if s.is_empty() => None,
+            Value::String(s) => Some(s.clone()),
+            Value::Bool(_) | Value::Number(_) => Some(value.to_string()),
+            _ => {
+                return Err(ScimError::invalid_value(format!(
+                    "{} takes a single value",
+                    key
+                )))
+            }
+        };
+        match (key, attribute) {
+            (_, Some(attribute)) => {
+                self.attributes.insert(attribute, text);
+            }
+            // Some clients send booleans as strings
+            ("active", _) => {
+                let active = text
+                    .as_deref()
+                    .and_then(|t| t.to_ascii_lowercase().parse().ok())
+                    .ok_or_else(|| ScimError::invalid_value("active must be true or false"))?;
+                self.active = Some(active);
+            }
+            ("password", _) => {
+                self.password =
+                    Some(text.ok_or_else(|| ScimError::invalid_value("password cannot be empty"))?)
+            }
+            _ => self.manager = Some(text),
+        }
+        Ok(())
+    }
+
+    fn set_all(
+        &mut self,
+        flavor: &dyn DirectoryFlavor,
+        key: &str,
+        value: &Value,
+        strict: bool,
+    ) -> ScimResult<()> {
+        let mut pairs = Vec::new();
+        flatten(key, value, &mut pairs);
+        for (key, value) in pairs {
+            self.set(flavor, &key, &value, strict)?;
+        }
+        Ok(())
+    }
+
+    /// Clears `key` and every attribute under it.
+    fn clear(&mut self, flavor: &dyn DirectoryFlavor, key: &str) -> ScimResult<()> {
+        let under = format!("{}.", key);
+        let mut found = false;
+        for (k, attribute) in user_attributes(flavor) {
+            if k == key || k.starts_with(&under) {
+                self.attributes.insert(attribute, None);
+                found = true;
+            }
+        }
+        if key == "enterprise.manager" || key == "enterprise" {
+            self.manager = Some(None);
+            found = true;
+        }
+        match key {
+            _ if found => Ok(()),
+            "active" | "password" | "username" => Err(ScimError::new(
+                Status::BadRequest,
+                Some("mutability"),
+                format!("{} cannot be removed", key),
+            )),
+            key if IGNORED_USER_KEYS.contains(&key) => Ok(()),
+            key => Err(ScimError::invalid_path(format!(
+                "unknown attribute {}",
+                key
+            ))),
+        }
+    }
+
+    /// A whole user, as sent to POST or PUT.
+    pub fn from_resource(flavor: &dyn DirectoryFlavor, body: &Value) -> ScimResult<UserUpdate> {
+        let mut update = UserUpdate::default();
+        update.set_all(flavor, "", body, false)?;
+        match update.attributes.get(flavor.login_attribute()) {
+            Some(Some(_)) => Ok(update),
+            _ => Err(ScimError::invalid_value("userName is required")),
+        }
+    }
+
+    /// A PUT: whatever the resource leaves out is cleared.
+    pub fn replacing(flavor: &dyn DirectoryFlavor, body: &Value) -> ScimResult<UserUpdate> {
+        let given = UserUpdate::from_resource(flavor, body)?;
+        let mut update = UserUpdate {
+            manager: Some(None),
+            ..Default::default()
+        };
+        for (_, attribute) in user_attributes(flavor) {
+            update.attributes.insert(attribute, None);
+        }
+        update.attributes.extend(given.attributes);
+        if given.manager.is_some() {
+            update.manager = given.manager;
+        }
+        update.active = given.active;
+        update.password = given.password;
+        Ok(update)
+    }
+
+    /// The operations of a PATCH request (RFC 7644 section 3.5.2).
+    pub fn from_patch(flavor: &dyn DirectoryFlavor, body: &Value) -> ScimResult<UserUpdate> {
+        let mut update = UserUpdate::default();
+        for op in patch_operations(body)? {
+            match (op.op, &op.path) {
+                (PatchOp::Add | PatchOp::Replace, None) => {
+                    update.set_all(flavor, "", &op.value, false)?
+                }
+                (PatchOp::Add | PatchOp::Replace, Some(path)) => {
+                    update.set_all(flavor, &user_key(path)?, &op.value, true)?
+                }
+                (PatchOp::Remove, Some(path)) => update.clear(flavor, &user_key(path)?)?,
+                (PatchOp::Remove, None) => {
+                    return Err(ScimError::new(
+                        Status::BadRequest,
+                        Some("noTarget"),
+                        "remove needs a path",
+                    ))
+                }
+            }
+        }
+        Ok(update)
+    }
+
+    /// Leaves out what already holds for `entry`.
+    pub fn retain_changes(&mut self, flavor: &dyn DirectoryFlavor, entry: &SearchEntry) {
+        self.attributes
+            .retain(|attribute, value| first(entry, attribute) != value.as_ref());
+        if self.active == Some(flavor.is_enabled(entry)) {
+            self.active = None;
+        }
+        if self.manager == Some(None) && first(entry, "manager").is_none() {
+            self.manager = None;
+        }
+    }
+
+    /// The attributes changed, for events. Enabling and disabling have
+    /// their own.
+    pub fn changed(&self) -> Vec<String> {
+        let mut changed: Vec<String> = self.attributes.keys().map(|a| a.to_string()).collect();
+        if self.manager.is_some() {
+            changed.push("manager".to_string());
+        }
+        if self.password.is_some() {
+            changed.push("password".to_string());
+        }
+        changed
+    }
+
+    /// The fields of a new user. The userPrincipalName and sAMAccountName
+    /// come from userName, and cn from the display name.
+    pub fn user_params(
+        &self,
+        flavor: &dyn DirectoryFlavor,
+        password: String,
+    ) -> ScimResult<UserParams> {
+        let get = |attribute: &str| {
+            self.attributes
+                .get(attribute)
+                .cloned()
+                .flatten()
+                .unwrap_or_default()
+        };
+        let login = get(flavor.login_attribute());
+        let sn = get("sn");
+        if sn.is_empty() {
+            return Err(ScimError::invalid_value("name.familyName is required"));
+        }
+        let given_name = get("givenName");
+        let display_name = match get("displayName") {
+            name if name.is_empty() => format!("{} {}", given_name, sn).trim().to_string(),
+            name => name,
+        };
+        let (upn, sam) = flavor.account_names(&login);
+        Ok(UserParams {
+            cn: display_name.clone(),
+            givenName: given_name,
+            sn,
+            displayName: display_name,
+            userPrincipalName: upn,
+            sAMAccountName: sam,
+            mail: get("mail"),
+            password,
+            create_mailbox: None,
+            template: None,
+            mailbox_password: None,
+            ou: None,
+            attributes: BTreeMap::new(),
+        })
+    }
+
+    /// Writes the attributes given into those of a new user, over the
+    /// flavor's defaults, and drops the empty ones.
+    pub fn merge_into(&self, attrs: &mut Vec<(String, Vec<String>)>) {
+        for (attribute, value) in &self.attributes {
+            let Some(value) = value else { continue };
+            match attrs
+                .iter_mut()
+                .find(|(a, _)| a.eq_ignore_ascii_case(attribute))
+            {
+                Some((_, values)) => *values = vec![value.clone()],
+                None => attrs.push((attribute.to_string(), vec![value.clone()])),
+            }
+        }
+        attrs.retain(|(_, values)| values.iter().any(|v| !v.is_empty()));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PatchOp {
+    Add,
+    Remove,
+    Replace,
+}
+
+struct PatchOperation {
+    op: PatchOp,
+    path: Option<Path>,
+    value: Value,
+}
+
+fn patch_operations(body: &Value) -> ScimResult<Vec<PatchOperation>> {
+    let schemas = body.get("schemas").and_then(Value::as_array);
+    if !schemas.is_some_and(|s| s.iter().any(|s| s.as_str() == Some(PATCH_OP_SCHEMA))) {
+        return Err(ScimError::invalid_syntax(format!(
+            "schemas must hold {}",
+            PATCH_OP_SCHEMA
+        )));
+    }
+    let operations = body
+        .get("Operations")
+        .and_then(Value::as_array)
+        .ok_or_else(|| ScimError::invalid_syntax("Operations is required"))?;
+    operations
+        .iter()
+        .map(|operation| {
+            let op = match operation
+                .get("op")
+                .and_then(Value::as_str)
+                .map(str::to_ascii_lowercase)
+                .as_deref()
+            {
+                Some("add") => PatchOp::Add,
+                Some("remove") => PatchOp::Remove,
+                Some("replace") => PatchOp::Replace,
+                _ => {
+                    return Err(ScimError::invalid_syntax(
+                        "op must be add, remove or replace",
+                    ))
+                }
+            };
+            let path = match operation.get("path").and_then(Value::as_str) {
+                Some(path) => Some(scim_filter::parse_path(path).map_err(ScimError::invalid_path)?),
+                None => None,
+            };
+            let value = operation.get("value").cloned().unwrap_or(Value::Null);
+            Ok(PatchOperation { op, path, value })
+        })
+        .collect()
+}
+
+/// The LDAP filter for a user filter.
+fn user_filter(directory: &Directory, filter: &Filter) -> ScimResult<String> {
+    let flavor = directory.flavor.as_ref();
+    let attributes = user_attributes(flavor);
+    let leaf =
+        |attribute: &str, comparison: Option<(CompareOp, &Value)>| -> Result<String, String> {
+            let path = scim_filter::parse_path(attribute)?;
+            let key = user_key(&path).map_err(|e| e.detail)?;
+            if key == "id" {
+                return match comparison {
+                    Some((CompareOp::Eq, value)) => Ok(flavor
+                        .guid_filter(&scim_filter::value_text(value))
+                        .unwrap_or_else(|| NOTHING.to_string())),
+                    _ => Err("id can only be compared with eq".to_string()),
+                };
+            }
+            let ldap_attribute = attributes
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, a)| *a)
+                .ok_or_else(|| format!("cannot filter on {}", attribute))?;
+            Ok(match comparison {
+                None => format!("({}=*)", ldap_attribute),
+                Some((op, value)) => {
+                    scim_filter::comparison(ldap_attribute, op, &scim_filter::value_text(value))
+                }
+            })
+        };
+    filter.to_ldap(&leaf).map_err(ScimError::invalid_filter)
+}
+
+pub fn parse_filter(filter: Option<&str>) -> ScimResult<Option<Filter>> {
+    match filter {
+        Some(filter) if !filter.trim().is_empty() => scim_filter::parse(filter)
+            .map(Some)
+            .map_err(ScimError::invalid_filter),
+        _ => Ok(None),
+    }
+}
+
+/// Every user matching the filter.
+pub async fn search_users(
+    ldap: &mut Connection,
+    directory: &Directory,
+    filter: Option<&Filter>,
+) -> ScimResult<Vec<SearchEntry>> {
+    let flavor = directory.flavor.as_ref();
+    let ldap_filter = match filter {
+        Some(filter) => format!(
+            "(&{}{})",
+            flavor.user_filter(),
+            user_filter(directory, filter)?
+        ),
+        None => flavor.user_filter().to_string(),
+    };
+    Ok(ldap
+        .search_paged(
+            &directory.config.base_dn,
+            Scope::Subtree,
+            &ldap_filter,
+            USER_ATTRIBUTES.to_vec(),
+            vec![],
+        )
+        .await?)
+}
+
+/// The entry of the user or group with the given ID.
+async fn find(
+    ldap: &mut Connection,
+    directory: &Directory,
+    base_dn: &str,
+    class_filter: &str,
+    resource: &str,
+    id: &str,
+) -> ScimResult<SearchEntry> {
+    let guid_filter = directory
+        .flavor
+        .guid_filter(id)
+        .ok_or_else(|| ScimError::not_found(resource, id))?;
+    let filter = format!("(&{}{})", class_filter, guid_filter);
+    let (rs, _res) = ldap_timed(
+        "search",
+        ldap.op(Operation::Search).search(
+            base_dn,
+            Scope::Subtree,
+            &filter,
+            USER_ATTRIBUTES.to_vec(),
+        ),
+    )
+    .instrument(info_span!("ldap.search", base = base_dn))
+    .await?
+    .success()?;
+    rs.into_iter()
+        .next()
+        .map(SearchEntry::construct)
+        .ok_or_else(|| ScimError::not_found(resource, id))
+}
+
+pub async fn find_user(
+    ldap: &mut Connection,
+    directory: &Directory,
+    id: &str,
+) -> ScimResult<SearchEntry> {
+    find(
+        ldap,
+        directory,
+        &directory.config.base_dn,
+        directory.flavor.user_filter(),
+        "User",
+        id,
+    )
+    .await
+}
+
+/// The container SCIM manages groups in. Groups elsewhere, such as the
+/// built-in administrative ones, are out of its reach.
+fn groups_dn(directory: &Directory) -> ScimResult<&str> {
+    directory.config.groups_dn.as_deref().ok_or_else(|| {
+        ScimError::new(
+            Status::Forbidden,
+            None,
+            format!("groups_dn is not set for directory {}", directory.name),
+        )
+    })
+}
+
+pub async fn find_group(
+    ldap: &mut Connection,
+    directory: &Directory,
+    id: &str,
+) -> ScimResult<SearchEntry> {
+    find(
+        ldap,
+        directory,
+        groups_dn(directory)?,
+        directory.flavor.group_filter(),
+        "Group",
+        id,
+    )
+    .await
+}
+
+/// The login a user entry is found by elsewhere in the API.
+pub fn login(entry: &SearchEntry, flavor: &dyn DirectoryFlavor) -> String {
+    first(entry, flavor.login_attribute())
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Writes the attributes and manager of `update` to the user and audits
+/// them. Passwords and `active` are left to the caller.
+pub async fn modify_user(
+    ldap: &mut Connection,
+    directory: &Directory,
+    auditor: &Auditor,
+    entry: &SearchEntry,
+    update: &UserUpdate,
+) -> ScimResult<()> {
+    let mut mods: Vec<Mod<String>> = update
+        .attributes
+        .iter()
+        .map(|(attribute, value)| {
+            Mod::Replace(attribute.to_string(), value.iter().cloned().collect())
+        })
+        .collect();
+    match &update.manager {
+        Some(Some(id)) => {
+            let manager = find_user(ldap, directory, id).await.map_err(|_| {
+                ScimError::invalid_value(format!("no user with id {} to be the manager", id))
+            })?;
+            if first(entry, "manager") != Some(&manager.dn) {
+                mods.push(Mod::Replace(
+                    "manager".to_string(),
+                    HashSet::from([manager.dn]),
+                ));
+            }
+        }
+        Some(None) => mods.push(Mod::Replace("manager".to_string(), HashSet::new())),
+        None => {}
+    }
+    if mods.is_empty() {
+        return Ok(());
+    }
+    let changes = mods
+        .iter()
+        .filter_map(|m| match m {
+            Mod::Replace(attribute, values) => Some(AuditChange::new(
+                attribute,
+                "replace",
+                values.iter().cloned().collect(),
+            )),
+            _ => None,
+        })
+        .collect();
+    let res = modify(ldap, &entry.dn, mods)
+        .await
+        .and_then(|res| res.success())
+        .map(|_| ());
+    auditor
+        .record(
+            "user.update",
+            Some(entry.dn.clone()),
+            None,
+            changes,
+            result_code(&res),
+        )
+        .await;
+    res?;
+    Ok(())
+}
+
+pub async fn list_users(
+    ldap: &mut Connection,
+    directory: &Directory,
+    base: &str,
+    query: &ListQuery,
+) -> ScimResult<Value> {
+    let filter = parse_filter(query.filter.as_deref())?;
+    let users = search_users(ldap, directory, filter.as_ref()).await?;
+    let total = users.len();
+    let (start, users) = page(query, users);
+    let managers = manager_ids(ldap, directory.flavor.as_ref(), &users).await;
+    let resources = users
+        .iter()
+        .map(|user| user_resource(user, directory, base, &managers))
+        .collect();
+    Ok(list_response(total, start, resources))
+}
+
+pub async fn get_user(
+    ldap: &mut Connection,
+    directory: &Directory,
+    base: &str,
+    id: &str,
+) -> ScimResult<Value> {
+    let entry = find_user(ldap, directory, id).await?;
+    let managers = manager_ids(
+        ldap,
+        directory.flavor.as_ref(),
+        std::slice::from_ref(&entry),
+    )
+    .await;
+    Ok(user_resource(&entry, directory, base, &managers))
+}
+
+/// Creates a user from a SCIM User with the default template, which does
+/// not provision a mailbox unless the template says so. Without a
+/// password, a random one is set.
+pub async fn create_user(
+    ldap: &mut Connection,
+    directory: &Directory,
+    state: &ServerState,
+    auditor: &Auditor,
+    base: &str,
+    body: &Value,
+) -> ScimResult<Value> {
+    let flavor = directory.flavor.as_ref();
+    let mut update = UserUpdate::from_resource(flavor, body)?;
+    let password = match &update.password {
+        Some(password) => {
+            state
+                .config
+                .password_policy
+                .check(password)
+                .map_err(ScimError::invalid_value)?;
+            password.clone()
+        }
+        None => generate_password(),
+    };
+    let user = update.user_params(flavor, password)?;
+    update
+        .attributes
+        .insert("displayName", Some(user.displayName.clone()));
+    let template = state
+        .templates
+        .get(None)
+        .ok_or_else(|| ScimError::new(Status::InternalServerError, None, "no default template"))?;
+    let dn = user.dn(directory).map_err(ScimError::invalid_value)?;
+    let mut attrs = user
+        .ldap_attributes(directory)
+        .map_err(ScimError::invalid_value)?;
+    update.merge_into(&mut attrs);
+    let changes = user.audit_changes(flavor, &attrs);
+    let login = flavor.login_name(&user).to_string();
+
+    let account = match UserAccount::create_new_user(ldap, directory, template, user, attrs).await {
+        Ok(account) => account,
+        Err(e) => {
+            auditor
+                .record("user.create", Some(dn), None, changes, e.result_code())
+                .await;
+            return Err(e.into());
+        }
+    };
+    let guid = UserAccount::get_guid(ldap, flavor, &dn).await;
+    auditor
+        .record(
+            "user.create",
+            Some(dn.clone()),
+            guid.clone(),
+            changes,
+            Some(0),
+        )
+        .await;
+    if let Some(mailbox) = &account.mailbox {
+        auditor.record_mailbox(&dn, mailbox).await;
+    }
+    let event = Event::new(
+        EventKind::UserCreated,
+        &directory.name,
+        auditor,
+        &dn,
+        Some(&login),
+        account_data(&account),
+    );
+    state.events.publish_write(ldap, event).await;
+
+    let id = guid.ok_or_else(|| {
+        ScimError::new(
+            Status::InternalServerError,
+            None,
+            "cannot read the new user",
+        )
+    })?;
+    let entry = find_user(ldap, directory, &id).await?;
+    let rest = UserUpdate {
+        manager: update.manager,
+        ..Default::default()
+    };
+    modify_user(ldap, directory, auditor, &entry, &rest).await?;
+    if update.active == Some(false) {
+        let res = flavor.set_enabled(ldap, &dn, false).await;
+        auditor
+            .record(
+                "user.disable",
+                Some(dn.clone()),
+                None,
+                vec![],
+                result_code(&res),
+            )
+            .await;
+        res?;
+    }
+    get_user(ldap, directory, base, &id).await
+}
+
+/// A group entry as a SCIM Group, with its members when they were read.
+pub fn group_resource(
+    entry: &SearchEntry,
+    flavor: &dyn DirectoryFlavor,
+    base: &str,
+    members: Option<Vec<Value>>,
+) -> Value {
+    let id = flavor.guid(entry).unwrap_or_default();
+    let mut group = json!({
+        "schemas": [GROUP_SCHEMA],
+        "id": id,
+        "displayName": first(entry, "cn"),
+        "meta": meta(entry, "Group", format!("{}/Groups/{}", base, id)),
+    });
+    if let Some(members) = members {
+        group["members"] = json!(members);
+    }
+    group
+}
+
+/// The direct members of the group at `dn`, found through `memberOf`. On
+/// OpenLDAP this needs the memberof overlay.
+pub async fn group_members(
+    ldap: &mut Connection,
+    directory: &Directory,
+    base: &str,
+    dn: &str,
+) -> ScimResult<Vec<Value>> {
+    let flavor = directory.flavor.as_ref();
+    let filter = format!("(memberOf={})", ldap3::ldap_escape(dn));
+    let mut attributes = MEMBER_ATTRIBUTES.to_vec();
+    attributes.push(flavor.guid_attribute());
+    let entries = ldap
+        .search_paged(
+            &directory.config.base_dn,
+            Scope::Subtree,
+            &filter,
+            attributes,
+            vec![],
+        )
+        .await?;
+    Ok(entries
+        .iter()
+        .filter_map(|entry| {
+            let id = flavor.guid(entry)?;
+            let is_group = entry.attrs.get("objectClass").is_some_and(|classes| {
+                classes.iter().any(|c| {
+                    c.eq_ignore_ascii_case("group") || c.eq_ignore_ascii_case("groupOfNames")
+                })
+            });
+            let (kind, path) = match is_group {
+                true => ("Group", "Groups"),
+                false => ("User", "Users"),
+            };
+            Some(json!({
+                "value": id,
+                "display": first(entry, "displayName").or(first(entry, "cn")),
+                "type": kind,
+                "$ref": format!("{}/{}/{}", base, path, id),
+            }))
+        })
+        .collect())
+}
+
+/// The DNs of the users or groups with the given IDs. Groups have to be
+/// under `groups_dn` like those they are members of.
+pub async fn member_dns(
+    ldap: &mut Connection,
+    directory: &Directory,
+    ids: &[String],
+) -> ScimResult<Vec<String>> {
+    let mut dns = Vec::new();
+    for id in ids {
+        let user = find_user(ldap, directory, id).await;
+        let entry = match user {
+            Err(e) if e.status == Status::NotFound => find_group(ldap, directory, id).await,
+            user => user,
+        }
+        .map_err(|e| match e.status {
+            status if status == Status::NotFound || status == Status::Forbidden => {
+                ScimError::invalid_value(format!("no user or group with id {}", id))
+            }
+            _ => e,
+        })?;
+        dns.push(entry.dn);
+    }
+    Ok(dns)
+}
+
+/// The IDs in a `members` value: objects with a `value`, or bare strings.
+fn member_ids(value: &Value) -> ScimResult<Vec<String>> {
+    let members = match value {
+        Value::Array(members) => members.as_slice(),
+        Value::Null => &[],
+        _ => return Err(ScimError::invalid_value("members must be a list")),
+    };
+    members
+        .iter()
+        .map(|member| match member {
+            Value::String(id) => Ok(id.clone()),
+            member => member
+                .get("value")
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .ok_or_else(|| ScimError::invalid_value("each member needs a value")),
+        })
+        .collect()
+}
+
+/// One change to a group, with members still given by ID.
+#[derive(Debug)]
+pub enum GroupChange {
+    Rename(String),
+    Add(Vec<String>),
+    Remove(Vec<String>),
+    Replace(Vec<String>),
+}
+
+impl GroupChange {
+    /// The LDAP modification it comes to, as in LDIF.
+    fn operation(&self) -> &'static str {
+        match self {
+            GroupChange::Add(_) => "add",
+            GroupChange::Remove(_) => "delete",
+            GroupChange::Rename(_) | GroupChange::Replace(_) => "replace",
+        }
+    }
+
+    /// The event data of a membership change, matching the LDIF import's.
+    fn membership_data(&self, dns: &[String]) -> Option<Value> {
+        if let GroupChange::Rename(_) = self {
+            return None;
+        }
+        Some(json!({
+            "changes": [{ "attribute": "member", "operation": self.operation(), "values": dns }],
+        }))
+    }
+
+    fn ids(&self) -> &[String] {
+        match self {
+            GroupChange::Rename(_) => &[],
+            GroupChange::Add(ids) | GroupChange::Remove(ids) | GroupChange::Replace(ids) => ids,
+        }
+    }
+}
+
+const IGNORED_GROUP_KEYS: [&str; 4] = ["schemas", "id", "meta", "externalid"];
+
+fn display_name(value: &Value) -> ScimResult<String> {
+    match value.as_str() {
+        Some(name) if !name.trim().is_empty() => Ok(name.trim().to_string()),
+        _ => Err(ScimError::invalid_value(
+            "displayName must be a non-empty string",
+        )),
+    }
+}
+
+/// A whole group, as sent to POST or PUT: its name and member IDs.
+pub fn group_from_resource(body: &Value) -> ScimResult<(String, Vec<String>)> {
+    let name = display_name(body.get("displayName").unwrap_or(&Value::Null))?;
+    let members = member_ids(body.get("members").unwrap_or(&Value::Null))?;
+    Ok((name, members))
+}
+
+/// The operations of a PATCH request on a group, in order.
+pub fn group_changes(body: &Value) -> ScimResult<Vec<GroupChange>> {
+    let mut changes = Vec::new();
+    for op in patch_operations(body)? {
+        let attribute = op.path.as_ref().map(|p| p.attribute.to_lowercase());
+        match (op.op, attribute.as_deref(), &op.path) {
+            (PatchOp::Add | PatchOp::Replace, None, _) => {
+                let object = op
+                    .value
+                    .as_object()
+                    .ok_or_else(|| ScimError::invalid_value("value must be an object"))?;
+                for (name, value) in object {
+                    match name.to_lowercase().as_str() {
+                        "displayname" => changes.push(GroupChange::Rename(display_name(value)?)),
+                        "members" if op.op == PatchOp::Add => {
+                            changes.push(GroupChange::Add(member_ids(value)?))
+                        }
+                        "members" => changes.push(GroupChange::Replace(member_ids(value)?)),
+                        key if IGNORED_GROUP_KEYS.contains(&key) => {}
+                        key => {
+                            return Err(ScimError::invalid_path(format!(
+                                "unknown attribute {}",
+                                key
+                            )))
+                        }
+                    }
+                }
+            }
+            (PatchOp::Add | PatchOp::Replace, Some("displayname"), _) => {
+                changes.push(GroupChange::Rename(display_name(&op.value)?))
+            }
+            (PatchOp::Add, Some("members"), Some(Path { filter: None, .. })) => {
+                changes.push(GroupChange::Add(member_ids(&op.value)?))
+            }
+            (PatchOp::Replace, Some("members"), Some(Path { filter: None, .. })) => {
+                changes.push(GroupChange::Replace(member_ids(&op.value)?))
+            }
+            (PatchOp::Remove, Some("members"), Some(Path { filter: None, .. })) => match op.value {
+                Value::Null => changes.push(GroupChange::Replace(vec![])),
+                ref value => changes.push(GroupChange::Remove(member_ids(value)?)),
+            },
+            (
+                PatchOp::Remove,
+                Some("members"),
+                Some(Path {
+                    filter: Some(filter),
+                    ..
+                }),
+            ) => {
+                let ids = filter.eq_values("value").ok_or_else(|| {
+                    ScimError::invalid_filter("members can only be picked by value eq")
+                })?;
+                changes.push(GroupChange::Remove(ids));
+            }
+            (_, Some(key), _) if IGNORED_GROUP_KEYS.contains(&key) => {}
+            (PatchOp::Remove, Some("displayname"), _) => {
+                return Err(ScimError::new(
+                    Status::BadRequest,
+                    Some("mutability"),
+                    "displayName cannot be removed",
+                ))
+            }
+            (PatchOp::Remove, None, _) => {
+                return Err(ScimError::new(
+                    Status::BadRequest,
+                    Some("noTarget"),
+                    "remove needs a path",
+                ))
+            }
+            (_, Some(key), _) => {
+                return Err(ScimError::invalid_path(format!(
+                    "unknown attribute {}",
+                    key
+                )))
+            }
+        }
+    }
+    Ok(changes)
+}
+
+/// Applies one group change, with its members as `dns`. A rename moves the
+/// group, so `dn` is updated.
+async fn apply_group_change(
+    ldap: &mut Connection,
+    dn: &mut String,
+    change: &GroupChange,
+    dns: Vec<String>,
+) -> Result<(), LdapError> {
+    match change {
+        GroupChange::Rename(name) => {
+            let rdn_attribute = dn.split('=').next().unwrap_or("cn").to_string();
+            let rdn = format!("{}={}", rdn_attribute, ldap3::dn_escape(name));
+            let current_rdn = dn.split(',').next().unwrap_or_default();
+            if current_rdn.eq_ignore_ascii_case(&rdn) {
+                return Ok(());
+            }
+            let parent = parent_dn(dn).map(str::to_string);
+            ldap_timed(
+                "modifydn",
+                ldap.op(Operation::Modify).modifydn(dn, &rdn, true, None),
+            )
+            .instrument(info_span!("ldap.modifydn", dn = %dn))
+            .await?
+            .success()?;
+            *dn = match parent {
+                Some(parent) => format!("{},{}", rdn, parent),
+                None => rdn,
+            };
+        }
+        GroupChange::Replace(_) => {
+            let mods = vec![Mod::Replace(
+                "member".to_string(),
+                dns.into_iter().collect(),
+            )];
+            modify(ldap, dn, mods).await?.success()?;
+        }
+        GroupChange::Add(_) => {
+            // One at a time, so that existing members are skipped
+            for member in dns {
+                let mods = vec![Mod::Add("member".to_string(), HashSet::from([member]))];
+                let res = modify(ldap, dn, mods).await?;
+                // attributeOrValueExists, or entryAlreadyExists on AD
+                if !matches!(res.rc, 20 | 68) {
+                    res.success()?;
+                }
+            }
+        }
+        GroupChange::Remove(_) => {
+            for member in dns {
+                let mods = vec![Mod::Delete("member".to_string(), HashSet::from([member]))];
+                let res = modify(ldap, dn, mods).await?;
+                // noSuchAttribute, or unwillingToPerform on AD for a
+                // non-member
+                if !matches!(res.rc, 16 | 53) {
+                    res.success()?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn modify(
+    ldap: &mut Connection,
+    dn: &str,
+    mods: Vec<Mod<String>>,
+) -> Result<LdapResult, LdapError> {
+    ldap_timed("modify", ldap.op(Operation::Modify).modify(dn, mods))
+        .instrument(info_span!("ldap.modify", dn = dn))
+        .await
+}
+
+/// The LDAP filter for a group filter. Members are matched by DN, looked up
+/// beforehand into `members`.
+fn group_filter(
+    directory: &Directory,
+    filter: &Filter,
+    members: &HashMap<String, String>,
+) -> ScimResult<String> {
+    let flavor = directory.flavor.as_ref();
+    let leaf =
+        |attribute: &str, comparison: Option<(CompareOp, &Value)>| -> Result<String, String> {
+            let path = scim_filter::parse_path(attribute)?;
+            let key = match &path.sub_attribute {
+                Some(sub) => format!("{}.{}", path.attribute, sub).to_lowercase(),
+                None => path.attribute.to_lowercase(),
+            };
+            match (key.as_str(), comparison) {
+                ("displayname", None) => Ok("(cn=*)".to_string()),
+                ("displayname", Some((op, value))) => Ok(scim_filter::comparison(
+                    "cn",
+                    op,
+                    &scim_filter::value_text(value),
+                )),
+                ("id", Some((CompareOp::Eq, value))) => Ok(flavor
+                    .guid_filter(&scim_filter::value_text(value))
+                    .unwrap_or_else(|| NOTHING.to_string())),
+                ("members" | "members.value", Some((CompareOp::Eq, value))) => {
+                    Ok(match members.get(&scim_filter::value_text(value)) {
+                        Some(dn) => format!("(member={})", ldap3::ldap_escape(dn)),
+                        None => NOTHING.to_string(),
+                    })
+                }
+                _ => Err(format!("cannot filter on {}", attribute)),
+            }
+        };
+    filter.to_ldap(&leaf).map_err(ScimError::invalid_filter)
+}
+
+/// The member IDs a group filter compares with.
+fn filter_member_ids(filter: &Filter, ids: &mut Vec<String>) {
+    match filter {
+        Filter::And(a, b) | Filter::Or(a, b) => {
+            filter_member_ids(a, ids);
+            filter_member_ids(b, ids);
+        }
+        Filter::Not(f) => filter_member_ids(f, ids),
+        Filter::Compare(attribute, CompareOp::Eq, value)
+            if attribute.eq_ignore_ascii_case("members")
+                || attribute.eq_ignore_ascii_case("members.value") =>
+        {
+            ids.push(scim_filter::value_text(value))
+        }
+        _ => {}
+    }
+}
+
+/// Every group matching the filter.
+pub async fn search_groups(
+    ldap: &mut Connection,
+    directory: &Directory,
+    filter: Option<&Filter>,
+) -> ScimResult<Vec<SearchEntry>> {
+    let flavor = directory.flavor.as_ref();
+    let ldap_filter = match filter {
+        Some(filter) => {
+            let mut ids = Vec::new();
+            filter_member_ids(filter, &mut ids);
+            let mut members = HashMap::new();
+            for id in ids {
+                // Unknown members match no group
+                if let Ok(dns) = member_dns(ldap, directory, std::slice::from_ref(&id)).await {
+                    members.insert(id, dns.into_iter().next().unwrap_or_default());
+                }
+            }
+            format!(
+                "(&{}{})",
+                flavor.group_filter(),
+                group_filter(directory, filter, &members)?
+            )
+        }
+        None => flavor.group_filter().to_string(),
+    };
+    Ok(ldap
+        .search_paged(
+            groups_dn(directory)?,
+            Scope::Subtree,
+            &ldap_filter,
+            USER_ATTRIBUTES.to_vec(),
+            vec![],
+        )
+        .await?)
+}
+
+async fn group_with_members(
+    ldap: &mut Connection,
+    directory: &Directory,
+    base: &str,
+    entry: &SearchEntry,
+    with_members: bool,
+) -> ScimResult<Value> {
+    let members = match with_members {
+        true => Some(group_members(ldap, directory, base, &entry.dn).await?),
+        false => None,
+    };
+    Ok(group_resource(
+        entry,
+        directory.flavor.as_ref(),
+        base,
+        members,
+    ))
+}
+
+pub async fn list_groups(
+    ldap: &mut Connection,
+    directory: &Directory,
+    base: &str,
+    query: &ListQuery,
+) -> ScimResult<Value> {
+    let filter = parse_filter(query.filter.as_deref())?;
+    let groups = search_groups(ldap, directory, filter.as_ref()).await?;
+    let total = groups.len();
+    let (start, groups) = page(query, groups);
+    let mut resources = Vec::with_capacity(groups.len());
+    for group in &groups {
+        let with_members = !query.excludes("members");
+        resources.push(group_with_members(ldap, directory, base, group, with_members).await?);
+    }
+    Ok(list_response(total, start, resources))
+}
+
+pub async fn get_group(
+    ldap: &mut Connection,
+    directory: &Directory,
+    base: &str,
+    id: &str,
+    with_members: bool,
+) -> ScimResult<Value> {
+    let entry = find_group(ldap, directory, id).await?;
+    group_with_members(ldap, directory, base, &entry, with_members).await
+}
+
+/// Creates a group directly under the directory's `groups_dn`.
+pub async fn create_group(
+    ldap: &mut Connection,
+    directory: &Directory,
+    state: &ServerState,
+    auditor: &Auditor,
+    base: &str,
+    body: &Value,
+) -> ScimResult<Value> {
+    let flavor = directory.flavor.as_ref();
+    let container = groups_dn(directory)?;
+    let (name, ids) = group_from_resource(body)?;
+    let members = member_dns(ldap, directory, &ids).await?;
+    let dn = flavor.new_group_dn(&name, container);
+    let attrs = flavor
+        .new_group_attributes(&name, &members)
+        .map_err(ScimError::invalid_value)?;
+    let changes = attrs
+        .iter()
+        .map(|(attribute, values)| AuditChange::new(attribute, "add", values.clone()))
+        .collect();
+    let attrs: Vec<(String, HashSet<String>)> = attrs
+        .into_iter()
+        .map(|(attribute, values)| (attribute, values.into_iter().collect()))
+        .collect();
+    let res = ldap_timed("add", ldap.op(Operation::Add).add(&dn, attrs))
+        .instrument(info_span!("ldap.add", dn = %dn))
+        .await
+        .and_then(|res| res.success())
+        .map(|_| ());
+    let guid = match res {
+        Ok(()) => UserAccount::get_guid(ldap, flavor, &dn).await,
+        Err(_) => None,
+    };
+    auditor
+        .record(
+            "group.create",
+            Some(dn.clone()),
+            guid.clone(),
+            changes,
+            result_code(&res),
+        )
+        .await;
+    res?;
+
+    let added = GroupChange::Add(ids);
+    if let Some(data) = added
+        .membership_data(&members)
+        .filter(|_| !members.is_empty())
+    {
+        let event = Event::new(
+            EventKind::GroupMembershipChanged,
+            &directory.name,
+            auditor,
+            &dn,
+            None,
+            data,
+        );
+        state.events.publish_write(ldap, event).await;
+    }
+    let id = guid.ok_or_else(|| {
+        ScimError::new(
+            Status::InternalServerError,
+            None,
+            "cannot read the new group",
+        )
+    })?;
+    get_group(ldap, directory, base, &id, true).await
+}
+
+/// Applies the changes to the group with the given ID in order, stopping at
+/// the first that fails.
+pub async fn update_group(
+    ldap: &mut Connection,
+    directory: &Directory,
+    state: &ServerState,
+    auditor: &Auditor,
+    base: &str,
+    id: &str,
+    changes: Vec<GroupChange>,
+) -> ScimResult<Value> {
+    let entry = find_group(ldap, directory, id).await?;
+    let mut dn = entry.dn;
+    for change in &changes {
+        let members = member_dns(ldap, directory, change.ids()).await?;
+        let audit = match change {
+            GroupChange::Rename(name) => AuditChange::new("cn", "replace", vec![name.clone()]),
+            change => AuditChange::new("member", change.operation(), members.clone()),
+        };
+        let target = dn.clone();
+        let res = apply_group_change(ldap, &mut dn, change, members.clone()).await;
+        auditor
+            .record(
+                "group.update",
+                Some(target),
+                Some(id.to_string()),
+                vec![audit],
+                result_code(&res),
+            )
+            .await;
+        res?;
+        if let Some(data) = change.membership_data(&members) {
+            let event = Event::new(
+                EventKind::GroupMembershipChanged,
+                &directory.name,
+                auditor,
+                &dn,
+                None,
+                data,
+            );
+            state.events.publish_write(ldap, event).await;
+        }
+    }
+    get_group(ldap, directory, base, id, true).await
+}
+
+/// The changes a PUT makes: the name, and the whole member list.
+pub fn group_replacement(body: &Value) -> ScimResult<Vec<GroupChange>> {
+    let (name, members) = group_from_resource(body)?;
+    Ok(vec![
+        GroupChange::Rename(name),
+        GroupChange::Replace(members),
+    ])
+}
+
+pub async fn delete_group(
+    ldap: &mut Connection,
+    directory: &Directory,
+    auditor: &Auditor,
+    id: &str,
+) -> ScimResult<()> {
+    let entry = find_group(ldap, directory, id).await?;
+    let res = ldap_timed("delete", ldap.op(Operation::Delete).delete(&entry.dn))
+        .instrument(info_span!("ldap.delete", dn = %entry.dn))
+        .await
+        .and_then(|res| res.success())
+        .map(|_| ());
+    auditor
+        .record(
+            "group.delete",
+            Some(entry.dn),
+            Some(id.to_string()),
+            vec![],
+            result_code(&res),
+        )
+        .await;
+    Ok(res?)
+}
+
+pub fn service_provider_config(base: &str) -> Value {
+    json!({
+        "schemas": [SERVICE_PROVIDER_CONFIG_SCHEMA],
+        "patch": { "supported": true },
+        "bulk": { "supported": false, "maxOperations": 0, "maxPayloadSize": 0 },
+        "filter": { "supported": true, "maxResults": MAX_RESULTS },
+        "changePassword": { "supported": true },
+        "sort": { "supported": false },
+        "etag": { "supported": false },
+        "authenticationSchemes": [{
+            "type": "oauthbearertoken",
+            "name": "Bearer token",
+            "description": "The token set as scim.bearer_token, sent as \
+                `Authorization: Bearer <token>`. Admins authenticated by the proxy \
+                in front of the service need none.",
+            "specUri": "https://www.rfc-editor.org/rfc/rfc6750",
+            "primary": true,
+        }],
+        "meta": {
+            "resourceType": "ServiceProviderConfig",
+            "location": format!("{}/ServiceProviderConfig", base),
+        },
+    })
+}
+
+pub fn resource_types(base: &str) -> Vec<Value> {
+    vec![
+        json!({
+            "schemas": [RESOURCE_TYPE_SCHEMA],
+            "id": "User",
+            "name": "User",
+            "endpoint": "/Users",
+            "schema": USER_SCHEMA,
+            "schemaExtensions": [{ "schema": ENTERPRISE_USER_SCHEMA, "required": false }],
+            "meta": { "resourceType": "ResourceType", "location": format!("{}/ResourceTypes/User", base) },
+        }),
+        json!({
+            "schemas": [RESOURCE_TYPE_SCHEMA],
+            "id": "Group",
+            "name": "Group",
+            "endpoint": "/Groups",
+            "schema": GROUP_SCHEMA,
+            "meta": { "resourceType": "ResourceType", "location": format!("{}/ResourceTypes/Group", base) },
+        }),
+    ]
+}
+
+/// An attribute definition of a schema (RFC 7643 section 7).
+fn attribute(
+    name: &str,
+    kind: &str,
+    multi_valued: bool,
+    required: bool,
+    mutability: &str,
+) -> Value {
+    json!({
+        "name": name,
+        "type": kind,
+        "multiValued": multi_valued,
+        "required": required,
+        "caseExact": false,
+        "mutability": mutability,
+        "returned": "default",
+        "uniqueness": if name == "userName" { "server" } else { "none" },
+    })
+}
+
+fn with_sub_attributes(mut attribute: Value, sub_attributes: Vec<Value>) -> Value {
+    attribute["subAttributes"] = json!(sub_attributes);
+    attribute
+}
+
+pub fn schemas(base: &str, flavor: &dyn DirectoryFlavor) -> Vec<Value> {
+    let string = |name: &str| attribute(name, "string", false, false, "readWrite");
+    let values = |name: &str| {
+        with_sub_attributes(
+            attribute(name, "complex", true, false, "readWrite"),
+            vec![
+                string("value"),
+                string("type"),
+                attribute("primary", "boolean", false, false, "readWrite"),
+            ],
+        )
+    };
+    let user = vec![
+        attribute("userName", "string", false, true, "readWrite"),
+        with_sub_attributes(
+            attribute("name", "complex", false, false, "readWrite"),
+            vec![
+                string("givenName"),
+                attribute("familyName", "string", false, true, "readWrite"),
+                attribute("formatted", "string", false, false, "readOnly"),
+            ],
+        ),
+        string("displayName"),
+        string("title"),
+        values("emails"),
+        values("phoneNumbers"),
+        attribute("active", "boolean", false, false, "readWrite"),
+        {
+            let mut password = attribute("password", "string", false, false, "writeOnly");
+            password["returned"] = json!("never");
+            password
+        },
+    ];
+    let mut enterprise: Vec<Value> = flavor
+        .enterprise_attributes()
+        .iter()
+        .map(|(name, _)| string(name))
+        .collect();
+    enterprise.push(with_sub_attributes(
+        attribute("manager", "complex", false, false, "readWrite"),
+        vec![
+            attribute("value", "string", false, false, "readWrite"),
+            attribute("$ref", "reference", false, false, "readOnly"),
+        ],
+    ));
+    let group = vec![
+        attribute("displayName", "string", false, true, "readWrite"),
+        with_sub_attributes(
+            attribute("members", "complex", true, false, "readWrite"),
+            vec![
+                attribute("value", "string", false, false, "immutable"),
+                attribute("display", "string", false, false, "readOnly"),
+                attribute("type", "string", false, false, "immutable"),
+                attribute("$ref", "reference", false, false, "immutable"),
+            ],
+        ),
+    ];
+
+    let schema = |id: &str, name: &str, attributes: Vec<Value>| {
+        json!({
+            "schemas": [SCHEMA_SCHEMA],
+            "id": id,
+            "name": name,
+            "attributes": attributes,
+            "meta": { "resourceType": "Schema", "location": format!("{}/Schemas/{}", base, id) },
+        })
+    };
+    vec![
+        schema(USER_SCHEMA, "User", user),
+        schema(ENTERPRISE_USER_SCHEMA, "EnterpriseUser", enterprise),
+        schema(GROUP_SCHEMA, "Group", group),
+    ]
+}
+
+/// A fixed list of discovery resources, as a ListResponse.
+pub fn discovery_list(resources: Vec<Value>) -> Value {
+    list_response(resources.len(), 1, resources)
+}
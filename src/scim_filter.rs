@@ -0,0 +1,319 @@
+use serde_json::Value;
+
+/// A SCIM filter expression (RFC 7644 section 3.4.2.2).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Present(String),
+    Compare(String, CompareOp, Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Co,
+    Sw,
+    Ew,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl CompareOp {
+    fn parse(word: &str) -> Option<CompareOp> {
+        match word.to_ascii_lowercase().as_str() {
+            "eq" => Some(CompareOp::Eq),
+            "ne" => Some(CompareOp::Ne),
+            "co" => Some(CompareOp::Co),
+            "sw" => Some(CompareOp::Sw),
+            "ew" => Some(CompareOp::Ew),
+            "gt" => Some(CompareOp::Gt),
+            "ge" => Some(CompareOp::Ge),
+            "lt" => Some(CompareOp::Lt),
+            "le" => Some(CompareOp::Le),
+            _ => None,
+        }
+    }
+}
+
+impl Filter {
+    /// The LDAP filter for this expression, with each comparison turned
+    /// into one by `leaf`. `Present` comes to `leaf` without an operator.
+    pub fn to_ldap<F>(&self, leaf: &F) -> Result<String, String>
+    where
+        F: Fn(&str, Option<(CompareOp, &Value)>) -> Result<String, String>,
+    {
+        Ok(match self {
+            Filter::And(a, b) => format!("(&{}{})", a.to_ldap(leaf)?, b.to_ldap(leaf)?),
+            Filter::Or(a, b) => format!("(|{}{})", a.to_ldap(leaf)?, b.to_ldap(leaf)?),
+            Filter::Not(f) => format!("(!{})", f.to_ldap(leaf)?),
+            Filter::Present(attribute) => leaf(attribute, None)?,
+            Filter::Compare(attribute, op, value) => leaf(attribute, Some((*op, value)))?,
+        })
+    }
+
+    /// The values of a filter made only of `<attribute> eq <value>` joined
+    /// by `or`, as in `members[value eq "a" or value eq "b"]`.
+    pub fn eq_values(&self, attribute: &str) -> Option<Vec<String>> {
+        match self {
+            Filter::Or(a, b) => {
+                let mut values = a.eq_values(attribute)?;
+                values.extend(b.eq_values(attribute)?);
+                Some(values)
+            }
+            Filter::Compare(name, CompareOp::Eq, value) if name.eq_ignore_ascii_case(attribute) => {
+                Some(vec![value_text(value)])
+            }
+            _ => None,
+        }
+    }
+}
+
+/// An LDAP comparison of `attribute` with a SCIM value.
+pub fn comparison(attribute: &str, op: CompareOp, value: &str) -> String {
+    let value = ldap3::ldap_escape(value);
+    match op {
+        CompareOp::Eq => format!("({}={})", attribute, value),
+        CompareOp::Ne => format!("(!({}={}))", attribute, value),
+        CompareOp::Co => format!("({}=*{}*)", attribute, value),
+        CompareOp::Sw => format!("({}={}*)", attribute, value),
+        CompareOp::Ew => format!("({}=*{})", attribute, value),
+        CompareOp::Ge => format!("({}>={})", attribute, value),
+        CompareOp::Le => format!("({}<={})", attribute, value),
+        // LDAP only has the inclusive orderings
+        CompareOp::Gt => format!("(&({0}>={1})(!({0}={1})))", attribute, value),
+        CompareOp::Lt => format!("(&({0}<={1})(!({0}={1})))", attribute, value),
+    }
+}
+
+/// A filter value as text; strings without their quotes.
+pub fn value_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Value(Value),
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let (start, c) = chars[i];
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(Token::Open);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::Close);
+                i += 1;
+            }
+            '"' => {
+                // A JSON string, escapes and all
+                let mut j = i + 1;
+                while j < chars.len() && chars[j].1 != '"' {
+                    j += if chars[j].1 == '\\' { 2 } else { 1 };
+                }
+                let end = chars
+                    .get(j)
+                    .map(|(at, _)| *at)
+                    .ok_or("unterminated string")?;
+                let value: String = serde_json::from_str(&text[start..=end])
+                    .map_err(|e| format!("invalid string: {}", e))?;
+                tokens.push(Token::Value(Value::String(value)));
+                i = j + 1;
+            }
+            _ => {
+                // Words run to a space or parenthesis, except inside the
+                // brackets of a value filter
+                let mut depth = 0;
+                let mut j = i;
+                while j < chars.len() {
+                    match chars[j].1 {
+                        '[' => depth += 1,
+                        ']' => depth -= 1,
+                        '"' if depth > 0 => {
+                            j += 1;
+                            while j < chars.len() && chars[j].1 != '"' {
+                                j += if chars[j].1 == '\\' { 2 } else { 1 };
+                            }
+                        }
+                        c if depth == 0 && (c.is_whitespace() || c == '(' || c == ')') => break,
+                        _ => {}
+                    }
+                    j += 1;
+                }
+                let end = chars.get(j).map_or(text.len(), |(at, _)| *at);
+                tokens.push(Token::Word(text[start..end].to_string()));
+                i = j;
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+}
+
+impl Parser {
+    fn peek_word(&self, word: &str) -> bool {
+        matches!(self.tokens.get(self.at), Some(Token::Word(w)) if w.eq_ignore_ascii_case(word))
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.at).cloned();
+        self.at += 1;
+        token
+    }
+
+    fn expect_close(&mut self) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Close) => Ok(()),
+            _ => Err("expected )".to_string()),
+        }
+    }
+
+    fn or(&mut self) -> Result<Filter, String> {
+        let mut filter = self.and()?;
+        while self.peek_word("or") {
+            self.at += 1;
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter, String> {
+        let mut filter = self.unary()?;
+        while self.peek_word("and") {
+            self.at += 1;
+            filter = Filter::And(Box::new(filter), Box::new(self.unary()?));
+        }
+        Ok(filter)
+    }
+
+    fn unary(&mut self) -> Result<Filter, String> {
+        if self.peek_word("not") {
+            self.at += 1;
+            if self.next() != Some(Token::Open) {
+                return Err("expected ( after not".to_string());
+            }
+            let filter = self.or()?;
+            self.expect_close()?;
+            return Ok(Filter::Not(Box::new(filter)));
+        }
+        match self.next() {
+            Some(Token::Open) => {
+                let filter = self.or()?;
+                self.expect_close()?;
+                Ok(filter)
+            }
+            Some(Token::Word(attribute)) => self.comparison(attribute),
+            Some(_) => Err("expected an attribute".to_string()),
+            None => Err("unexpected end of filter".to_string()),
+        }
+    }
+
+    fn comparison(&mut self, attribute: String) -> Result<Filter, String> {
+        let op = match self.next() {
+            Some(Token::Word(op)) if op.eq_ignore_ascii_case("pr") => {
+                return Ok(Filter::Present(attribute))
+            }
+            Some(Token::Word(op)) => {
+                CompareOp::parse(&op).ok_or_else(|| format!("unknown operator {}", op))?
+            }
+            _ => return Err(format!("expected an operator after {}", attribute)),
+        };
+        let value = match self.next() {
+            Some(Token::Value(value)) => value,
+            // true, false, null and numbers
+            Some(Token::Word(word)) => {
+                serde_json::from_str(&word).map_err(|_| format!("invalid value {}", word))?
+            }
+            _ => return Err(format!("expected a value after {}", attribute)),
+        };
+        Ok(Filter::Compare(attribute, op, value))
+    }
+}
+
+pub fn parse(text: &str) -> Result<Filter, String> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        at: 0,
+    };
+    let filter = parser.or()?;
+    if parser.at < parser.tokens.len() {
+        return Err("unexpected text after the filter".to_string());
+    }
+    Ok(filter)
+}
+
+/// An attribute path of a PATCH operation (RFC 7644 section 3.5.2), such
+/// as `name.givenName`, `emails[type eq "work"].value` or an extension
+/// attribute behind its schema URN.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path {
+    pub schema: Option<String>,
+    pub attribute: String,
+    pub filter: Option<Filter>,
+    pub sub_attribute: Option<String>,
+}
+
+pub fn parse_path(text: &str) -> Result<Path, String> {
+    let text = text.trim();
+    let (schema, rest) = match text.strip_prefix("urn:") {
+        // The URN's own dots, as in "2.0", come before its last colon
+        Some(_) => match text.rfind(':') {
+            Some(at) => (Some(text[..at].to_string()), &text[at + 1..]),
+            None => return Err(format!("invalid path {}", text)),
+        },
+        None => (None, text),
+    };
+
+    let (attribute, filter, rest) = match rest.find('[') {
+        Some(open) => {
+            let close = rest
+                .rfind(']')
+                .ok_or_else(|| format!("unclosed [ in path {}", text))?;
+            let filter = parse(&rest[open + 1..close])?;
+            (&rest[..open], Some(filter), &rest[close + 1..])
+        }
+        None => match rest.split_once('.') {
+            Some((attribute, _)) => (attribute, None, &rest[attribute.len()..]),
+            None => (rest, None, ""),
+        },
+    };
+    let sub_attribute = match rest {
+        "" => None,
+        rest => Some(
+            rest.strip_prefix('.')
+                .filter(|sub| !sub.is_empty() && !sub.contains('.'))
+                .ok_or_else(|| format!("invalid path {}", text))?
+                .to_string(),
+        ),
+    };
+    if attribute.is_empty() {
+        return Err(format!("invalid path {}", text));
+    }
+    Ok(Path {
+        schema,
+        attribute: attribute.to_string(),
+        filter,
+        sub_attribute,
+    })
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hickory_resolver::TokioAsyncResolver;
+use rand::Rng;
+
+use crate::config::{LdapConfig, LdapServer};
+
+/// How long a server which failed to connect is tried only after all others.
+const HOLD_DOWN: Duration = Duration::from_secs(30);
+
+/// The servers a directory may connect to, in the order to try them.
+pub async fn candidates(
+    config: &LdapConfig,
+    failed: &FailedServers,
+) -> Result<Vec<String>, String> {
+    let servers = match &config.discovery {
+        Some(discovery) => {
+            let (service, scheme) = if discovery.ldaps {
+                ("_ldaps._tcp", "ldaps")
+            } else {
+                ("_ldap._tcp", "ldap")
+            };
+            let name = format!("{}.{}.", service, discovery.domain.trim_end_matches('.'));
+            discover(&name, scheme).await?
+        }
+        None => config.servers.clone(),
+    };
+
+    let mut urls: Vec<String> = order(servers).into_iter().map(|s| s.url).collect();
+    // Servers in hold-down go last but stay in the list, so that a directory
+    // whose servers all failed is still retried.
+    urls.sort_by_key(|url| failed.is_held_down(url));
+    Ok(urls)
+}
+
+async fn discover(name: &str, scheme: &str) -> Result<Vec<LdapServer>, String> {
+    let resolver = TokioAsyncResolver::tokio_from_system_conf()
+        .map_err(|e| format!("cannot read resolver configuration: {}", e))?;
+    let records = resolver
+        .srv_lookup(name)
+        .await
+        .map_err(|e| format!("SRV lookup for {} failed: {}", name, e))?;
+
+    let servers: Vec<LdapServer> = records
+        .iter()
+        // A target of "." means the service is decidedly not available
+        .filter(|srv| !srv.target().is_root())
+        .map(|srv| LdapServer {
+            url: format!(
+                "{}://{}:{}",
+                scheme,
+                srv.target().to_utf8().trim_end_matches('.'),
+                srv.port()
+            ),
+            priority: srv.priority(),
+            weight: srv.weight(),
+        })
+        .collect();
+    if servers.is_empty() {
+        return Err(format!("no servers published under {}", name));
+    }
+    Ok(servers)
+}
+
+/// Sorts by priority, then orders each priority group by weighted random
+/// selection as RFC 2782 describes.
+fn order(mut servers: Vec<LdapServer>) -> Vec<LdapServer> {
+    servers.sort_by_key(|s| s.priority);
+    let mut rng = rand::thread_rng();
+    let mut ordered = Vec::with_capacity(servers.len());
+
+    while !servers.is_empty() {
+        let priority = servers[0].priority;
+        let end = servers
+            .iter()
+            .position(|s| s.priority != priority)
+            .unwrap_or(servers.len());
+        let mut group: Vec<LdapServer> = servers.drain(..end).collect();
+
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|s| s.weight as u32).sum();
+            let index = if total == 0 {
+                rng.gen_range(0..group.len())
+            } else {
+                let mut pick = rng.gen_range(0..total);
+                group
+                    .iter()
+                    .position(|s| {
+                        if pick < s.weight as u32 {
+                            true
+                        } else {
+                            pick -= s.weight as u32;
+                            false
+                        }
+                    })
+                    .unwrap_or(0)
+            };
+            ordered.push(group.remove(index));
+        }
+    }
+    ordered
+}
+
+/// Servers which recently failed to connect.
+#[derive(Default)]
+pub struct FailedServers {
+    failed: Mutex<HashMap<String, Instant>>,
+}
+
+impl FailedServers {
+    pub fn mark_failed(&self, url: &str) {
+        self.failed
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), Instant::now());
+    }
+
+    pub fn mark_ok(&self, url: &str) {
+        self.failed.lock().unwrap().remove(url);
+    }
+
+    fn is_held_down(&self, url: &str) -> bool {
+        self.failed
+            .lock()
+            .unwrap()
+            .get(url)
+            .is_some_and(|at| at.elapsed() < HOLD_DOWN)
+    }
+}
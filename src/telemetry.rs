@@ -0,0 +1,80 @@
+use std::time::Instant;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::{Data, Request, Response};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+use crate::metrics::metrics;
+use crate::request_id::RequestId;
+
+/// Installs the global JSON subscriber. Levels come from `LOG_LEVEL`, using
+/// the usual `EnvFilter` syntax (e.g. `info,ldap_api=debug`).
+pub fn init() {
+    let filter = EnvFilter::try_from_env("LOG_LEVEL").unwrap_or_else(|_| EnvFilter::new("info"));
+
+    tracing_subscriber::fmt()
+        .json()
+        .with_env_filter(filter)
+        .with_current_span(true)
+        .with_span_list(true)
+        .with_span_events(FmtSpan::CLOSE)
+        .init();
+}
+
+struct RequestStart(Instant);
+
+/// Assigns every request an ID, echoes it back in `X-Request-Id`, logs the
+/// start and outcome of each request and feeds the request metrics.
+pub struct RequestTracer;
+
+#[rocket::async_trait]
+impl Fairing for RequestTracer {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request tracing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Instant::now()));
+        let request_id = RequestId::of(request);
+        tracing::info!(
+            request_id = %request_id,
+            method = %request.method(),
+            uri = %request.uri(),
+            "request started"
+        );
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let request_id = RequestId::of(request);
+        let elapsed = request
+            .local_cache(|| RequestStart(Instant::now()))
+            .0
+            .elapsed();
+
+        tracing::info!(
+            request_id = %request_id,
+            method = %request.method(),
+            uri = %request.uri(),
+            status = response.status().code,
+            elapsed_ms = elapsed.as_secs_f64() * 1000.0,
+            "request completed"
+        );
+        let route = request
+            .route()
+            .map(|route| route.uri.to_string())
+            .unwrap_or_else(|| "unmatched".to_string());
+        metrics().observe_request(
+            &route,
+            request.method().as_str(),
+            response.status().code,
+            elapsed,
+        );
+
+        response.set_header(Header::new("X-Request-Id", request_id.0));
+    }
+}
@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use serde::Deserialize;
+
+use crate::directory::Directory;
+use crate::errors::Rejection;
+
+/// Header a client may send to set the timeout of every LDAP operation made
+/// for its request, in milliseconds.
+pub const TIMEOUT_HEADER: &str = "X-Timeout";
+
+/// The kinds of LDAP operation which get their own timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Bind,
+    Search,
+    Add,
+    /// Also covers extended operations which change an entry.
+    Modify,
+    Delete,
+}
+
+/// Per-operation timeouts of a directory, in milliseconds.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    pub bind_ms: u64,
+    pub search_ms: u64,
+    pub add_ms: u64,
+    pub modify_ms: u64,
+    pub delete_ms: u64,
+    /// Largest value accepted in `X-Timeout`.
+    pub max_ms: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            bind_ms: 5_000,
+            search_ms: 30_000,
+            add_ms: 10_000,
+            modify_ms: 10_000,
+            delete_ms: 10_000,
+            max_ms: 120_000,
+        }
+    }
+}
+
+/// The timeouts LDAP operations are made with.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    bind: Duration,
+    search: Duration,
+    add: Duration,
+    modify: Duration,
+    delete: Duration,
+}
+
+impl Timeouts {
+    /// The same timeout for every operation.
+    pub fn uniform(timeout: Duration) -> Timeouts {
+        Timeouts {
+            bind: timeout,
+            search: timeout,
+            add: timeout,
+            modify: timeout,
+            delete: timeout,
+        }
+    }
+
+    /// The longest of the timeouts, which bounds waiting for a connection.
+    pub fn longest(&self) -> Duration {
+        [self.bind, self.search, self.add, self.modify, self.delete]
+            .into_iter()
+            .max()
+            .unwrap_or_default()
+    }
+
+    pub fn get(&self, operation: Operation) -> Duration {
+        match operation {
+            Operation::Bind => self.bind,
+            Operation::Search => self.search,
+            Operation::Add => self.add,
+            Operation::Modify => self.modify,
+            Operation::Delete => self.delete,
+        }
+    }
+}
+
+impl From<&TimeoutConfig> for Timeouts {
+    fn from(config: &TimeoutConfig) -> Self {
+        Timeouts {
+            bind: Duration::from_millis(config.bind_ms),
+            search: Duration::from_millis(config.search_ms),
+            add: Duration::from_millis(config.add_ms),
+            modify: Duration::from_millis(config.modify_ms),
+            delete: Duration::from_millis(config.delete_ms),
+        }
+    }
+}
+
+/// The directory's configured timeouts, or the request's `X-Timeout` when it
+/// sends one. Values above the directory's `max_ms` are rejected.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Timeouts {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let directory = match request.guard::<&Directory>().await {
+            Outcome::Success(directory) => directory,
+            Outcome::Error((status, _)) => return Outcome::Error((status, String::new())),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+        let config = &directory.config.timeouts;
+
+        let header = match request.headers().get_one(TIMEOUT_HEADER) {
+            Some(header) => header,
+            None => return Outcome::Success(config.into()),
+        };
+        match header.trim().parse::<u64>() {
+            Ok(ms) if ms > 0 && ms <= config.max_ms => {
+                Outcome::Success(Timeouts::uniform(Duration::from_millis(ms)))
+            }
+            _ => {
+                let reason = format!(
+                    "{} must be a number of milliseconds between 1 and {}",
+                    TIMEOUT_HEADER, config.max_ms
+                );
+                request.local_cache(|| Rejection(Some(reason.clone())));
+                Outcome::Error((Status::BadRequest, reason))
+            }
+        }
+    }
+}
@@ -1,14 +1,20 @@
 #![allow(dead_code, non_snake_case)]
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-use base64::Engine;
 use ldap3::Mod;
-use ldap3::{Ldap, Scope, SearchEntry};
-use reqwest::Error;
+use ldap3::{LdapError, Scope, SearchEntry};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, info_span, warn, Instrument};
 
+use crate::audit::AuditChange;
+use crate::directory::{Connection, Directory};
 use crate::errors::APIErrors;
+use crate::flavor::DirectoryFlavor;
+use crate::mailbox::{Mailbox, Template};
+use crate::metrics::ldap_timed;
+use crate::timeout::Operation;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UserParams {
@@ -20,10 +26,125 @@ pub struct UserParams {
     pub sAMAccountName: String,
     pub mail: String,
     pub password: String,
-    pub create_cpanel_account: Option<bool>,
+    /// Provision a mailbox from the user's template.
+    #[serde(alias = "create_cpanel_account")]
+    pub create_mailbox: Option<bool>,
+    /// The user template; `default` when unset.
+    pub template: Option<String>,
+    /// Password of the new mailbox; generated when unset.
+    pub mailbox_password: Option<String>,
+    /// DN of the container to create the user in, at or under the
+    /// directory's `base_dn`; `base_dn` itself when unset.
+    pub ou: Option<String>,
+    /// Fields from the directory's attribute map.
+    #[serde(flatten)]
+    pub attributes: BTreeMap<String, Value>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl UserParams {
+    pub fn field_names() -> Vec<String> {
+        [
+            "cn",
+            "givenName",
+            "sn",
+            "displayName",
+            "userPrincipalName",
+            "sAMAccountName",
+            "mail",
+            "password",
+            "create_mailbox",
+            "create_cpanel_account",
+            "template",
+            "mailbox_password",
+            "ou",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+    }
+
+    /// The container the user is created in. Fails when `ou` lies outside
+    /// the directory.
+    pub fn container<'a>(&'a self, directory: &'a Directory) -> Result<&'a str, String> {
+        let base_dn = directory.config.base_dn.as_str();
+        let ou = match &self.ou {
+            Some(ou) => ou.trim(),
+            None => return Ok(base_dn),
+        };
+        let (ou_lower, base_lower) = (ou.to_lowercase(), base_dn.to_lowercase());
+        let under_base = ou_lower == base_lower
+            || ou_lower
+                .strip_suffix(&base_lower)
+                .is_some_and(|rdns| rdns.ends_with(','));
+        if under_base {
+            Ok(ou)
+        } else {
+            Err(format!("ou {} is not under {}", ou, base_dn))
+        }
+    }
+
+    /// DN of the new entry.
+    pub fn dn(&self, directory: &Directory) -> Result<String, String> {
+        Ok(directory
+            .flavor
+            .new_user_dn(self, self.container(directory)?))
+    }
+
+    /// Attributes of the new entry: the flavor's own plus the mapped fields
+    /// given. Fails when a mapped field cannot be written.
+    pub fn ldap_attributes(
+        &self,
+        directory: &Directory,
+    ) -> Result<Vec<(String, Vec<String>)>, String> {
+        let mut attrs = directory.flavor.new_user_attributes(self);
+        attrs.extend(directory.config.attributes.write(&self.attributes)?);
+        Ok(attrs)
+    }
+
+    /// The attributes written by `create_new_user`, for the audit log.
+    pub fn audit_changes(
+        &self,
+        flavor: &dyn DirectoryFlavor,
+        attrs: &[(String, Vec<String>)],
+    ) -> Vec<AuditChange> {
+        let mut changes: Vec<AuditChange> = attrs
+            .iter()
+            .map(|(attribute, values)| AuditChange::new(attribute, "add", values.clone()))
+            .collect();
+        changes.push(AuditChange::new(
+            flavor.password_attribute(),
+            "replace",
+            vec![self.password.clone()],
+        ));
+        changes.extend(
+            flavor
+                .activation_attributes()
+                .into_iter()
+                .map(|(attribute, values)| AuditChange::new(&attribute, "replace", values)),
+        );
+        changes
+    }
+}
+
+/// Attributes requested for user listings: every user and operational
+/// attribute, narrowed down by `UserAccount` and the attribute map.
+pub const USER_ATTRIBUTES: [&str; 2] = ["*", "+"];
+
+/// Body of a password reset.
+#[derive(Deserialize, Debug)]
+pub struct PasswordReset {
+    pub password: String,
+    /// New mailbox password; generated when unset.
+    pub mailbox_password: Option<String>,
+    /// The user template naming the mailbox provider; `default` when unset.
+    pub template: Option<String>,
+}
+
+/// The `userPrincipalName` and `mail` of a user, which its mailbox is named
+/// after.
+pub type MailIdentity = (Option<String>, Option<String>);
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct UserAccount {
     pub sAMAccountName: Option<Vec<String>>,
     pub sn: Option<Vec<String>>,
@@ -57,246 +178,255 @@ pub struct UserAccount {
     pub primaryGroupID: Option<Vec<String>>,
     pub uSNCreated: Option<Vec<String>>,
     pub dSCorePropagationData: Option<Vec<String>>,
+    /// The mailbox provisioned when the user was created.
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub mailbox: Option<Mailbox>,
+    /// Fields from the directory's attribute map.
+    #[serde(flatten)]
+    pub attributes: BTreeMap<String, Value>,
 }
 
 impl UserAccount {
-    pub fn default() -> Self {
+    pub fn field_names() -> Vec<String> {
+        let mut names = match serde_json::to_value(UserAccount::default()) {
+            Ok(Value::Object(fields)) => fields.keys().cloned().collect(),
+            _ => vec![],
+        };
+        names.push("mailbox".to_string());
+        names
+    }
+
+    /// Fills `distinguishedName` from the entry's DN for directories which do
+    /// not store it as an attribute.
+    pub fn from_entry(entry: SearchEntry, directory: &Directory) -> UserAccount {
+        let mut attrs = entry.attrs;
+        attrs
+            .entry("distinguishedName".to_string())
+            .or_insert_with(|| vec![entry.dn]);
         UserAccount {
-            sAMAccountName: None,
-            sn: None,
-            badPasswordTime: None,
-            uSNChanged: None,
-            objectClass: None,
-            logonCount: None,
-            homeDirectory: None,
-            accountExpires: None,
-            lastLogonTimestamp: None,
-            lastLogoff: None,
-            distinguishedName: None,
-            countryCode: None,
-            objectCategory: None,
-            cn: None,
-            codePage: None,
-            memberOf: None,
-            instanceType: None,
-            name: None,
-            givenName: None,
-            sAMAccountType: None,
-            userPrincipalName: None,
-            whenChanged: None,
-            pwdLastSet: None,
-            badPwdCount: None,
-            lastLogon: None,
-            whenCreated: None,
-            displayName: None,
-            homeDrive: None,
-            userAccountControl: None,
-            primaryGroupID: None,
-            uSNCreated: None,
-            dSCorePropagationData: None,
+            attributes: directory.config.attributes.read(&attrs),
+            ..attrs.into()
         }
     }
 
-    pub async fn fetch_all_users(ldap: &mut Ldap) -> Vec<UserAccount> {
-        let base_dn_string = std::env::var("BASE_DN").unwrap();
-        let base_dn = base_dn_string.as_str();
+    pub async fn fetch_all_users(
+        ldap: &mut Connection,
+        directory: &Directory,
+    ) -> Result<Vec<UserAccount>, APIErrors> {
+        let base_dn = directory.config.base_dn.as_str();
+
         // Perform a search
-        let (rs, _res) = ldap
-            .search(
+        let (rs, _res) = ldap_timed(
+            "search",
+            ldap.op(Operation::Search).search(
                 base_dn,
                 Scope::Subtree,
-                "(objectClass=user)",
-                vec!["*", "+"],
-            )
-            .await
-            .unwrap()
-            .success()
-            .unwrap();
+                directory.flavor.user_filter(),
+                USER_ATTRIBUTES.to_vec(),
+            ),
+        )
+        .instrument(info_span!("ldap.search", base = base_dn))
+        .await?
+        .success()?;
 
         let mut res = Vec::new();
 
         // Iterate through search results and print them
         for entry in rs {
-            let entry = SearchEntry::construct(entry);
-            let user: UserAccount = entry.attrs.into();
-            res.push(user);
+            res.push(UserAccount::from_entry(
+                SearchEntry::construct(entry),
+                directory,
+            ));
+        }
+        Ok(res)
+    }
+
+    /// Sets the initial password of a new user, then activates the account.
+    async fn set_up_new_user(
+        ldap: &mut Connection,
+        flavor: &dyn DirectoryFlavor,
+        dn: &str,
+        password: &str,
+    ) -> Result<(), LdapError> {
+        flavor.set_password(ldap, dn, password).await?;
+        let activation: Vec<Mod<String>> = flavor
+            .activation_attributes()
+            .into_iter()
+            .map(|(attribute, values)| Mod::Replace(attribute, values.into_iter().collect()))
+            .collect();
+        if !activation.is_empty() {
+            let res = ldap_timed("modify", ldap.op(Operation::Modify).modify(dn, activation))
+                .instrument(info_span!("ldap.modify", dn = dn))
+                .await?
+                .success()?;
+            debug!(rc = res.rc, "activated account");
         }
-        res
+        Ok(())
     }
 
     pub async fn create_new_user(
-        ldap: &mut Ldap,
+        ldap: &mut Connection,
+        directory: &Directory,
+        template: &Template,
         user: UserParams,
+        attrs: Vec<(String, Vec<String>)>,
     ) -> Result<UserAccount, APIErrors> {
-        let binding = format!("CN={},{}", user.cn, std::env::var("BASE_DN").unwrap()).to_owned();
+        let flavor = directory.flavor.as_ref();
+        let binding = user.dn(directory).map_err(|_| APIErrors::AddError(None))?;
         let new_user_dn = binding.as_str();
 
-        // Lookup the userPrincipalName to see if it already exists
-        let user_exists = Self::get_dn_from_uname(ldap, user.userPrincipalName.as_str()).await;
+        // Lookup the login name to see if it already exists
+        let user_exists =
+            Self::get_dn_from_uname(ldap, directory, flavor.login_name(&user)).await?;
         if user_exists.is_some() {
             return Err(APIErrors::EntryExists);
         }
 
-        let quoted_b64_password = format!("'{}'", user.password);
-
-        let new_password_utf16: Vec<u16> = quoted_b64_password.encode_utf16().collect();
-        let new_password_bytes: Vec<u8> = new_password_utf16
-            .iter()
-            .flat_map(|&c| c.to_le_bytes())
+        let new_user_attrs: Vec<(String, HashSet<String>)> = attrs
+            .into_iter()
+            .map(|(attribute, values)| (attribute, values.into_iter().collect()))
             .collect();
-        let b64_password = base64::engine::general_purpose::STANDARD.encode(&new_password_bytes);
-
-        let mut password_utf16: HashSet<&[u8]> = HashSet::new();
-        password_utf16.insert(&b64_password.as_bytes());
-
-        let new_user_attrs = vec![
-            (
-                "objectClass",
-                ["top", "person", "organizationalPerson", "user"]
-                    .iter()
-                    .cloned()
-                    .collect::<HashSet<_>>(),
-            ), // Object Class
-            (
-                "cn",
-                [user.cn.as_str()].iter().cloned().collect::<HashSet<_>>(),
-            ), // Common Name
-            (
-                "givenName",
-                [user.givenName.as_str()]
-                    .iter()
-                    .cloned()
-                    .collect::<HashSet<_>>(),
-            ), // First Name
-            (
-                "sn",
-                [user.sn.as_str()].iter().cloned().collect::<HashSet<_>>(),
-            ), // Surname
-            (
-                "displayName",
-                [user.givenName.as_str()]
-                    .iter()
-                    .cloned()
-                    .collect::<HashSet<_>>(),
-            ), // Display Name
-            (
-                "userPrincipalName",
-                [user.userPrincipalName.as_str()]
-                    .iter()
-                    .cloned()
-                    .collect::<HashSet<_>>(),
-            ), // User Logon Name
-            (
-                "sAMAccountName",
-                [user.sAMAccountName.as_str()]
-                    .iter()
-                    .cloned()
-                    .collect::<HashSet<_>>(),
-            ), // User Logon Name
-            (
-                "mail",
-                [user.mail.as_str()].iter().cloned().collect::<HashSet<_>>(),
-            ), // Internal Mail
-        ];
-        let res = ldap.add(new_user_dn, new_user_attrs).await;
-        if res.is_err() {
-            return Err(APIErrors::AddError);
+        let res = ldap_timed(
+            "add",
+            ldap.op(Operation::Add).add(new_user_dn, new_user_attrs),
+        )
+        .instrument(info_span!("ldap.add", dn = new_user_dn))
+        .await;
+        match res {
+            Ok(res) if res.rc == 68 => return Err(APIErrors::EntryExists),
+            Ok(res) if res.rc == 0 => {}
+            Ok(res) => return Err(APIErrors::AddError(Some(res.rc))),
+            Err(LdapError::Timeout { .. }) => return Err(APIErrors::Timeout),
+            Err(_) => return Err(APIErrors::AddError(None)),
         }
 
-        let _ = Self::set_password(ldap, new_user_dn, &user.password).await;
-        let _ = Self::update_user_account_control(ldap, new_user_dn, 66048).await;
-
-        println!("{:?}", user.create_cpanel_account);
-
-        if user.create_cpanel_account.unwrap_or(false) {
-            let cpanel_user = user.userPrincipalName.split('@').next().unwrap();
-            let res = create_cpanel_account(cpanel_user.to_string(), "jh.com.jo".to_string()).await;
-            println!("Cpanel account created: {:?}", res);
+        // A user left without its password or disabled is of no use, and
+        // would make a retry fail as already existing
+        if let Err(e) = Self::set_up_new_user(ldap, flavor, new_user_dn, &user.password).await {
+            warn!(dn = new_user_dn, error = %e, "setting up the new user failed, removing it");
+            let removed = ldap_timed("delete", ldap.op(Operation::Delete).delete(new_user_dn))
+                .instrument(info_span!("ldap.delete", dn = new_user_dn))
+                .await
+                .and_then(|res| res.success());
+            if let Err(e) = removed {
+                warn!(dn = new_user_dn, error = %e, "removing the new user failed");
+            }
+            return Err(match e {
+                LdapError::Timeout { .. } => APIErrors::Timeout,
+                LdapError::LdapResult { result } => APIErrors::AddError(Some(result.rc)),
+                _ => APIErrors::AddError(None),
+            });
         }
 
-        match Self::fetch_user(ldap, new_user_dn).await {
-            Some(user) => Ok(user),
+        let mailbox = if user.create_mailbox.unwrap_or(false) {
+            template
+                .create(
+                    &user.userPrincipalName,
+                    Some(&user.mail),
+                    user.mailbox_password.as_deref(),
+                )
+                .await
+        } else {
+            None
+        };
+
+        match Self::fetch_user(ldap, directory, new_user_dn).await {
+            Some(user) => Ok(UserAccount { mailbox, ..user }),
             None => Err(APIErrors::EntryNotFound),
         }
     }
 
-    pub async fn fetch_user(ldap: &mut Ldap, dn: &str) -> Option<UserAccount> {
-        let (rs, _res) = ldap
-            .search(dn, ldap3::Scope::Base, "(objectClass=user)", vec!["*", "+"])
-            .await
-            .ok()?
-            .success()
-            .ok()?; // Get the search result
+    pub async fn fetch_user(
+        ldap: &mut Connection,
+        directory: &Directory,
+        dn: &str,
+    ) -> Option<UserAccount> {
+        let (rs, _res) = ldap_timed(
+            "search",
+            ldap.op(Operation::Search)
+                .search(dn, Scope::Base, "(objectClass=*)", vec!["*", "+"]),
+        )
+        .instrument(info_span!("ldap.search", base = dn))
+        .await
+        .ok()?
+        .success()
+        .ok()?; // Get the search result
 
         let entry = rs.into_iter().next()?;
 
-        let entry = ldap3::SearchEntry::construct(entry);
-
-        Some(entry.attrs.into())
+        Some(UserAccount::from_entry(
+            SearchEntry::construct(entry),
+            directory,
+        ))
     }
 
-    async fn set_password(
-        conn: &mut ldap3::Ldap,
-        user_dn: &str,
-        new_password: &str,
-    ) -> Result<(), ldap3::LdapError> {
-        // Encode the password
-        let attr_name = String::from("unicodePwd").into_bytes();
-        let values: Vec<u8> = format!("\"{}\"", new_password)
-            .encode_utf16()
-            .flat_map(|v| v.to_le_bytes())
-            .collect();
-        let mut passwd = HashSet::new();
-        passwd.insert(values);
-        let mods = vec![Mod::Replace(attr_name, passwd)];
-        let result = conn.modify(&user_dn, mods).await.unwrap();
-        println!("Set password result: {:?}", result);
-        Ok(())
+    pub async fn mail_identity(ldap: &mut Connection, dn: &str) -> Result<MailIdentity, APIErrors> {
+        let (rs, _res) = ldap_timed(
+            "search",
+            ldap.op(Operation::Search).search(
+                dn,
+                Scope::Base,
+                "(objectClass=*)",
+                vec!["userPrincipalName", "mail"],
+            ),
+        )
+        .instrument(info_span!("ldap.search", base = dn))
+        .await?
+        .success()?;
+
+        let attrs = match rs.into_iter().next() {
+            Some(entry) => SearchEntry::construct(entry).attrs,
+            None => return Err(APIErrors::EntryNotFound),
+        };
+        let first = |name: &str| attrs.get(name).and_then(|v| v.first()).cloned();
+        Ok((first("userPrincipalName"), first("mail")))
     }
 
-    async fn update_user_account_control(
-        conn: &mut ldap3::Ldap,
-        user_dn: &str,
-        flag: u32,
-    ) -> Result<(), ldap3::LdapError> {
-        // First, get the current userAccountControl value
-        let res = conn
-            .modify(
-                user_dn,
-                vec![ldap3::Mod::Replace(
-                    "userAccountControl",
-                    HashSet::from([flag.to_string().as_str()]),
-                )],
-            )
-            .await;
-        println!("Update user account control result: {:?}", res);
-        Ok(())
+    pub async fn get_guid(
+        ldap: &mut Connection,
+        flavor: &dyn DirectoryFlavor,
+        dn: &str,
+    ) -> Option<String> {
+        let (rs, _res) = ldap_timed(
+            "search",
+            ldap.op(Operation::Search).search(
+                dn,
+                Scope::Base,
+                "(objectClass=*)",
+                vec![flavor.guid_attribute()],
+            ),
+        )
+        .instrument(info_span!("ldap.search", base = dn))
+        .await
+        .ok()?
+        .success()
+        .ok()?;
+
+        flavor.guid(&SearchEntry::construct(rs.into_iter().next()?))
     }
 
-    pub async fn get_dn_from_uname(ldap: &mut Ldap, uname: &str) -> Option<String> {
-        let filter = format!(
-            "(&(objectCategory=person)(objectClass=user)(userPrincipalName={}))",
-            uname
-        );
+    pub async fn get_dn_from_uname(
+        ldap: &mut Connection,
+        directory: &Directory,
+        uname: &str,
+    ) -> Result<Option<String>, APIErrors> {
+        let base_dn = directory.config.base_dn.as_str();
+        let filter = directory.flavor.login_filter(uname);
 
-        let base_dn_string = std::env::var("BASE_DN").unwrap();
-        let base_dn = base_dn_string.as_str();
         // Perform a search
-        let (rs, _res) = ldap
-            .search(base_dn, Scope::Subtree, &filter, vec!["distinguishedName"])
-            .await
-            .unwrap()
-            .success()
-            .unwrap();
-
-        if rs.is_empty() {
-            return None;
-        }
+        let (rs, _res) = ldap_timed(
+            "search",
+            ldap.op(Operation::Search)
+                .search(base_dn, Scope::Subtree, &filter, vec!["1.1"]),
+        )
+        .instrument(info_span!("ldap.search", base = base_dn))
+        .await?
+        .success()?;
 
-        let res = rs[0].clone();
-        let entry = SearchEntry::construct(res);
-        let dn = entry.attrs.get("distinguishedName").unwrap();
-        Some(dn[0].clone())
+        Ok(rs
+            .into_iter()
+            .next()
+            .map(|entry| SearchEntry::construct(entry).dn))
     }
 }
 
@@ -335,24 +465,8 @@ impl From<HashMap<String, Vec<String>>> for UserAccount {
             primaryGroupID: attrs.get("primaryGroupID").cloned(),
             uSNCreated: attrs.get("uSNCreated").cloned(),
             dSCorePropagationData: attrs.get("dSCorePropagationData").cloned(),
+            mailbox: None,
+            attributes: BTreeMap::new(),
         }
     }
 }
-
-async fn create_cpanel_account(user: String, domain: String) -> Result<String, Error> {
-    let cpanel_url = std::env::var("CPANEL_URL").unwrap();
-    let user_password = std::env::var("CPANEL_PASSWORD").unwrap();
-    let access_token = std::env::var("CPANEL_ACCESS_TOKEN").unwrap();
-    let client = reqwest::Client::new();
-    let res = client
-        .get(format!(
-            "{}/execute/Email/add_pop?email={}&password={}&domain={}&quota=2048",
-            cpanel_url, user, user_password, domain
-        ))
-        .header("Authorization", access_token)
-        .send()
-        .await?;
-    let body = res.text().await?;
-    let json = serde_json::from_str::<serde_json::Value>(&body).unwrap();
-    Ok(json["data"].as_str().unwrap_or("").to_string())
-}
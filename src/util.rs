@@ -0,0 +1,68 @@
+use ldap3::LdapError;
+
+/// Formats a binary objectGUID in the usual registry form. The first three
+/// groups are stored little-endian.
+pub fn format_guid(bytes: &[u8]) -> Option<String> {
+    if bytes.len() != 16 {
+        return None;
+    }
+    Some(format!(
+        "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        bytes[3], bytes[2], bytes[1], bytes[0],
+        bytes[5], bytes[4],
+        bytes[7], bytes[6],
+        bytes[8], bytes[9],
+        bytes[10], bytes[11], bytes[12], bytes[13], bytes[14], bytes[15]
+    ))
+}
+
+pub fn parse_guid(guid: &str) -> Option<[u8; 16]> {
+    let hex: String = guid
+        .trim_matches(|c| c == '{' || c == '}')
+        .chars()
+        .filter(|c| *c != '-')
+        .collect();
+    if hex.len() != 32 {
+        return None;
+    }
+
+    let mut raw = [0u8; 16];
+    for (i, byte) in raw.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+
+    let order = [3, 2, 1, 0, 5, 4, 7, 6, 8, 9, 10, 11, 12, 13, 14, 15];
+    let mut bytes = [0u8; 16];
+    for (i, pos) in order.iter().enumerate() {
+        bytes[i] = raw[*pos];
+    }
+    Some(bytes)
+}
+
+pub fn escape_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("\\{:02x}", b)).collect()
+}
+
+/// The DN above `dn`, skipping escaped commas. `None` for a single RDN.
+pub fn parent_dn(dn: &str) -> Option<&str> {
+    let mut escaped = false;
+    for (i, c) in dn.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            ',' => return Some(dn[i + 1..].trim_start()),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The LDAP result code of an operation, for the audit log; `None` when the
+/// server never answered.
+pub fn result_code(res: &Result<(), LdapError>) -> Option<u32> {
+    match res {
+        Ok(()) => Some(0),
+        Err(LdapError::LdapResult { result }) => Some(result.rc),
+        Err(_) => None,
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ldap3::{Scope, SearchEntry};
+use rocket::tokio::time::sleep;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::config::ChangeFeedConfig;
+use crate::deleted::{deleted_objects_dn, show_deleted};
+use crate::directory::{Connection, Directory};
+use crate::events::EventBus;
+use crate::metrics::ldap_timed;
+use crate::timeout::Operation;
+use crate::user::{UserAccount, USER_ATTRIBUTES};
+use crate::webhooks::{account_data, Event, EventKind};
+
+/// What the feed last saw of a user.
+struct Known {
+    dn: String,
+    login: Option<String>,
+    enabled: bool,
+    /// `uSNChanged` of the entry as last read.
+    usn: Option<u64>,
+}
+
+/// Finds changes made to an Active Directory domain without the API, by
+/// polling for users whose `uSNChanged` moved past the last poll and for new
+/// tombstones. DirSync would need the "Replicating Directory Changes" right,
+/// which the API's account does not otherwise have.
+struct Watcher {
+    directory: Arc<Directory>,
+    events: Arc<EventBus>,
+    interval: Duration,
+    /// Update sequence numbers are local to each domain controller, so they
+    /// only compare on the server they came from.
+    server: Option<String>,
+    usn: u64,
+    known: HashMap<String, Known>,
+}
+
+/// Starts watching `directory`, if its flavor allows it.
+pub fn spawn(directory: Arc<Directory>, events: Arc<EventBus>, config: &ChangeFeedConfig) {
+    if !directory.flavor.supports_change_feed() {
+        warn!(directory = %directory.name, flavor = directory.flavor.name(), "the change feed needs uSNChanged, not watching");
+        return;
+    }
+    events.watch(&directory.name);
+    let span = info_span!("change_feed", directory = %directory.name);
+    let watcher = Watcher {
+        directory,
+        events,
+        interval: Duration::from_millis(config.poll_interval_ms),
+        server: None,
+        usn: 0,
+        known: HashMap::new(),
+    };
+    rocket::tokio::spawn(watcher.run().instrument(span));
+}
+
+impl Watcher {
+    async fn run(mut self) {
+        loop {
+            if let Err(e) = self.poll().await {
+                warn!(error = %e, "change feed poll failed");
+            }
+            sleep(self.interval).await;
+        }
+    }
+
+    async fn poll(&mut self) -> Result<(), String> {
+        let directory = self.directory.clone();
+        let mut ldap = directory
+            .lock_ldap((&directory.config.timeouts).into())
+            .await
+            .map_err(|_| "timed out waiting for the directory".to_string())?;
+        let highest = highest_usn(&mut ldap).await?;
+
+        if self.server.as_deref() != Some(ldap.server.as_str()) {
+            return self.resync(&mut ldap, highest).await;
+        }
+        if highest <= self.usn {
+            return Ok(());
+        }
+
+        let filter = format!(
+            "(&{}(uSNChanged>={}))",
+            directory.flavor.user_filter(),
+            self.usn + 1
+        );
+        let changed = ldap
+            .search_paged(
+                &directory.config.base_dn,
+                Scope::Subtree,
+                &filter,
+                USER_ATTRIBUTES.to_vec(),
+                vec![],
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        for entry in changed {
+            self.changed(entry);
+        }
+
+        let tombstones = deleted_objects_dn(&mut ldap)
+            .await
+            .map_err(|_| "cannot find the Deleted Objects container".to_string())?;
+        let filter = format!(
+            "(&(objectClass=user)(isDeleted=TRUE)(uSNChanged>={}))",
+            self.usn + 1
+        );
+        let deleted = ldap
+            .search_paged(
+                &tombstones,
+                Scope::OneLevel,
+                &filter,
+                vec![directory.flavor.guid_attribute(), "uSNChanged"],
+                vec![show_deleted()],
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        for entry in deleted {
+            if let Some(guid) = directory.flavor.guid(&entry) {
+                self.deleted(&guid, usn_changed(&entry));
+            }
+        }
+
+        // Changes committed while searching have higher numbers and come up
+        // again next time, rather than being missed
+        self.usn = highest;
+        self.events
+            .forget_writes(&directory.name, &ldap.server, highest);
+        Ok(())
+    }
+
+    /// Reads every user afresh. Only differences in existence and in being
+    /// enabled are reported, as the earlier state of other attributes is not
+    /// kept; this happens on start, when nothing is reported, and after
+    /// failing over to another server.
+    async fn resync(&mut self, ldap: &mut Connection, highest: u64) -> Result<(), String> {
+        let directory = self.directory.clone();
+        let users = ldap
+            .search_paged(
+                &directory.config.base_dn,
+                Scope::Subtree,
+                directory.flavor.user_filter(),
+                USER_ATTRIBUTES.to_vec(),
+                vec![],
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let report = self.server.is_some();
+        // Writes the API noted on this server are compared as entries come up
+        self.server = Some(ldap.server.clone());
+        let mut previous = std::mem::take(&mut self.known);
+        for entry in users {
+            let Some((guid, known)) = self.read(&entry) else {
+                continue;
+            };
+            let kind = match previous.remove(&guid) {
+                None => Some(EventKind::UserCreated),
+                Some(before) if before.enabled && !known.enabled => Some(EventKind::UserDisabled),
+                Some(before) if before.enabled != known.enabled || before.dn != known.dn => {
+                    Some(EventKind::UserUpdated)
+                }
+                Some(_) => None,
+            };
+            if let (Some(kind), true) = (kind, report) {
+                let account = UserAccount::from_entry(entry, &directory);
+                self.publish(kind, &known, account_data(&account));
+            }
+            self.known.insert(guid, known);
+        }
+        if report {
+            for (guid, known) in previous {
+                self.publish(
+                    EventKind::UserDeleted,
+                    &known,
+                    serde_json::json!({ "guid": guid }),
+                );
+            }
+        }
+
+        self.usn = highest;
+        self.events
+            .forget_writes(&directory.name, &ldap.server, highest);
+        info!(server = %ldap.server, usn = highest, users = self.known.len(), "change feed synced");
+        Ok(())
+    }
+
+    fn changed(&mut self, entry: SearchEntry) {
+        let Some((guid, known)) = self.read(&entry) else {
+            return;
+        };
+        let kind = match self.known.get(&guid) {
+            None => EventKind::UserCreated,
+            Some(before) if before.enabled && !known.enabled => EventKind::UserDisabled,
+            Some(_) => EventKind::UserUpdated,
+        };
+        let account = UserAccount::from_entry(entry, &self.directory);
+        self.publish(kind, &known, account_data(&account));
+        self.known.insert(guid, known);
+    }
+
+    fn deleted(&mut self, guid: &str, usn: Option<u64>) {
+        // Users deleted before the feed knew of them have nothing to report
+        if let Some(known) = self.known.remove(guid) {
+            let known = Known { usn, ..known };
+            self.publish(
+                EventKind::UserDeleted,
+                &known,
+                serde_json::json!({ "guid": guid }),
+            );
+        }
+    }
+
+    fn read(&self, entry: &SearchEntry) -> Option<(String, Known)> {
+        let flavor = self.directory.flavor.as_ref();
+        let guid = flavor.guid(entry)?;
+        let known = Known {
+            dn: entry.dn.clone(),
+            login: entry
+                .attrs
+                .get(flavor.login_attribute())
+                .and_then(|v| v.first().cloned()),
+            enabled: flavor.is_enabled(entry),
+            usn: usn_changed(entry),
+        };
+        Some((guid, known))
+    }
+
+    fn publish(&self, kind: EventKind, user: &Known, data: serde_json::Value) {
+        let name = &self.directory.name;
+        let server = self.server.as_deref().unwrap_or_default();
+        if user
+            .usn
+            .is_some_and(|usn| self.events.written_by_api(name, &user.dn, server, usn))
+        {
+            debug!(dn = %user.dn, kind = kind.name(), "change made through the API, not reported again");
+            return;
+        }
+        info!(dn = %user.dn, kind = kind.name(), "external change");
+        self.events.publish(Event::observed(
+            kind,
+            name,
+            &user.dn,
+            user.login.as_deref(),
+            data,
+        ));
+    }
+}
+
+fn usn_changed(entry: &SearchEntry) -> Option<u64> {
+    entry.attrs.get("uSNChanged")?.first()?.parse().ok()
+}
+
+/// The server's `highestCommittedUSN`, from its rootDSE.
+pub async fn highest_usn(ldap: &mut Connection) -> Result<u64, String> {
+    let (rs, _res) = ldap_timed(
+        "search",
+        ldap.op(Operation::Search).search(
+            "",
+            Scope::Base,
+            "(objectClass=*)",
+            vec!["highestCommittedUSN"],
+        ),
+    )
+    .instrument(info_span!("ldap.search", base = "rootDSE"))
+    .await
+    .and_then(|res| res.success())
+    .map_err(|e| e.to_string())?;
+
+    rs.into_iter()
+        .next()
+        .map(SearchEntry::construct)
+        .and_then(|entry| {
+            entry
+                .attrs
+                .get("highestCommittedUSN")?
+                .first()?
+                .parse()
+                .ok()
+        })
+        .ok_or_else(|| "the rootDSE has no highestCommittedUSN".to_string())
+}
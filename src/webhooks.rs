@@ -0,0 +1,388 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use redb::{Database, ReadableTable, TableDefinition};
+use rocket::tokio::task::spawn_blocking;
+use rocket::tokio::time::sleep;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use tracing::{error, info, warn};
+
+use crate::audit::Auditor;
+use crate::config::{WebhookEndpoint, WebhooksConfig};
+use crate::jobs::StoreError;
+use crate::metrics::metrics;
+use crate::user::UserAccount;
+
+/// Dead letters by ID, as JSON.
+const DEAD_LETTERS: TableDefinition<&str, &[u8]> = TableDefinition::new("dead_letters");
+
+/// Longest wait between two attempts at a delivery.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+pub const EVENT_HEADER: &str = "X-Webhook-Event";
+pub const ID_HEADER: &str = "X-Webhook-Id";
+pub const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+/// `sha256=<hex HMAC-SHA256 of "<timestamp>.<body>">`, keyed with the
+/// endpoint's secret.
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    #[serde(rename = "user.created")]
+    UserCreated,
+    #[serde(rename = "user.updated")]
+    UserUpdated,
+    #[serde(rename = "user.disabled")]
+    UserDisabled,
+    #[serde(rename = "user.deleted")]
+    UserDeleted,
+    #[serde(rename = "group.membership_changed")]
+    GroupMembershipChanged,
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::UserCreated => "user.created",
+            EventKind::UserUpdated => "user.updated",
+            EventKind::UserDisabled => "user.disabled",
+            EventKind::UserDeleted => "user.deleted",
+            EventKind::GroupMembershipChanged => "group.membership_changed",
+        }
+    }
+}
+
+/// Where an event was noticed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EventSource {
+    /// A change made through the API.
+    #[default]
+    Api,
+    /// A change made elsewhere, found by the change feed.
+    Directory,
+}
+
+/// A change to the directory, as posted to webhooks.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Event {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    pub occurred_at: DateTime<Utc>,
+    #[serde(default)]
+    pub source: EventSource,
+    pub directory: String,
+    pub actor: String,
+    pub request_id: String,
+    pub dn: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub login: Option<String>,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub data: Value,
+}
+
+impl Event {
+    pub fn new(
+        kind: EventKind,
+        directory: &str,
+        auditor: &Auditor,
+        dn: &str,
+        login: Option<&str>,
+        data: Value,
+    ) -> Event {
+        Event {
+            id: uuid::Uuid::new_v4().to_string(),
+            kind,
+            occurred_at: Utc::now(),
+            source: EventSource::Api,
+            directory: directory.to_string(),
+            actor: auditor.actor().to_string(),
+            request_id: auditor.request_id().to_string(),
+            dn: dn.to_string(),
+            login: login.map(str::to_string),
+            data,
+        }
+    }
+
+    /// A change the change feed found. Who made it is not known.
+    pub fn observed(
+        kind: EventKind,
+        directory: &str,
+        dn: &str,
+        login: Option<&str>,
+        data: Value,
+    ) -> Event {
+        Event {
+            id: uuid::Uuid::new_v4().to_string(),
+            kind,
+            occurred_at: Utc::now(),
+            source: EventSource::Directory,
+            directory: directory.to_string(),
+            actor: "external".to_string(),
+            request_id: String::new(),
+            dn: dn.to_string(),
+            login: login.map(str::to_string),
+            data,
+        }
+    }
+}
+
+/// A new user's account, without the mailbox, whose generated password is
+/// for the caller alone.
+pub fn account_data(account: &UserAccount) -> Value {
+    let mut data = serde_json::to_value(account).unwrap_or_default();
+    if let Value::Object(fields) = &mut data {
+        fields.remove("mailbox");
+    }
+    data
+}
+
+/// A delivery which used up its attempts.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeadLetter {
+    pub id: String,
+    pub endpoint: String,
+    pub event: Event,
+    pub attempts: u32,
+    pub last_error: String,
+    pub failed_at: DateTime<Utc>,
+}
+
+pub enum Replay {
+    Delivered(DeadLetter),
+    /// Still failing; the dead letter stays.
+    Failed(DeadLetter),
+}
+
+#[derive(Clone)]
+struct DeadLetterStore {
+    db: Arc<Database>,
+}
+
+impl DeadLetterStore {
+    fn open(config: &WebhooksConfig) -> Result<DeadLetterStore, StoreError> {
+        let db = Database::create(&config.dead_letter_path)?;
+        let txn = db.begin_write()?;
+        txn.open_table(DEAD_LETTERS)?;
+        txn.commit()?;
+        Ok(DeadLetterStore { db: Arc::new(db) })
+    }
+
+    fn save(&self, letter: &DeadLetter) -> Result<(), StoreError> {
+        let value = serde_json::to_vec(letter).unwrap_or_default();
+        let txn = self.db.begin_write()?;
+        txn.open_table(DEAD_LETTERS)?
+            .insert(letter.id.as_str(), value.as_slice())?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    fn load(&self, id: &str) -> Result<Option<DeadLetter>, StoreError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(DEAD_LETTERS)?;
+        let letter = table
+            .get(id)?
+            .and_then(|value| serde_json::from_slice(value.value()).ok());
+        Ok(letter)
+    }
+
+    fn remove(&self, id: &str) -> Result<bool, StoreError> {
+        let txn = self.db.begin_write()?;
+        let removed = txn.open_table(DEAD_LETTERS)?.remove(id)?.is_some();
+        txn.commit()?;
+        Ok(removed)
+    }
+
+    fn all(&self) -> Result<Vec<DeadLetter>, StoreError> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(DEAD_LETTERS)?;
+        let mut letters = Vec::new();
+        for entry in table.iter()? {
+            if let Ok(letter) = serde_json::from_slice(entry?.1.value()) {
+                letters.push(letter);
+            }
+        }
+        Ok(letters)
+    }
+
+    /// Runs a store operation off the async threads.
+    async fn run<T, F>(&self, op: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&DeadLetterStore) -> Result<T, StoreError> + Send + 'static,
+    {
+        let store = self.clone();
+        spawn_blocking(move || op(&store))
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.0)
+    }
+}
+
+/// Posts lifecycle events to the configured endpoints. Deliveries retry
+/// with backoff in the background; those still failing are kept as dead
+/// letters for an admin to replay. Retries in progress do not survive a
+/// restart.
+pub struct Webhooks {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    endpoints: BTreeMap<String, WebhookEndpoint>,
+    client: reqwest::Client,
+    dead_letters: DeadLetterStore,
+}
+
+impl Webhooks {
+    pub fn new(config: &WebhooksConfig) -> Result<Arc<Webhooks>, String> {
+        let dead_letters = DeadLetterStore::open(config).map_err(|e| {
+            format!(
+                "cannot open webhook dead letters {}: {}",
+                config.dead_letter_path.display(),
+                e.0
+            )
+        })?;
+        let client = reqwest::Client::builder()
+            .build()
+            .map_err(|e| format!("cannot build webhook client: {}", e))?;
+        Ok(Arc::new(Webhooks {
+            max_attempts: config.max_attempts,
+            initial_backoff: config.initial_backoff,
+            endpoints: config.endpoints.clone(),
+            client,
+            dead_letters,
+        }))
+    }
+
+    /// Sends `event` to every endpoint subscribed to it, without waiting.
+    pub fn emit(self: &Arc<Self>, event: Event) {
+        for (name, endpoint) in &self.endpoints {
+            if !endpoint.events.is_empty() && !endpoint.events.contains(&event.kind) {
+                continue;
+            }
+            let (webhooks, name, event) = (self.clone(), name.clone(), event.clone());
+            rocket::tokio::spawn(async move { webhooks.deliver(name, event).await });
+        }
+    }
+
+    async fn deliver(&self, endpoint: String, event: Event) {
+        let mut backoff = self.initial_backoff;
+        let mut last_error = String::new();
+        for attempt in 1..=self.max_attempts {
+            match self.send(&endpoint, &event).await {
+                Ok(()) => return,
+                Err(e) => {
+                    warn!(endpoint, event = %event.id, attempt, error = %e, "webhook delivery failed");
+                    last_error = e;
+                }
+            }
+            if attempt < self.max_attempts {
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+
+        let letter = DeadLetter {
+            id: uuid::Uuid::new_v4().to_string(),
+            endpoint,
+            event,
+            attempts: self.max_attempts,
+            last_error,
+            failed_at: Utc::now(),
+        };
+        error!(endpoint = %letter.endpoint, event = %letter.event.id, dead_letter = %letter.id, "webhook delivery gave up");
+        let saved = letter.clone();
+        if let Err(e) = self.dead_letters.run(move |s| s.save(&saved)).await {
+            error!(error = %e, letter = ?letter, "failed to save webhook dead letter");
+        }
+    }
+
+    /// One attempt at posting `event` to the named endpoint.
+    async fn send(&self, name: &str, event: &Event) -> Result<(), String> {
+        let endpoint = self
+            .endpoints
+            .get(name)
+            .ok_or_else(|| format!("endpoint {} is no longer configured", name))?;
+        let body = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+        let timestamp = Utc::now().timestamp();
+
+        let res = self
+            .client
+            .post(&endpoint.url)
+            .timeout(endpoint.timeout)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, event.kind.name())
+            .header(ID_HEADER, &event.id)
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(
+                SIGNATURE_HEADER,
+                signature(endpoint.secret.expose().as_bytes(), timestamp, &body),
+            )
+            .body(body)
+            .send()
+            .await;
+        let res = match res {
+            Ok(res) if res.status().is_success() => Ok(()),
+            Ok(res) => Err(format!("endpoint answered HTTP {}", res.status().as_u16())),
+            Err(e) if e.is_timeout() => Err("endpoint timed out".to_string()),
+            Err(e) => Err(format!("cannot reach endpoint: {}", e)),
+        };
+        metrics().observe_webhook(name, res.is_ok());
+        if res.is_ok() {
+            info!(endpoint = name, event = %event.id, kind = event.kind.name(), "webhook delivered");
+        }
+        res
+    }
+
+    /// The dead letters, most recent first.
+    pub async fn dead_letters(&self) -> Result<Vec<DeadLetter>, String> {
+        let mut letters = self.dead_letters.run(|s| s.all()).await?;
+        letters.sort_by_key(|letter| std::cmp::Reverse(letter.failed_at));
+        Ok(letters)
+    }
+
+    /// Tries a dead letter once more, dropping it once delivered.
+    pub async fn replay(&self, id: &str) -> Result<Option<Replay>, String> {
+        let key = id.to_string();
+        let mut letter = match self.dead_letters.run(move |s| s.load(&key)).await? {
+            Some(letter) => letter,
+            None => return Ok(None),
+        };
+        letter.attempts += 1;
+        match self.send(&letter.endpoint, &letter.event).await {
+            Ok(()) => {
+                let key = id.to_string();
+                self.dead_letters.run(move |s| s.remove(&key)).await?;
+                Ok(Some(Replay::Delivered(letter)))
+            }
+            Err(e) => {
+                letter.last_error = e;
+                letter.failed_at = Utc::now();
+                let saved = letter.clone();
+                self.dead_letters.run(move |s| s.save(&saved)).await?;
+                Ok(Some(Replay::Failed(letter)))
+            }
+        }
+    }
+
+    /// Drops a dead letter without delivering it.
+    pub async fn discard(&self, id: &str) -> Result<bool, String> {
+        let key = id.to_string();
+        self.dead_letters.run(move |s| s.remove(&key)).await
+    }
+}
+
+pub fn signature(secret: &[u8], timestamp: i64, body: &[u8]) -> String {
+    // HMAC takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    let digest = mac.finalize().into_bytes();
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256={}", hex)
+}
@@ -0,0 +1,16 @@
+#!/bin/sh
+# Creates the realm and the two principals on first start, exporting their
+# keys for slapd and for the API.
+set -e
+
+if [ ! -f /var/lib/krb5kdc/principal ]; then
+    kdb5_util create -s -r EXAMPLE.TEST -P master-password
+    kadmin.local -q "addprinc -randkey ldap/ldap.example.test@EXAMPLE.TEST"
+    kadmin.local -q "addprinc -randkey ldap-api@EXAMPLE.TEST"
+    rm -f /out/ldap.keytab /out/ldap-api.keytab
+    kadmin.local -q "ktadd -k /out/ldap.keytab ldap/ldap.example.test@EXAMPLE.TEST"
+    kadmin.local -q "ktadd -k /out/ldap-api.keytab ldap-api@EXAMPLE.TEST"
+    chmod 644 /out/ldap.keytab /out/ldap-api.keytab
+fi
+
+exec krb5kdc -n
@@ -0,0 +1,32 @@
+#!/bin/sh
+# Issues the CA, server and client certificates, loads the base entries on
+# first start and runs slapd on ldap:// and ldaps://.
+set -e
+
+until [ -f /out/ldap.keytab ]; do
+    echo "waiting for the KDC to export the service keytab"
+    sleep 1
+done
+
+if [ ! -f /out/ca.pem ]; then
+    cd /out
+    openssl req -x509 -newkey rsa:2048 -nodes -days 365 -subj "/CN=Test CA" \
+        -keyout ca.key -out ca.pem
+    openssl req -newkey rsa:2048 -nodes -subj "/CN=ldap.example.test" \
+        -keyout server.key -out server.csr
+    printf "subjectAltName=DNS:ldap.example.test,DNS:localhost,IP:127.0.0.1\n" > server.ext
+    openssl x509 -req -in server.csr -CA ca.pem -CAkey ca.key -CAcreateserial \
+        -days 365 -extfile server.ext -out server.pem
+    # PKCS#8, as the API loads client keys
+    openssl req -newkey rsa:2048 -nodes -subj "/CN=ldap-api" \
+        -keyout ldap-api.key -out ldap-api.csr
+    openssl x509 -req -in ldap-api.csr -CA ca.pem -CAkey ca.key -CAcreateserial \
+        -days 365 -out ldap-api.pem
+    chmod 644 ./*.pem ./*.key
+fi
+
+if [ ! -f /var/lib/ldap/data.mdb ]; then
+    slapadd -f /etc/ldap/slapd.conf -l /etc/ldap/base.ldif
+fi
+
+exec slapd -d 0 -u root -f /etc/ldap/slapd.conf -h "ldap:/// ldaps:///"